@@ -0,0 +1,379 @@
+//! 外网服务器的管理接口
+//!
+//! 一个简单的 HTTP 服务，每个连接处理一个请求：
+//! - `GET /peers`：注册的 peer，每行为 id、外网地址、距最近一次注册的秒数、所在的其他服务器，
+//!   可疑的 peer 后跟 `suspect`。其他命名空间中的 id 显示为 `<命名空间>/<id>`
+//! - `GET /metrics`：Prometheus 格式的指标
+//! - `POST /evict/<id>`、`POST /evict/<命名空间>/<id>`：注销 peer，命名空间和 id 可以使用 `%XX` 转义
+//!
+//! 注册表由服务器主循环持有，HTTP 连接通过 [`Command`] 向主循环查询或修改。
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::registry::display_id;
+
+/// 请求头最大长度
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// 读取请求的超时时间
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 服务器处理的消息计数
+#[derive(Debug, Default, Clone)]
+pub struct Counters {
+    pub queries: u64,
+    pub registers: u64,
+    pub joins: u64,
+    pub unregisters: u64,
+    pub lookups: u64,
+    /// 查询的 peer 未注册
+    pub lookup_misses: u64,
+    /// 被查询的 peer 没有响应打洞请求
+    pub lookup_timeouts: u64,
+    /// 转发给 peer 的打洞请求
+    pub requests: u64,
+    /// peer 对打洞请求的响应
+    pub responses: u64,
+    /// 经其他服务器转发的打洞请求和响应
+    pub cluster_requests: u64,
+    pub cluster_peers: u64,
+    pub syncs: u64,
+    /// 没有通过认证的服务器之间的消息
+    pub cluster_rejected: u64,
+    /// 因限速丢弃的消息
+    pub rate_limited: u64,
+    /// 因注册表已满拒绝的注册
+    pub registry_full: u64,
+    /// 上线通知的订阅和发出的通知
+    pub subscribes: u64,
+    pub notifications: u64,
+}
+
+/// 一个 peer 的注册信息
+#[derive(Debug, Clone)]
+pub struct PeerStatus {
+    /// 所在的命名空间，为空表示默认命名空间
+    pub namespace: Vec<u8>,
+    pub id: Vec<u8>,
+    pub addr: SocketAddr,
+    /// 距最近一次注册的时间
+    pub age: Duration,
+    /// 所在的其他服务器
+    pub node: Option<SocketAddr>,
+    /// 最近一次转发的打洞请求没有响应
+    pub suspect: bool,
+}
+
+/// 服务器当前状态
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub peers: Vec<PeerStatus>,
+    pub networks: usize,
+    /// 等待上线通知的订阅数
+    pub subscriptions: usize,
+    pub counters: Counters,
+}
+
+/// HTTP 连接发给服务器主循环的命令
+#[derive(Debug)]
+pub enum Command {
+    /// 获取当前状态
+    Status(oneshot::Sender<Status>),
+    /// 注销命名空间中的 peer，返回 peer 是否存在
+    Evict(Vec<u8>, Vec<u8>, oneshot::Sender<bool>),
+}
+
+/// 在 `listener` 上处理管理请求，直到出错
+pub async fn serve(listener: TcpListener, commands: Sender<Command>) -> crate::Result<()> {
+    loop {
+        let (stream, src) = listener.accept().await.map_err(err!())?;
+        let commands = commands.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, commands).await {
+                error!("admin {}: {}", src, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, commands: Sender<Command>) -> crate::Result<()> {
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(err!("read request"))??;
+    let (method, path) = {
+        let mut parts = request.split(' ');
+        (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+    };
+
+    let (code, body) = match (method, path) {
+        ("GET", "/peers") => (200, peers(&status(&commands).await?)),
+        ("GET", "/metrics") => (200, metrics(&status(&commands).await?)),
+        ("POST", path) if path.starts_with("/evict/") => {
+            let (namespace, id) = match path["/evict/".len()..].split_once('/') {
+                Some((namespace, id)) => (percent_decode(namespace), percent_decode(id)),
+                None => (Vec::new(), percent_decode(&path["/evict/".len()..])),
+            };
+            let name = display_id(&namespace, &id);
+            let (tx, rx) = oneshot::channel();
+            send(&commands, Command::Evict(namespace, id, tx)).await?;
+            match rx.await.map_err(err!())? {
+                true => (200, format!("evict {}\n", name)),
+                false => (404, format!("{} is not registered\n", name)),
+            }
+        }
+        ("GET", _) | ("POST", _) => (404, "not found\n".to_string()),
+        _ => (405, "method not allowed\n".to_string()),
+    };
+
+    let reason = match code {
+        200 => "OK",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        reason,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(err!())?;
+    stream.shutdown().await.map_err(err!())
+}
+
+/// 读取请求头，返回请求行
+async fn read_request(stream: &mut TcpStream) -> crate::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|v| v == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await.map_err(err!())?;
+        if n == 0 || buf.len() + n > MAX_REQUEST_SIZE {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "invalid request");
+            return Err(e).map_err(err!());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let line = buf.split(|v| *v == b'\r').next().unwrap_or_default();
+    Ok(String::from_utf8_lossy(line).into_owned())
+}
+
+async fn send(commands: &Sender<Command>, command: Command) -> crate::Result<()> {
+    commands
+        .send(command)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        .map_err(err!("server stopped"))
+}
+
+async fn status(commands: &Sender<Command>) -> crate::Result<Status> {
+    let (tx, rx) = oneshot::channel();
+    send(commands, Command::Status(tx)).await?;
+    rx.await.map_err(err!())
+}
+
+/// 注册的 peer，按命名空间和 id 排序
+fn peers(status: &Status) -> String {
+    let mut peers = status.peers.clone();
+    peers.sort_by(|a, b| (&a.namespace, &a.id).cmp(&(&b.namespace, &b.id)));
+    let mut body = String::new();
+    for peer in peers {
+        let node = peer.node.map(|v| v.to_string());
+        let _ = writeln!(
+            body,
+            "{}\t{}\t{}\t{}{}",
+            display_id(&peer.namespace, &peer.id),
+            peer.addr,
+            peer.age.as_secs(),
+            node.as_deref().unwrap_or("-"),
+            if peer.suspect { "\tsuspect" } else { "" }
+        );
+    }
+    body
+}
+
+/// Prometheus 文本格式的指标
+fn metrics(status: &Status) -> String {
+    let c = &status.counters;
+    let local = status.peers.iter().filter(|v| v.node.is_none()).count();
+    let suspect = status.peers.iter().filter(|v| v.suspect).count();
+    let gauges = [
+        ("uhp_peers", "Registered peers", status.peers.len() as u64),
+        (
+            "uhp_local_peers",
+            "Peers registered on this server",
+            local as u64,
+        ),
+        ("uhp_networks", "Mesh networks", status.networks as u64),
+        (
+            "uhp_suspect_peers",
+            "Peers that did not respond to the last punch request",
+            suspect as u64,
+        ),
+        (
+            "uhp_subscriptions",
+            "Pending presence subscriptions",
+            status.subscriptions as u64,
+        ),
+    ];
+    let counters = [
+        ("uhp_queries_total", "Address queries", c.queries),
+        ("uhp_registers_total", "Registrations", c.registers),
+        ("uhp_joins_total", "Mesh joins", c.joins),
+        ("uhp_unregisters_total", "Unregistrations", c.unregisters),
+        ("uhp_lookups_total", "Lookups", c.lookups),
+        (
+            "uhp_lookup_misses_total",
+            "Lookups of unregistered peers",
+            c.lookup_misses,
+        ),
+        (
+            "uhp_lookup_timeouts_total",
+            "Lookups of peers that did not respond",
+            c.lookup_timeouts,
+        ),
+        (
+            "uhp_requests_total",
+            "Punch requests forwarded to peers",
+            c.requests,
+        ),
+        (
+            "uhp_responses_total",
+            "Punch responses from peers",
+            c.responses,
+        ),
+        (
+            "uhp_cluster_requests_total",
+            "Punch requests relayed for other servers",
+            c.cluster_requests,
+        ),
+        (
+            "uhp_cluster_peers_total",
+            "Punch responses relayed from other servers",
+            c.cluster_peers,
+        ),
+        (
+            "uhp_syncs_total",
+            "Registrations synced from other servers",
+            c.syncs,
+        ),
+        (
+            "uhp_cluster_rejected_total",
+            "Cluster messages that failed authentication",
+            c.cluster_rejected,
+        ),
+        (
+            "uhp_rate_limited_total",
+            "Messages dropped by rate limiting",
+            c.rate_limited,
+        ),
+        (
+            "uhp_registry_full_total",
+            "Registrations rejected because the registry is full",
+            c.registry_full,
+        ),
+        (
+            "uhp_subscribes_total",
+            "Presence subscriptions",
+            c.subscribes,
+        ),
+        (
+            "uhp_notifications_total",
+            "Presence notifications sent",
+            c.notifications,
+        ),
+    ];
+
+    let mut body = String::new();
+    for (name, help, value) in gauges {
+        let _ = write!(
+            body,
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+        );
+    }
+    for (name, help, value) in counters {
+        let _ = write!(
+            body,
+            "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n"
+        );
+    }
+
+    // 默认命名空间之外的各命名空间中注册的 peer 个数
+    let mut namespaces = BTreeMap::new();
+    for peer in status.peers.iter().filter(|v| !v.namespace.is_empty()) {
+        *namespaces.entry(&peer.namespace).or_insert(0) += 1;
+    }
+    if !namespaces.is_empty() {
+        let name = "uhp_namespace_peers";
+        let _ = write!(
+            body,
+            "# HELP {name} Registered peers per namespace\n# TYPE {name} gauge\n"
+        );
+        for (namespace, value) in namespaces {
+            let label = String::from_utf8_lossy(namespace)
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            let _ = writeln!(body, "{name}{{namespace=\"{label}\"}} {value}");
+        }
+    }
+    body
+}
+
+/// 解码 URL 路径中的 `%XX`
+fn percent_decode(s: &str) -> Vec<u8> {
+    let s = s.as_bytes();
+    let mut v = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        // from_str_radix 接受 `+` 前缀，需要先检查是否都是十六进制数字
+        let hex = s
+            .get(i + 1..i + 3)
+            .filter(|v| v.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|v| std::str::from_utf8(v).ok());
+        match hex.map(|v| u8::from_str_radix(v, 16)) {
+            Some(Ok(b)) if s[i] == b'%' => {
+                v.push(b);
+                i += 3;
+            }
+            _ => {
+                v.push(s[i]);
+                i += 1;
+            }
+        }
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("alice"), b"alice");
+        assert_eq!(percent_decode("a%20b"), b"a b");
+        assert_eq!(percent_decode("%2f%2F"), b"//");
+        assert_eq!(percent_decode("%E4%BD%A0"), "你".as_bytes());
+        assert_eq!(percent_decode("%ff"), [0xff]);
+        // 不完整或无效的转义原样保留
+        assert_eq!(percent_decode("%"), b"%");
+        assert_eq!(percent_decode("a%2"), b"a%2");
+        assert_eq!(percent_decode("%zz"), b"%zz");
+        assert_eq!(percent_decode("%+1"), b"%+1");
+        assert_eq!(percent_decode("%%41"), b"%A");
+    }
+}
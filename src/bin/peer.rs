@@ -1,54 +1,272 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::future::pending;
 use std::io::{self, ErrorKind};
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use async_trait::async_trait;
-use log::{error, info};
+use log::{error, info, warn};
 use structopt::StructOpt;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
-use tokio::time::{sleep, Duration};
+use tokio::io::{stdin, stdout};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, timeout, Duration, Interval, MissedTickBehavior};
 
-use udp_hole_punching::file_transfer::{receive, send};
-use udp_hole_punching::util::{init_logger, resolve, runtime};
+use udp_hole_punching::daemon::{DaemonConfig, IdConfig};
+use udp_hole_punching::file_transfer::{
+    deposit, fetch, forward, forward_udp, list, respond, respond_stream, send, send_stream, tunnel,
+    Accept, AllowForward, Conflict, Forward, Mailbox, Policy, Service, UDP_IDLE_DURATION,
+};
+use udp_hole_punching::mesh::Mesh;
+use udp_hole_punching::transport::{Channel, Identity, Transport};
+use udp_hole_punching::tun::Tun;
+use udp_hole_punching::util::{init_logger, resolve, runtime, shutdown};
 use udp_hole_punching::Message::*;
-use udp_hole_punching::{err, perform, Message, Operation, Result, Socket, WithContext};
+use udp_hole_punching::{
+    err, perform, ErrorCode, Message, Namespace, Operation, Result, ServerError, Socket,
+    WithContext,
+};
 
 #[derive(StructOpt)]
 struct Opt {
-    /// 外网服务器地址
-    #[structopt(short, long)]
-    addr: String,
+    /// 外网服务器地址，可指定多个，和 `--addr2` 按顺序对应。服务器无响应时使用下一个
+    #[structopt(short, long, required = true)]
+    addr: Vec<String>,
 
     /// 外网服务器地址
-    #[structopt(long)]
-    addr2: String,
+    #[structopt(long, required = true)]
+    addr2: Vec<String>,
 
-    /// 要发送的文件，指定本项表示这是一个发送端
-    #[structopt(short, long, conflicts_with("receive"), required_unless("receive"))]
-    send: Option<PathBuf>,
+    /// 要发送的文件，可指定多个，同时发送。`-` 表示发送标准输入
+    #[structopt(
+        short,
+        long,
+        conflicts_with_all(&["receive", "serve", "allow-forward", "accept-tun", "mailbox"]),
+        required_unless_one(&[
+            "receive",
+            "serve",
+            "mailbox",
+            "daemon",
+            "list",
+            "fetch",
+            "forward",
+            "forward-udp",
+            "allow-forward",
+            "tun",
+            "accept-tun",
+            "mesh"
+        ])
+    )]
+    send: Vec<PathBuf>,
 
-    /// 接收文件保存位置，指定本项表示这是一个接收端
-    #[structopt(short, long, required_unless("send"))]
+    /// 接收文件保存位置，指定本项表示这是一个接收端。
+    /// `-` 表示输出到标准输出，接收一次后退出
+    #[structopt(short, long, conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun"]))]
     receive: Option<PathBuf>,
 
+    /// 对外提供的目录，其他 peer 可获取目录列表和其中的文件。可以和 `--receive` 同时使用
+    #[structopt(long, conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun"]))]
+    serve: Option<PathBuf>,
+
+    /// 获取 `--serve` 端的目录列表，`.` 表示根目录
+    #[structopt(long)]
+    list: Option<String>,
+
+    /// 获取 `--serve` 端的文件，可指定多个，同时获取
+    #[structopt(long)]
+    fetch: Vec<String>,
+
+    /// `--fetch` 获取的文件保存位置，`-` 表示输出到标准输出
+    #[structopt(short, long, default_value = ".")]
+    output: PathBuf,
+
+    /// 发送标准输入时使用的名称，接收端保存为文件时作为文件名
+    #[structopt(long, default_value = "stdin")]
+    name: String,
+
     /// 如果作为发送端，表示接收端的 id，否则表示自己的 id
+    #[structopt(long, required_unless("daemon"))]
+    id: Option<String>,
+
+    /// `--id` 所在的命名空间（租户），不同命名空间中的 id 互不冲突。不指定时使用默认命名空间
     #[structopt(long)]
-    id: String,
+    namespace: Option<String>,
+
+    /// 命名空间的凭证
+    #[structopt(long, requires("namespace"))]
+    token: Option<String>,
+
+    /// 发起端查询的 peer 不在线时，订阅其上线通知，等待上线后再连接
+    #[structopt(long, conflicts_with_all(&["receive", "serve", "allow-forward", "accept-tun", "mesh"]))]
+    wait: bool,
+
+    /// 通过暂存 peer 发送，`--id` 不在线时由暂存 peer 保存，上线后再转交。
+    /// 使用 `--token` 作为凭证。传输是加密的，但文件以明文保存在暂存 peer 上
+    #[structopt(
+        long,
+        requires("send"),
+        conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun", "mesh"])
+    )]
+    via: Option<String>,
+
+    /// 作为暂存 peer，为不在线的 peer 保存 `--via` 发来的文件，在对方上线后转交。
+    /// 可以和 `--receive`、`--serve`、`--allow-forward` 同时使用。只通过 QUIC 接收和转交，
+    /// 发送端需要提供所在命名空间的 `--token`
+    #[structopt(
+        long,
+        requires("quic"),
+        conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun"])
+    )]
+    mailbox: Option<PathBuf>,
+
+    /// 暂存文件的总大小上限（MiB）
+    #[structopt(long, default_value = "1024")]
+    mailbox_quota: u64,
+
+    /// 暂存文件的保存期限（小时），过期未转交的文件被删除
+    #[structopt(long, default_value = "72")]
+    mailbox_ttl: u64,
+
+    /// 发送端声明的名称，接收端的 `--accept-name` 据此决定是否接收。名称没有经过验证
+    #[structopt(long)]
+    from: Option<String>,
+
+    /// 只接收声明了这些名称（`--from`）的发送端发送的文件，可指定多个。
+    /// 名称可以伪造，只用来过滤，限制访问应使用命名空间凭证
+    #[structopt(long, conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun"]))]
+    accept_name: Vec<String>,
+
+    /// 接收文件的大小上限（MiB），长度未知的数据流同样拒绝
+    #[structopt(long, conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun"]))]
+    max_size: Option<u64>,
+
+    /// 接收每个文件之前在终端询问，60 秒内没有回答时拒绝
+    #[structopt(long, conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun"]))]
+    prompt: bool,
+
+    /// 同名文件处理方式：overwrite, skip, rename, ask。
+    /// 接收端使用 ask 时由发送端的设置决定
+    #[structopt(long, default_value = "overwrite")]
+    conflict: Conflict,
+
+    /// 使用 QUIC 传输，由注册端指定，发起端通过外网服务器获取证书指纹后自动使用。
+    /// 需要启用 `quic` feature
+    #[structopt(long, conflicts_with_all(&["send", "list", "fetch", "forward", "forward-udp", "tun"]))]
+    quic: bool,
+
+    /// TCP 端口转发，格式：本地端口:目标主机:目标端口，可指定多个。
+    /// 监听 127.0.0.1 的本地端口，连接转发到对方可访问的目标地址
+    #[structopt(long)]
+    forward: Vec<Forward>,
+
+    /// UDP 端口转发，格式同 `--forward`。每个来源地址使用一个通道，空闲一段时间后结束
+    #[structopt(long)]
+    forward_udp: Vec<Forward>,
+
+    /// 允许对方转发 TCP 连接和 UDP 数据报的目标地址，格式：主机:端口，端口为 `*` 时允许所有端口。
+    /// 可指定多个，不指定时不允许转发。可以和 `--receive`、`--serve` 同时使用
+    #[structopt(long, conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun"]))]
+    allow_forward: Vec<AllowForward>,
+
+    /// 创建 TUN 设备并设置本端地址（如 `10.9.0.2/24`），和对方的 TUN 设备之间转发 IP 包。
+    /// 需要 root 权限，只支持 Linux
+    #[structopt(long)]
+    tun: Option<String>,
+
+    /// 创建 TUN 设备并设置本端地址，接受对方 `--tun` 的连接，同时只能连接一个 peer。
+    /// 可以和 `--receive`、`--serve`、`--allow-forward` 同时使用
+    #[structopt(long, conflicts_with_all(&["list", "fetch", "forward", "forward-udp", "tun"]))]
+    accept_tun: Option<String>,
+
+    /// 加入网络，和网络中的其他成员打洞并维护路由表，`--id` 为自己在网络中的 id。
+    /// 可以和 `--receive`、`--serve`、`--allow-forward` 同时使用
+    #[structopt(long, conflicts_with_all(&["tun", "accept-tun"]))]
+    mesh: Option<String>,
+
+    /// 网络成员为其他成员中继，无法直接打洞的成员可以通过本成员访问
+    #[structopt(long, requires("mesh"))]
+    relay: bool,
+
+    /// 作为网络成员时，`--send`、`--list`、`--fetch`、`--forward`、`--forward-udp` 的对方 id。
+    /// 无法直接打洞时通过中继成员访问
+    #[structopt(long, requires("mesh"))]
+    to: Option<String>,
+
+    /// TUN 设备名称，`%d` 由内核分配编号
+    #[structopt(long, default_value = "uhp%d")]
+    tun_name: String,
+
+    /// 守护进程配置文件，在一个 socket 上注册其中的多个 id，每个 id 有自己的凭证和接收目录等设置。
+    /// 只能和 `--addr`、`--addr2` 同时使用
+    #[structopt(
+        long,
+        conflicts_with_all(&[
+            "send", "receive", "serve", "list", "fetch", "id", "namespace", "wait", "quic",
+            "forward", "forward-udp", "allow-forward", "tun", "accept-tun", "mesh", "mailbox",
+            "accept-name", "max-size", "prompt"
+        ])
+    )]
+    daemon: Option<PathBuf>,
 }
 
 const RECV_BUF_SIZE: usize = 256;
 
 const PUNCH_HOLE_DURATION: Duration = Duration::from_secs(1);
 
+/// UDP 数据报最大长度
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// 每个 UDP 转发来源缓存的数据报个数
+const FLOW_CAPACITY: usize = 1024;
+
+/// 会话空闲时间，没有通道且超过此时间没有收到对方数据时结束会话
+const SESSION_IDLE_DURATION: Duration = Duration::from_secs(10);
+
+/// 超过此时间没有收到对方数据时认为对方已断开，即使还有通道也结束会话
+const SESSION_DEAD_DURATION: Duration = Duration::from_secs(30);
+
+/// TUN 设备 MTU，留出隧道消息头和 UDP、IP 头的空间
+const TUN_MTU: u32 = 1400;
+
+/// 网络成员定期向服务器 Join 的间隔
+const MESH_JOIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 和网络成员打洞失败或会话断开后，间隔此时间再重试
+const MESH_RETRY_DURATION: Duration = Duration::from_secs(30);
+
+/// 接收 Members 消息的缓冲区大小
+const MEMBERS_BUF_SIZE: usize = 2048;
+
+/// 等待到 `--to` 成员的路由的时间
+const MESH_ROUTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 连续此数量的注册间隔没有收到服务器回复时，切换到下一个服务器
+const SERVER_DEAD_COUNT: u32 = 3;
+
+/// 重新注册的最大间隔
+const REGISTER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 暂存 peer 检查过期文件和待转交文件的间隔
+const MAILBOX_SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 转交失败后，间隔此时间再重试
+const MAILBOX_RETRY_DURATION: Duration = Duration::from_secs(60);
+
 fn main() {
     let opt: Opt = Opt::from_args();
     init_logger();
 
-    runtime(opt.receive.is_some()).block_on(async move {
+    let multi_thread = is_service(&opt)
+        || !opt.forward.is_empty()
+        || !opt.forward_udp.is_empty()
+        || opt.tun.is_some()
+        || opt.send.len() + opt.fetch.len() > 1;
+    runtime(multi_thread).block_on(async move {
         if let Err(e) = run(opt).await {
             error!("{}", e);
             exit(1);
@@ -57,110 +275,920 @@ fn main() {
 }
 
 async fn run(opt: Opt) -> Result<()> {
-    let server_addr = resolve(&opt.addr).await?;
-    let server_addr2 = resolve(&opt.addr2).await?;
+    // 网络成员通过 --to 指定传输的对方
+    if opt.mesh.is_some() && opt.to.is_some() != is_initiator(&opt) {
+        let e = io::Error::other("--to must be used with send, list, fetch or forward");
+        Err(e).map_err(err!())?;
+    }
+    if opt.addr.len() != opt.addr2.len() {
+        let e = io::Error::other("--addr and --addr2 must be given in pairs");
+        Err(e).map_err(err!())?;
+    }
+    // 暂存 peer 需要知道文件大小
+    if opt.via.is_some() && opt.send.iter().any(|v| is_stdio(v)) {
+        let e = io::Error::other("--via cannot send stdin");
+        Err(e).map_err(err!())?;
+    }
+    let mut server_pairs = Vec::new();
+    for (addr, addr2) in opt.addr.iter().zip(&opt.addr2) {
+        server_pairs.push((resolve(addr).await?, resolve(addr2).await?));
+    }
     let mut sock = Socket::new_unspecified().await?;
     let mut buf = vec![0u8; RECV_BUF_SIZE];
 
-    // 如果是对称型 nat，终止
-    let mut op = DetectSymmetricNat::new(&sock, server_addr, server_addr2, &mut buf);
-    perform(&mut op)
-        .await
-        .map_err(err!("detect symmetric nat"))?;
+    // 如果是对称型 nat，终止。之后优先使用有响应的服务器
+    let first = detect_symmetric_nat(&sock, &server_pairs, &mut buf).await?;
+    let mut servers: Vec<_> = server_pairs.iter().map(|v| v.0).collect();
+    servers.rotate_left(first);
 
-    let id = opt.id.into_bytes();
-    if opt.receive.is_some() {
-        // 向服务器注册，等待连接
-        sock.connect(server_addr).await.map_err(err!())?;
-        let mut op = Register::new(&sock, &id, &mut buf);
-        perform(&mut op).await.map_err(err!("register"))?;
+    if let Some(ref path) = opt.daemon {
+        let config = DaemonConfig::load(path)?;
+        let mut registrations = Vec::new();
+        for v in &config.ids {
+            registrations.push(Registration::from_config(v)?);
+        }
+        return serve(sock, buf, servers, registrations, None).await;
+    }
 
-        let peers = Arc::new(Mutex::new(HashMap::new()));
-        loop {
-            tokio::select! {
-                recv = sock.recv(&mut buf) => {
-                    match recv.map_err(err!())? {
-                        Request { peer_addr } => {
-                            match peers.lock().unwrap().entry(peer_addr) {
-                                Entry::Vacant(v) => {
-                                    let (tx, rx) = unbounded_channel::<()>();
-                                    v.insert(tx);
-                                    let peers = Arc::clone(&peers);
-                                    let dir = opt.receive.clone().unwrap();
-                                    tokio::spawn(async move {
-                                        if let Err(e) = handle_punch(server_addr, peer_addr, rx, dir).await {
-                                            error!("{}", e);
-                                        }
-                                        peers.lock().unwrap().remove(&peer_addr);
-                                    });
-                                }
-                                // 防止重复处理
-                                Entry::Occupied(v) => v.get().send(()).map_err(err!())?,
-                            }
-                        }
-                        _ => {}
-                    }
+    // 先监听转发端口，端口被占用时不必打洞
+    let mut listeners = Vec::new();
+    for spec in &opt.forward {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, spec.port))
+            .await
+            .map_err(err!("cannot listen on port {}", spec.port))?;
+        listeners.push((listener, spec.clone()));
+    }
+    let mut udp_sockets = Vec::new();
+    for spec in &opt.forward_udp {
+        let sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, spec.port))
+            .await
+            .map_err(err!("cannot bind to udp port {}", spec.port))?;
+        udp_sockets.push((sock, spec.clone()));
+    }
+    let tun = match opt.tun {
+        Some(ref addr) => Some(create_tun(&opt.tun_name, addr)?),
+        None => None,
+    };
+
+    let id = opt.id.clone().unwrap_or_default().into_bytes();
+    let namespace = Namespace {
+        name: opt.namespace.clone().unwrap_or_default().into_bytes(),
+        token: opt.token.clone().unwrap_or_default().into_bytes(),
+    };
+    if is_service(&opt) {
+        let mesh = opt
+            .mesh
+            .as_ref()
+            .map(|_| Arc::new(Mesh::new(id.clone(), opt.relay)));
+        let service = Service {
+            receive: opt.receive.clone(),
+            conflict: opt.conflict,
+            serve: opt.serve.clone(),
+            forward: opt.allow_forward.clone(),
+            tun: match opt.accept_tun {
+                Some(ref addr) => Some(Arc::new(create_tun(&opt.tun_name, addr)?)),
+                None => None,
+            },
+            relay: mesh.clone().filter(|_| opt.relay),
+            mailbox: match opt.mailbox {
+                Some(ref dir) => {
+                    let quota = opt.mailbox_quota * 1024 * 1024;
+                    let ttl = Duration::from_secs(opt.mailbox_ttl * 3600);
+                    let token = namespace.token.clone();
+                    Some(Arc::new(Mailbox::new(dir.clone(), token, quota, ttl)?))
                 }
-                _ = sleep(Duration::from_secs(30)) => {
-                    // 定时向服务器注册
-                    let id = id.clone();
-                    sock.send_to(&Message::Register { id }, server_addr).await.map_err(err!())?;
+                None => None,
+            },
+            accept: accept_policy(&opt.accept_name, opt.max_size, opt.prompt),
+        };
+        if let Some(ref mailbox) = service.mailbox {
+            let mailbox = Arc::clone(mailbox);
+            let servers = servers.clone();
+            let namespace = namespace.clone();
+            tokio::spawn(deliver_mail(mailbox, servers, namespace));
+        }
+        let identity = match opt.quic {
+            true => Some(Arc::new(Identity::generate()?)),
+            false => None,
+        };
+
+        // 向服务器注册或者加入网络，等待连接
+        let register = match opt.mesh {
+            Some(ref network) => {
+                buf.resize(MEMBERS_BUF_SIZE, 0);
+                let network = network.clone().into_bytes();
+                let id = id.clone();
+                Message::Join {
+                    namespace,
+                    network,
+                    id,
+                }
+            }
+            None => Message::Register {
+                namespace,
+                id: id.clone(),
+            },
+        };
+        let registration = Registration {
+            register,
+            service,
+            identity,
+        };
+        let serving = serve(sock, buf, servers, vec![registration], mesh.clone());
+
+        // 网络成员同时向另一个成员传输
+        match (mesh, &opt.to) {
+            (Some(mesh), Some(to)) => {
+                let to = to.clone().into_bytes();
+                let member = async {
+                    wait_route(&mesh, &to).await?;
+                    let target = Arc::new(Target::Member(mesh, to));
+                    transfer(&opt, target, listeners, udp_sockets, tun).await
+                };
+                tokio::select! {
+                    result = serving => result,
+                    result = member => result,
                 }
             }
+            _ => serving.await,
         }
     } else {
-        // 查询 peer，发起打洞
-        let mut op = Lookup::new(&sock, server_addr, &id, &mut buf);
-        match perform(&mut op).await.map_err(err!("lookup"))? {
-            Some(peer_addr) => {
-                let ttl = sock.as_ref().ttl().map_err(err!())?;
-                sock.as_ref().set_ttl(6).map_err(err!())?;
-                sock.send_to(&Hello, peer_addr).await.map_err(err!())?;
-                sock.as_ref().set_ttl(ttl).map_err(err!())?;
-
-                std::thread::sleep(Duration::from_millis(50));
-                sock.send_to(&Hello, peer_addr).await.map_err(err!())?;
-                let deadline = Instant::now() + PUNCH_HOLE_DURATION;
-                loop {
-                    tokio::select! {
-                        recv = sock.recv_from(&mut buf) => {
-                            let (msg, src) = recv.map_err(err!())?;
-                            match msg {
-                                Hello if src == peer_addr => {
-                                    sock.connect(peer_addr).await?;
-                                    sock.send(&HelloAck).await.map_err(err!())?;
-                                    break;
+        // 查询 peer，发起打洞。通过暂存 peer 发送时连接暂存 peer
+        let peer_id = match opt.via {
+            Some(ref via) => via.clone().into_bytes(),
+            None => id,
+        };
+        let (peer_addr, fingerprint) =
+            lookup(&sock, &servers, &namespace, &peer_id, opt.wait, &mut buf).await?;
+        if opt.via.is_some() && fingerprint.is_none() {
+            let e = io::Error::new(ErrorKind::PermissionDenied, "mailbox does not use quic");
+            Err(e).map_err(err!())?;
+        }
+        punch(&mut sock, peer_addr, &mut buf).await?;
+
+        // 在同一个会话上同时进行所有传输
+        let transport = Transport::client(sock, fingerprint).await?;
+        let target = Arc::new(Target::Session(transport));
+        transfer(&opt, target, listeners, udp_sockets, tun).await
+    }
+}
+
+/// 依次向外网服务器检测是否是对称型 NAT，返回第一个有响应的服务器
+async fn detect_symmetric_nat(
+    sock: &Socket,
+    servers: &[(SocketAddr, SocketAddr)],
+    buf: &mut [u8],
+) -> Result<usize> {
+    for (i, &(addr, addr2)) in servers.iter().enumerate() {
+        let mut op = DetectSymmetricNat::new(sock, addr, addr2, buf);
+        match perform(&mut op).await {
+            Ok(()) => return Ok(i),
+            Err(e) if e.kind() == ErrorKind::TimedOut => warn!("server {} is unreachable", addr),
+            Err(e) => Err(e).map_err(err!("detect symmetric nat"))?,
+        }
+    }
+    Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!("detect symmetric nat"))
+}
+
+/// 连接服务器，注册或者加入网络，返回重新注册的间隔。网络成员同时更新成员列表
+async fn connect_server(
+    sock: &mut Socket,
+    server_addr: SocketAddr,
+    msg: &Message,
+    mesh: Option<&Mesh>,
+    buf: &mut [u8],
+) -> Result<Duration> {
+    sock.connect(server_addr).await?;
+    match (msg, mesh) {
+        (
+            Message::Join {
+                namespace,
+                network,
+                id,
+            },
+            Some(mesh),
+        ) => {
+            let mut op = Join::new(sock, namespace, network, id, buf);
+            let members = perform(&mut op)
+                .await
+                .map_err(err!("join {}", server_addr))?;
+            mesh.update_members(members);
+            Ok(MESH_JOIN_INTERVAL)
+        }
+        (Message::Register { namespace, id }, _) => {
+            let mut op = Register::new(sock, namespace, id, buf);
+            let ttl = perform(&mut op)
+                .await
+                .map_err(err!("register {}", server_addr))?;
+            Ok(interval_for_ttl(ttl))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// 根据服务器告知的注册有效期计算重新注册的间隔，同时用来保持 NAT 映射
+fn interval_for_ttl(ttl: u64) -> Duration {
+    REGISTER_INTERVAL.min(Duration::from_secs(ttl) / SERVER_DEAD_COUNT)
+}
+
+/// 是否是外网服务器回复的 id 无效或者没有权限的错误
+fn is_rejected(e: &udp_hole_punching::Error) -> bool {
+    matches!(
+        ServerError::find(e),
+        Some(v) if v.code == ErrorCode::InvalidId || v.code == ErrorCode::Unauthorized
+    )
+}
+
+/// 依次向外网服务器查询 peer，服务器无响应时查询下一个。
+/// `wait` 为 true 时 peer 不在线则等待其上线后重新查询
+async fn lookup(
+    sock: &Socket,
+    servers: &[SocketAddr],
+    namespace: &Namespace,
+    peer_id: &[u8],
+    wait: bool,
+    buf: &mut [u8],
+) -> Result<(SocketAddr, Option<Vec<u8>>)> {
+    let mut i = 0;
+    while i < servers.len() {
+        let server_addr = servers[i];
+        let mut op = Lookup::new(sock, server_addr, namespace, peer_id, buf);
+        match perform(&mut op).await {
+            Ok(v) => return Ok(v),
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                warn!("server {} is unreachable", server_addr);
+                i += 1;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound && wait => {
+                info!("{}, wait for it to come online", e);
+                wait_online(sock, server_addr, namespace, peer_id, buf).await?;
+            }
+            Err(e) => Err(e).map_err(err!("lookup"))?,
+        }
+    }
+    let e = io::Error::new(ErrorKind::TimedOut, "no server is reachable");
+    Err(e).map_err(err!("lookup"))
+}
+
+/// 订阅 peer 的上线通知，等待直到 peer 注册。在订阅有效期内定期重新订阅
+async fn wait_online(
+    sock: &Socket,
+    server_addr: SocketAddr,
+    namespace: &Namespace,
+    peer_id: &[u8],
+    buf: &mut [u8],
+) -> Result<()> {
+    loop {
+        let mut op = Subscribe::new(sock, server_addr, namespace, peer_id, buf);
+        let ttl = match perform(&mut op).await.map_err(err!("subscribe"))? {
+            Some(v) => v,
+            None => break,
+        };
+        let online = async {
+            loop {
+                match sock.recv_from(buf).await? {
+                    (Online { peer_id: v }, src) if src == server_addr && v == peer_id => {
+                        return Ok::<_, io::Error>(());
+                    }
+                    _ => {}
+                }
+            }
+        };
+        if let Ok(result) = timeout(interval_for_ttl(ttl), online).await {
+            result.map_err(err!("wait for online"))?;
+            break;
+        }
+    }
+    info!("{} is online", String::from_utf8_lossy(peer_id));
+    Ok(())
+}
+
+/// 注册端的一个 id：注册或者加入网络的消息，以及处理对该 id 的打洞请求的方式
+struct Registration {
+    register: Message,
+    service: Service,
+    identity: Option<Arc<Identity>>,
+}
+
+impl Registration {
+    /// 守护进程配置文件中的一个 id
+    fn from_config(config: &IdConfig) -> Result<Self> {
+        let register = Message::Register {
+            namespace: config.namespace(),
+            id: config.id.clone().into_bytes(),
+        };
+        let service = Service {
+            receive: config.receive.clone(),
+            conflict: config.conflict,
+            serve: config.serve.clone(),
+            forward: config.allow_forward.clone(),
+            tun: None,
+            relay: None,
+            mailbox: None,
+            accept: accept_policy(&config.accept_name, config.max_size, false),
+        };
+        let identity = match config.quic {
+            true => Some(Arc::new(Identity::generate()?)),
+            false => None,
+        };
+        Ok(Self {
+            register,
+            service,
+            identity,
+        })
+    }
+
+    /// 注册所在的命名空间和 id
+    fn key(&self) -> (&Namespace, &[u8]) {
+        match self.register {
+            Message::Register {
+                ref namespace,
+                ref id,
+            }
+            | Message::Join {
+                ref namespace,
+                ref id,
+                ..
+            } => (namespace, id),
+            _ => unreachable!(),
+        }
+    }
+
+    /// 退出时的注销消息
+    fn unregister(&self) -> Message {
+        let (namespace, id) = self.key();
+        Unregister {
+            namespace: namespace.name.clone(),
+            id: id.to_vec(),
+        }
+    }
+}
+
+/// 向服务器注册所有 id，返回重新注册的间隔
+async fn connect_all(
+    sock: &mut Socket,
+    server_addr: SocketAddr,
+    registrations: &[Registration],
+    mesh: Option<&Mesh>,
+    buf: &mut [u8],
+) -> Result<Duration> {
+    let mut interval = REGISTER_INTERVAL;
+    for v in registrations {
+        let id = String::from_utf8_lossy(v.key().1).to_string();
+        let result = connect_server(sock, server_addr, &v.register, mesh, buf).await;
+        interval = interval.min(result.ctx("id", id)?);
+    }
+    Ok(interval)
+}
+
+/// 注册端：向服务器注册 `registrations` 中的所有 id，处理服务器转发的打洞请求，定时注册，退出时注销。
+/// 网络成员同时和其他成员打洞。服务器无响应或长时间没有回复时切换到 `servers` 中的下一个
+async fn serve(
+    mut sock: Socket,
+    mut buf: Vec<u8>,
+    servers: Vec<SocketAddr>,
+    registrations: Vec<Registration>,
+    mesh: Option<Arc<Mesh>>,
+) -> Result<()> {
+    let mut server = 0;
+    let mut register_interval = loop {
+        match connect_all(
+            &mut sock,
+            servers[server],
+            &registrations,
+            mesh.as_deref(),
+            &mut buf,
+        )
+        .await
+        {
+            Ok(v) => break v,
+            // id 无效或者没有权限时其他服务器同样会拒绝
+            Err(e) if server + 1 < servers.len() && !is_rejected(&e) => {
+                warn!("{}", e);
+                server += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    let mut server_addr = servers[server];
+    // 定时器在循环外创建，收到消息不会推迟注册
+    let mut register = register_timer(register_interval);
+    let shutdown = shutdown();
+    tokio::pin!(shutdown);
+    // 最近一次收到服务器回复的时间
+    let mut last_ack = Instant::now();
+    // 向每个成员最近一次发起打洞的时间
+    let mut attempts = HashMap::new();
+    // 网络成员只注册一个 id
+    let (namespace, _) = registrations[0].key();
+    if let Some(ref mesh) = mesh {
+        let service = &registrations[0].service;
+        connect_members(server_addr, namespace, mesh, service, &mut attempts);
+    }
+
+    let peers: Arc<Punches> = Arc::default();
+    let (done_tx, mut done_rx) = unbounded_channel::<()>();
+    loop {
+        tokio::select! {
+            recv = sock.recv(&mut buf) => match recv {
+                Ok(Request { peer_addr, namespace, id, nonce }) => {
+                    let registration = match registrations.iter().find(|v| {
+                        let key = v.key();
+                        key.0.name == namespace && key.1 == id
+                    }) {
+                        Some(v) => v,
+                        None => {
+                            warn!("request for unknown id {}", String::from_utf8_lossy(&id));
+                            continue;
+                        }
+                    };
+                    // 同一个 peer 可能同时连接本 socket 上的多个 id
+                    let key = (peer_addr, namespace, id);
+                    match peers.lock().unwrap().entry(key.clone()) {
+                        Entry::Vacant(v) => {
+                            let (tx, rx) = unbounded_channel::<u64>();
+                            v.insert(tx);
+                            let guard = PunchGuard { peers: Arc::clone(&peers), key };
+                            let service = registration.service.clone();
+                            let identity = registration.identity.clone();
+                            let mesh = mesh.clone();
+                            let done_tx = done_tx.clone();
+                            tokio::spawn(async move {
+                                let stdout = is_stdout(&service);
+                                let identity = identity.as_deref();
+                                match handle_punch(server_addr, peer_addr, nonce, rx, service, identity, mesh).await {
+                                    // 输出到标准输出时只接收一次
+                                    Ok(()) if stdout => {
+                                        let _ = done_tx.send(());
+                                    }
+                                    Ok(()) => {}
+                                    Err(e) => error!("{}", e),
                                 }
-                                _ => {}
-                            }
-                            sock.send(&Hello).await.map_err(err!())?;
+                                drop(guard);
+                            });
                         }
-                        _ = sleep(Duration::from_millis(100)) => {
-                            if Instant::now() < deadline {
-                                sock.send_to(&Hello, peer_addr).await.map_err(err!())?;
-                            } else {
-                                Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!("punch hole with {} failed", peer_addr))?;
-                            }
+                        // 防止重复处理，服务器重发的 Request 可能带有新的 nonce。
+                        // 任务正在结束时发送失败，忽略
+                        Entry::Occupied(v) => {
+                            let _ = v.get().send(nonce);
                         }
                     }
                 }
+                // 更新成员，和新成员打洞
+                Ok(Members(ids)) => {
+                    last_ack = Instant::now();
+                    if let Some(ref mesh) = mesh {
+                        mesh.update_members(ids);
+                        let service = &registrations[0].service;
+                        connect_members(server_addr, namespace, mesh, service, &mut attempts);
+                    }
+                }
+                Ok(RegisterAck { ttl }) => {
+                    last_ack = Instant::now();
+                    let v = interval_for_ttl(ttl);
+                    if v != register_interval {
+                        register_interval = v;
+                        register = register_timer(v);
+                    }
+                }
+                // 不把错误回复当作服务器的确认，注册表已满时切换到下一个服务器
+                Ok(Error { code, reason }) => error!("{}", ServerError { code, reason }),
+                Ok(_) => {}
+                // 服务器不可达，等待切换
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {}
+                Err(e) => Err(e).map_err(err!())?,
+            },
+            _ = done_rx.recv() => {
+                unregister(&sock, &registrations).await;
+                return Ok(());
             }
-            None => Err(io::Error::new(ErrorKind::Other, "peer not found")).map_err(err!())?,
+            _ = &mut shutdown => {
+                info!("unregister from {}", server_addr);
+                unregister(&sock, &registrations).await;
+                return Ok(());
+            }
+            _ = register.tick() => {
+                if last_ack.elapsed() > register_interval * SERVER_DEAD_COUNT && servers.len() > 1 {
+                    server = (server + 1) % servers.len();
+                    server_addr = servers[server];
+                    warn!("server is unreachable, switch to {}", server_addr);
+                    sock.connect(server_addr).await?;
+                    last_ack = Instant::now();
+                }
+                // 定时向服务器注册
+                for v in &registrations {
+                    if let Err(e) = sock.send_to(&v.register, server_addr).await {
+                        error!("register to {}: {}", server_addr, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 每隔 `period` 重新注册的定时器，第一次在 `period` 之后
+fn register_timer(period: Duration) -> Interval {
+    let mut timer = interval(period);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    timer.reset();
+    timer
+}
+
+/// 正在处理的打洞请求，键为发起查询的 peer 的地址、被查询的命名空间和 id
+type Punches = Mutex<HashMap<(SocketAddr, Vec<u8>, Vec<u8>), UnboundedSender<u64>>>;
+
+/// 打洞任务结束（包括 panic）时删除对应的记录，之后同一 peer 的请求可以重新处理
+struct PunchGuard {
+    peers: Arc<Punches>,
+    key: (SocketAddr, Vec<u8>, Vec<u8>),
+}
+
+impl Drop for PunchGuard {
+    fn drop(&mut self) {
+        self.peers.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// 注销所有 id
+async fn unregister(sock: &Socket, registrations: &[Registration]) {
+    for v in registrations {
+        let _ = sock.send(&v.unregister()).await;
+    }
+}
+
+/// 接收策略，没有任何限制时为 `None`。`max_size` 的单位为 MiB
+fn accept_policy(names: &[String], max_size: Option<u64>, prompt: bool) -> Option<Arc<dyn Accept>> {
+    if names.is_empty() && max_size.is_none() && !prompt {
+        return None;
+    }
+    let max_size = max_size.map(|v| v * 1024 * 1024);
+    Some(Arc::new(Policy::new(names.to_vec(), max_size, prompt)))
+}
+
+/// 暂存 peer：定期删除过期的文件，等待接收端上线后转交文件。每个接收端同时只有一个转交任务
+async fn deliver_mail(mailbox: Arc<Mailbox>, servers: Vec<SocketAddr>, namespace: Namespace) {
+    let delivering = Arc::new(Mutex::new(HashSet::new()));
+    loop {
+        mailbox.expire();
+        for to in mailbox.recipients() {
+            if !delivering.lock().unwrap().insert(to.clone()) {
+                continue;
+            }
+            let mailbox = Arc::clone(&mailbox);
+            let servers = servers.clone();
+            let namespace = namespace.clone();
+            let delivering = Arc::clone(&delivering);
+            tokio::spawn(async move {
+                if let Err(e) = deliver(&mailbox, &servers, &namespace, &to).await {
+                    error!("deliver to {}: {}", String::from_utf8_lossy(&to), e);
+                    sleep(MAILBOX_RETRY_DURATION).await;
+                }
+                delivering.lock().unwrap().remove(&to);
+            });
         }
+        sleep(MAILBOX_SCAN_INTERVAL).await;
+    }
+}
+
+/// 等待接收端 `to` 上线，打洞后依次发送暂存的文件，发送完成的文件被删除。
+/// 超过保存期限仍未上线时放弃
+async fn deliver(
+    mailbox: &Mailbox,
+    servers: &[SocketAddr],
+    namespace: &Namespace,
+    to: &[u8],
+) -> Result<()> {
+    let mut sock = Socket::new_unspecified().await?;
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let found = lookup(&sock, servers, namespace, to, true, &mut buf);
+    let (peer_addr, fingerprint) = match timeout(mailbox.ttl(), found).await {
+        Ok(v) => v?,
+        Err(_) => return Ok(()),
+    };
+    // 只通过加密的通道转交
+    if fingerprint.is_none() {
+        let e = io::Error::new(ErrorKind::PermissionDenied, "recipient does not use quic");
+        Err(e).map_err(err!())?;
+    }
+    punch(&mut sock, peer_addr, &mut buf).await?;
 
-        let file = opt.send.unwrap();
-        send(sock, &file).await.ctx("file", file.display())
+    let transport = Transport::client(sock, fingerprint).await?;
+    let files = mailbox.pending(to);
+    let total = files.len();
+    let mut failed = 0;
+    // 以原发送端的名称转交
+    for file in files {
+        let channel = transport.open().await?;
+        match send(channel, &file.path, Conflict::Ask, &file.from).await {
+            Ok(()) => mailbox.remove(&file.path),
+            Err(e) => {
+                error!("{}: {}", file.path.display(), e);
+                failed += 1;
+            }
+        }
     }
+    transport.close().await;
+    if failed > 0 {
+        let e = io::Error::other(format!("{} of {} files failed", failed, total));
+        Err(e).map_err(err!())?;
+    }
+    Ok(())
+}
+
+/// 传输的对方
+enum Target {
+    /// 打洞建立的会话
+    Session(Transport),
+    /// 网络成员，直连或者通过中继成员访问
+    Member(Arc<Mesh>, Vec<u8>),
 }
 
+impl Target {
+    async fn open(&self) -> Result<Channel> {
+        match self {
+            Self::Session(v) => v.open().await,
+            Self::Member(mesh, id) => mesh.open(id).await,
+        }
+    }
+
+    /// 等待直到对方断开。网络成员的会话由控制通道检测，这里一直等待
+    async fn wait_dead(&self) {
+        match self {
+            Self::Session(v) => wait_dead(v).await,
+            Self::Member(..) => pending().await,
+        }
+    }
+
+    async fn close(&self) {
+        if let Self::Session(v) = self {
+            v.close().await;
+        }
+    }
+}
+
+/// 等待到成员 `id` 的路由
+async fn wait_route(mesh: &Mesh, id: &[u8]) -> Result<()> {
+    let deadline = Instant::now() + MESH_ROUTE_TIMEOUT;
+    while mesh.route(id).is_none() {
+        if Instant::now() > deadline {
+            let e = io::Error::new(ErrorKind::NotConnected, "no route");
+            Err(e).map_err(err!("{}", String::from_utf8_lossy(id)))?;
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+    Ok(())
+}
+
+/// 在 `target` 上同时进行所有传输和转发
+async fn transfer(
+    opt: &Opt,
+    target: Arc<Target>,
+    listeners: Vec<(TcpListener, Forward)>,
+    udp_sockets: Vec<(UdpSocket, Forward)>,
+    tun: Option<Tun>,
+) -> Result<()> {
+    let mut forwards: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, spec)| tokio::spawn(forward_port(Arc::clone(&target), listener, spec)))
+        .collect();
+    for (sock, spec) in udp_sockets {
+        let target = Arc::clone(&target);
+        forwards.push(tokio::spawn(forward_udp_port(target, sock, spec)));
+    }
+    if let Some(tun) = tun {
+        let channel = target.open().await?;
+        forwards.push(tokio::spawn(async move { tunnel(channel, &tun).await }));
+    }
+    let mut tasks = Vec::new();
+    for file in opt.send.clone() {
+        let channel = target.open().await?;
+        let name = opt.name.clone();
+        let conflict = opt.conflict;
+        let via = opt
+            .via
+            .is_some()
+            .then(|| opt.id.clone().unwrap_or_default().into_bytes());
+        let from = opt.from.clone().unwrap_or_default();
+        let token = opt.token.clone().unwrap_or_default().into_bytes();
+        tasks.push(tokio::spawn(async move {
+            if let Some(to) = via {
+                deposit(channel, to, token, &file, &from)
+                    .await
+                    .ctx("file", file.display())
+            } else if is_stdio(&file) {
+                send_stream(channel, stdin(), name, conflict, &from).await
+            } else {
+                send(channel, &file, conflict, &from)
+                    .await
+                    .ctx("file", file.display())
+            }
+        }));
+    }
+    if let Some(ref path) = opt.list {
+        tasks.push(tokio::spawn(list(target.open().await?, path.clone())));
+    }
+    let dir = (!is_stdio(&opt.output)).then(|| opt.output.clone());
+    for path in opt.fetch.clone() {
+        let channel = target.open().await?;
+        let dir = dir.clone();
+        let conflict = opt.conflict;
+        tasks.push(tokio::spawn(async move {
+            fetch(channel, path.clone(), dir, conflict)
+                .await
+                .ctx("file", path)
+        }));
+    }
+
+    let total = tasks.len();
+    let mut failed = 0;
+    for task in tasks {
+        if let Err(e) = task.await.map_err(err!())? {
+            error!("{}", e);
+            failed += 1;
+        }
+    }
+    // 一直转发，直到出错或者对方断开
+    let wait = async {
+        for task in forwards {
+            task.await.map_err(err!())??;
+        }
+        Ok(())
+    };
+    tokio::select! {
+        result = wait => result?,
+        _ = target.wait_dead() => {
+            Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!("peer is unreachable"))?;
+        }
+    }
+    target.close().await;
+    if failed > 0 {
+        let e = io::Error::other(format!("{} of {} transfers failed", failed, total));
+        Err(e).map_err(err!())?;
+    }
+    Ok(())
+}
+
+/// 路径是否表示标准输入/输出
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// 是否作为注册端，等待对方连接
+fn is_service(opt: &Opt) -> bool {
+    opt.receive.is_some()
+        || opt.serve.is_some()
+        || !opt.allow_forward.is_empty()
+        || opt.accept_tun.is_some()
+        || opt.mesh.is_some()
+        || opt.mailbox.is_some()
+        || opt.daemon.is_some()
+}
+
+/// 是否有发起端的传输或转发
+fn is_initiator(opt: &Opt) -> bool {
+    !opt.send.is_empty()
+        || opt.list.is_some()
+        || !opt.fetch.is_empty()
+        || !opt.forward.is_empty()
+        || !opt.forward_udp.is_empty()
+}
+
+/// 创建并配置 TUN 设备
+fn create_tun(name: &str, addr: &str) -> Result<Tun> {
+    let tun = Tun::create(name)?;
+    tun.configure(addr, TUN_MTU)?;
+    info!("tun {} is up, address {}", tun.name(), addr);
+    Ok(tun)
+}
+
+/// 等待直到超过 `SESSION_DEAD_DURATION` 没有收到对方数据
+async fn wait_dead(transport: &Transport) {
+    while transport.idle_time() < SESSION_DEAD_DURATION {
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// 把本地端口的连接转发到对方
+async fn forward_port(target: Arc<Target>, listener: TcpListener, spec: Forward) -> Result<()> {
+    info!("forward {}", spec);
+    loop {
+        let (tcp, addr) = listener.accept().await.map_err(err!())?;
+        info!("accept {} on port {}", addr, spec.port);
+        let target = Arc::clone(&target);
+        let host = spec.host.clone();
+        let port = spec.remote_port;
+        // 通过中继成员访问时需要先和中继成员握手
+        tokio::spawn(async move {
+            let result = match target.open().await {
+                Ok(channel) => forward(channel, tcp, host, port).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+            }
+        });
+    }
+}
+
+/// 把本地 UDP 端口的数据报转发到对方，每个来源地址使用一个通道
+async fn forward_udp_port(target: Arc<Target>, sock: UdpSocket, spec: Forward) -> Result<()> {
+    info!("forward udp {}", spec);
+    let sock = Arc::new(sock);
+    let mut flows: HashMap<SocketAddr, Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (n, src) = sock.recv_from(&mut buf).await.map_err(err!())?;
+        let mut data = buf[..n].to_vec();
+        if let Some(tx) = flows.get(&src) {
+            match tx.try_send(data) {
+                // 缓存已满时和 UDP 一样丢弃
+                Ok(()) | Err(TrySendError::Full(_)) => continue,
+                // 已过期，重新建立
+                Err(TrySendError::Closed(v)) => data = v,
+            }
+        }
+
+        flows.retain(|_, tx| !tx.is_closed());
+        let (tx, mut rx) = channel(FLOW_CAPACITY);
+        tx.try_send(data).unwrap();
+        flows.insert(src, tx);
+
+        let target = Arc::clone(&target);
+        let sock = Arc::clone(&sock);
+        let host = spec.host.clone();
+        let port = spec.remote_port;
+        tokio::spawn(async move {
+            let result = match target.open().await {
+                Ok(channel) => forward_udp(channel, sock, src, &mut rx, host, port).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+                // 丢弃该来源的数据报直到空闲，防止每个数据报都重新请求
+                while let Ok(Some(_)) = timeout(UDP_IDLE_DURATION, rx.recv()).await {}
+            }
+        });
+    }
+}
+
+/// 向对方打洞，成功后 `sock` 连接对方
+async fn punch(sock: &mut Socket, peer_addr: SocketAddr, buf: &mut [u8]) -> Result<()> {
+    let ttl = sock.as_ref().ttl().map_err(err!())?;
+    sock.as_ref().set_ttl(6).map_err(err!())?;
+    sock.send_to(&Hello, peer_addr).await.map_err(err!())?;
+    sock.as_ref().set_ttl(ttl).map_err(err!())?;
+
+    std::thread::sleep(Duration::from_millis(50));
+    sock.send_to(&Hello, peer_addr).await.map_err(err!())?;
+    let deadline = Instant::now() + PUNCH_HOLE_DURATION;
+    loop {
+        tokio::select! {
+            recv = sock.recv_from(buf) => {
+                let (msg, src) = recv.map_err(err!())?;
+                match msg {
+                    Hello if src == peer_addr => {
+                        sock.connect(peer_addr).await?;
+                        sock.send(&HelloAck).await.map_err(err!())?;
+                        break;
+                    }
+                    _ => {}
+                }
+                sock.send(&Hello).await.map_err(err!())?;
+            }
+            _ = sleep(Duration::from_millis(100)) => {
+                if Instant::now() < deadline {
+                    sock.send_to(&Hello, peer_addr).await.map_err(err!())?;
+                } else {
+                    Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!("punch hole with {} failed", peer_addr))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 是否把接收的数据输出到标准输出
+fn is_stdout(service: &Service) -> bool {
+    matches!(service.receive, Some(ref dir) if is_stdio(dir))
+}
+
+/// 响应 `peer_addr` 的查询并和它打洞，`rx` 收到重复的 Request 的 nonce
 async fn handle_punch(
     server_addr: SocketAddr,
     peer_addr: SocketAddr,
-    mut rx: UnboundedReceiver<()>,
-    dir: PathBuf,
+    nonce: u64,
+    mut rx: UnboundedReceiver<u64>,
+    service: Service,
+    identity: Option<&Identity>,
+    mesh: Option<Arc<Mesh>>,
 ) -> Result<()> {
     let mut sock = Socket::new_unspecified().await?;
-    let response = Response { peer_addr };
+    let fingerprint = identity.map(Identity::fingerprint);
+    let mut response = Response {
+        peer_addr,
+        nonce,
+        fingerprint: fingerprint.clone(),
+    };
     sock.send_to(&response, server_addr).await.map_err(err!())?;
 
     let mut buf = vec![0u8; RECV_BUF_SIZE];
@@ -192,7 +1220,12 @@ async fn handle_punch(
                     _ => {}
                 }
             }
-            _ = rx.recv(), if !hello => {
+            Some(nonce) = rx.recv(), if !hello => {
+                response = Response {
+                    peer_addr,
+                    nonce,
+                    fingerprint: fingerprint.clone(),
+                };
                 server_ack = false;
                 sock.as_ref().set_ttl(default_ttl).map_err(err!())?;
                 sock.send_to(&response, server_addr).await.map_err(err!())?;
@@ -215,7 +1248,121 @@ async fn handle_punch(
     }
     sock.connect(peer_addr).await?;
 
-    receive(sock, dir).await
+    let transport = Arc::new(Transport::server(sock, identity).await?);
+    // 网络成员打开的第一个通道是控制通道
+    let control = match mesh {
+        Some(mesh) => match timeout(SESSION_IDLE_DURATION, transport.accept()).await {
+            Ok(Some(channel)) => {
+                let session = mesh.run_session(Arc::clone(&transport), channel);
+                Some(tokio::spawn(session))
+            }
+            _ => Err(io::Error::from(ErrorKind::TimedOut))
+                .map_err(err!("no mesh control channel"))?,
+        },
+        None => None,
+    };
+    serve_session(transport, service, peer_addr, control).await
+}
+
+/// 处理对方打开的通道，直到会话结束。`control` 为网络成员的控制通道任务，结束时会话结束
+async fn serve_session(
+    transport: Arc<Transport>,
+    service: Service,
+    peer_addr: SocketAddr,
+    mut control: Option<JoinHandle<Result<()>>>,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            channel = transport.accept() => {
+                let channel = match channel {
+                    Some(v) => v,
+                    None => return wait_control(&mut control).await,
+                };
+                // 输出到标准输出时只接收一次
+                if is_stdout(&service) {
+                    let result = respond_stream(channel, stdout(), &service).await;
+                    transport.close().await;
+                    return result;
+                }
+                let service = service.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = respond(channel, &service).await {
+                        error!("{}", e);
+                    }
+                });
+            }
+            result = wait_control(&mut control), if control.is_some() => {
+                info!("session with {} closed", peer_addr);
+                return result;
+            }
+            _ = sleep(SESSION_IDLE_DURATION), if control.is_none() => {
+                if transport.channel_count() == 0 && transport.idle_time() >= SESSION_IDLE_DURATION {
+                    info!("session with {} closed", peer_addr);
+                    return Ok(());
+                }
+            }
+            _ = wait_dead(&transport), if control.is_none() => {
+                info!("session with {} is dead", peer_addr);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// 等待控制通道任务结束，没有控制通道时立即返回
+async fn wait_control(control: &mut Option<JoinHandle<Result<()>>>) -> Result<()> {
+    match control {
+        Some(v) => v.await.map_err(err!())?,
+        None => Ok(()),
+    }
+}
+
+/// 和 id 比自己大的成员打洞，id 小的一方发起，防止双方同时发起
+fn connect_members(
+    server_addr: SocketAddr,
+    namespace: &Namespace,
+    mesh: &Arc<Mesh>,
+    service: &Service,
+    attempts: &mut HashMap<Vec<u8>, Instant>,
+) {
+    for peer_id in mesh.members() {
+        if peer_id.as_slice() <= mesh.id() || mesh.is_connected(&peer_id) {
+            continue;
+        }
+        if matches!(attempts.get(&peer_id), Some(v) if v.elapsed() < MESH_RETRY_DURATION) {
+            continue;
+        }
+        attempts.insert(peer_id.clone(), Instant::now());
+        let namespace = namespace.clone();
+        let mesh = Arc::clone(mesh);
+        let service = service.clone();
+        tokio::spawn(async move {
+            let name = String::from_utf8_lossy(&peer_id).to_string();
+            if let Err(e) = connect_member(server_addr, namespace, mesh, peer_id, service).await {
+                error!("mesh peer {}: {}", name, e);
+            }
+        });
+    }
+}
+
+/// 和一个成员打洞，建立会话
+async fn connect_member(
+    server_addr: SocketAddr,
+    namespace: Namespace,
+    mesh: Arc<Mesh>,
+    peer_id: Vec<u8>,
+    service: Service,
+) -> Result<()> {
+    let mut sock = Socket::new_unspecified().await?;
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let mut op = Lookup::new(&sock, server_addr, &namespace, &peer_id, &mut buf);
+    let (peer_addr, fingerprint) = perform(&mut op).await.map_err(err!("lookup"))?;
+    punch(&mut sock, peer_addr, &mut buf).await?;
+
+    let transport = Arc::new(Transport::client(sock, fingerprint).await?);
+    let control = transport.open().await?;
+    let session = tokio::spawn(mesh.run_session(Arc::clone(&transport), control));
+    serve_session(transport, service, peer_addr, Some(session)).await
 }
 
 /// 检测是否是对称型 NAT
@@ -260,7 +1407,12 @@ impl<'a> Operation<()> for DetectSymmetricNat<'a> {
 
     async fn resolve(&mut self) -> io::Result<()> {
         loop {
-            if let (Address(addr), src) = self.socket.recv_from(&mut self.buf).await? {
+            let (msg, src) = self.socket.recv_from(self.buf).await?;
+            if let Error { code, reason } = msg {
+                if src == self.server_addr1 || src == self.server_addr2 {
+                    return Err(ServerError { code, reason }.into());
+                }
+            } else if let Address(addr) = msg {
                 if src == self.server_addr1 {
                     self.addr1 = Some(addr);
                 } else if src == self.server_addr2 {
@@ -269,12 +1421,12 @@ impl<'a> Operation<()> for DetectSymmetricNat<'a> {
                     continue;
                 }
 
-                if self.addr1.is_some() && self.addr2.is_some() {
-                    info!("address: {} {}", self.addr1.unwrap(), self.addr2.unwrap());
-                    return if self.addr1 == self.addr2 {
+                if let (Some(addr1), Some(addr2)) = (self.addr1, self.addr2) {
+                    info!("address: {} {}", addr1, addr2);
+                    return if addr1 == addr2 {
                         Ok(())
                     } else {
-                        Err(io::Error::new(ErrorKind::Other, "symmetric nat"))
+                        Err(io::Error::other("symmetric nat"))
                     };
                 }
             }
@@ -290,23 +1442,78 @@ pub struct Register<'a> {
 }
 
 impl<'a> Register<'a> {
-    pub fn new(socket: &'a Socket, id: &Vec<u8>, buf: &'a mut [u8]) -> Self {
-        let msg = Message::Register { id: id.clone() };
+    pub fn new(socket: &'a Socket, namespace: &Namespace, id: &[u8], buf: &'a mut [u8]) -> Self {
+        let msg = Message::Register {
+            namespace: namespace.clone(),
+            id: id.to_vec(),
+        };
         Self { socket, msg, buf }
     }
 }
 
 #[async_trait]
-impl<'a> Operation<()> for Register<'a> {
+impl<'a> Operation<u64> for Register<'a> {
     async fn poll(&mut self) -> io::Result<()> {
         self.socket.send(&self.msg).await
     }
 
-    async fn resolve(&mut self) -> io::Result<()> {
+    /// 返回注册有效期（秒）
+    async fn resolve(&mut self) -> io::Result<u64> {
         loop {
-            if let RegisterAck = self.socket.recv(&mut self.buf).await? {
-                info!("register ok");
-                return Ok(());
+            match self.socket.recv(self.buf).await? {
+                RegisterAck { ttl } => {
+                    if let Message::Register { ref id, .. } = self.msg {
+                        info!("register {} ok, ttl {}s", String::from_utf8_lossy(id), ttl);
+                    }
+                    return Ok(ttl);
+                }
+                Error { code, reason } => return Err(ServerError { code, reason }.into()),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// peer 加入网络
+pub struct Join<'a> {
+    socket: &'a Socket,
+    msg: Message,
+    buf: &'a mut [u8],
+}
+
+impl<'a> Join<'a> {
+    pub fn new(
+        socket: &'a Socket,
+        namespace: &Namespace,
+        network: &[u8],
+        id: &[u8],
+        buf: &'a mut [u8],
+    ) -> Self {
+        let msg = Message::Join {
+            namespace: namespace.clone(),
+            network: network.to_vec(),
+            id: id.to_vec(),
+        };
+        Self { socket, msg, buf }
+    }
+}
+
+#[async_trait]
+impl<'a> Operation<Vec<Vec<u8>>> for Join<'a> {
+    async fn poll(&mut self) -> io::Result<()> {
+        self.socket.send(&self.msg).await
+    }
+
+    /// 返回第一个 Members 消息中的成员
+    async fn resolve(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        loop {
+            match self.socket.recv(self.buf).await? {
+                Members(ids) => {
+                    info!("join ok");
+                    return Ok(ids);
+                }
+                Error { code, reason } => return Err(ServerError { code, reason }.into()),
+                _ => {}
             }
         }
     }
@@ -324,14 +1531,88 @@ impl<'a> Lookup<'a> {
     pub fn new(
         socket: &'a Socket,
         server_addr: SocketAddr,
-        peer_id: &Vec<u8>,
+        namespace: &Namespace,
+        peer_id: &[u8],
+        buf: &'a mut [u8],
+    ) -> Self {
+        let namespace = namespace.clone();
+        let peer_id = peer_id.to_vec();
+        let msg = Message::Lookup { namespace, peer_id };
+        Self {
+            socket,
+            server_addr,
+            msg,
+            buf,
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> Operation<(SocketAddr, Option<Vec<u8>>)> for Lookup<'a> {
+    /// 服务器等待被查询的 peer 响应的时间较长，查询的总时间应超过该时间
+    const RETRY_COUNT: usize = 5;
+    const RETRY_DURATION: Duration = Duration::from_millis(500);
+
+    async fn poll(&mut self) -> io::Result<()> {
+        self.socket.send_to(&self.msg, self.server_addr).await
+    }
+
+    /// 返回 peer 外网地址和证书指纹。peer 未注册或没有响应时返回 `NotFound` 错误
+    async fn resolve(&mut self) -> io::Result<(SocketAddr, Option<Vec<u8>>)> {
+        loop {
+            match self.socket.recv_from(self.buf).await? {
+                (
+                    Peer {
+                        addr,
+                        fingerprint,
+                        reason,
+                    },
+                    src,
+                ) if src == self.server_addr => {
+                    return match addr {
+                        Some(addr) => Ok((addr, fingerprint)),
+                        None => {
+                            let reason = reason.unwrap_or_else(|| "peer not found".to_string());
+                            Err(io::Error::new(ErrorKind::NotFound, reason))
+                        }
+                    };
+                }
+                (Error { code, reason }, src) if src == self.server_addr => {
+                    return Err(ServerError { code, reason }.into());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// 订阅 peer 的上线通知
+pub struct Subscribe<'a> {
+    socket: &'a Socket,
+    server_addr: SocketAddr,
+    peer_id: Vec<u8>,
+    msg: Message,
+    buf: &'a mut [u8],
+}
+
+impl<'a> Subscribe<'a> {
+    pub fn new(
+        socket: &'a Socket,
+        server_addr: SocketAddr,
+        namespace: &Namespace,
+        peer_id: &[u8],
         buf: &'a mut [u8],
     ) -> Self {
-        let peer_id = peer_id.clone();
-        let msg = Message::Lookup { peer_id };
+        let namespace = namespace.clone();
+        let peer_id = peer_id.to_vec();
+        let msg = Message::Subscribe {
+            namespace,
+            peer_id: peer_id.clone(),
+        };
         Self {
             socket,
             server_addr,
+            peer_id,
             msg,
             buf,
         }
@@ -339,16 +1620,21 @@ impl<'a> Lookup<'a> {
 }
 
 #[async_trait]
-impl<'a> Operation<Option<SocketAddr>> for Lookup<'a> {
+impl<'a> Operation<Option<u64>> for Subscribe<'a> {
     async fn poll(&mut self) -> io::Result<()> {
         self.socket.send_to(&self.msg, self.server_addr).await
     }
 
-    async fn resolve(&mut self) -> io::Result<Option<SocketAddr>> {
+    /// 返回订阅有效期（秒），peer 已经在线时返回 `None`
+    async fn resolve(&mut self) -> io::Result<Option<u64>> {
         loop {
-            match self.socket.recv_from(&mut self.buf).await? {
-                (Peer { addr }, src) if src == self.server_addr => {
-                    return Ok(addr);
+            match self.socket.recv_from(self.buf).await? {
+                (SubscribeAck { ttl }, src) if src == self.server_addr => return Ok(Some(ttl)),
+                (Online { peer_id: v }, src) if src == self.server_addr && v == self.peer_id => {
+                    return Ok(None);
+                }
+                (Error { code, reason }, src) if src == self.server_addr => {
+                    return Err(ServerError { code, reason }.into());
                 }
                 _ => {}
             }
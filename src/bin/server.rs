@@ -1,110 +1,1068 @@
 //! 外网服务器，协调打洞
 
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::env::{set_var, var};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use log::{error, info};
+use log::{debug, error, info, warn};
 use structopt::StructOpt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::interval;
 
-use udp_hole_punching::util::{init_logger, runtime};
+use udp_hole_punching::admin::{self, Command, Counters, PeerStatus, Status};
+use udp_hole_punching::cluster;
+use udp_hole_punching::config::{Auth, Config, Limits, Listen};
+use udp_hole_punching::rate_limit::RateLimiter;
+use udp_hole_punching::registry::{display_id, Full, Network, Registration, Registry};
+use udp_hole_punching::util::{init_logger, random_u64, runtime, shutdown, Hangup};
 use udp_hole_punching::Message::*;
-use udp_hole_punching::{cont, Result, Socket};
+use udp_hole_punching::{err, ErrorCode, Message, Namespace, Result, Socket, MAX_ID_LEN};
 
 #[derive(StructOpt)]
 struct Opt {
+    /// 配置文件（TOML），收到 SIGHUP 时重新加载。指定后不能使用其他选项
+    #[structopt(
+        long,
+        conflicts_with_all(&[
+            "addr",
+            "addr2",
+            "registry",
+            "cluster",
+            "cluster-secret",
+            "rate",
+            "request-rate",
+            "max-peers",
+            "admin",
+            "peer-ttl"
+        ])
+    )]
+    config: Option<PathBuf>,
+
     /// 绑定地址，格式：ip:端口
-    #[structopt(long)]
-    addr: SocketAddr,
+    #[structopt(long, required_unless("config"))]
+    addr: Option<SocketAddr>,
 
     /// 绑定地址，格式：ip:端口
+    #[structopt(long, required_unless("config"))]
+    addr2: Option<SocketAddr>,
+
+    /// 注册信息快照文件，启动时加载，运行时定期保存，重启后 peer 不必重新注册
+    #[structopt(long)]
+    registry: Option<PathBuf>,
+
+    /// 其他外网服务器的 `--addr`，可指定多个。服务器之间同步注册信息，
+    /// 注册在一个服务器上的 peer 可以通过其他服务器查询
+    #[structopt(long, requires("cluster-secret"))]
+    cluster: Vec<SocketAddr>,
+
+    /// 集群中所有服务器共用的密钥，服务器之间的消息使用它认证
+    #[structopt(long)]
+    cluster_secret: Option<String>,
+
+    /// 每个来源 IP 每秒最多处理的消息数，超过的消息被丢弃。通过认证的集群消息不限速
+    #[structopt(long, default_value = "20")]
+    rate: f64,
+
+    /// 每个 peer 每秒最多收到的打洞请求数，防止被其他 peer 的 Lookup 淹没
+    #[structopt(long, default_value = "5")]
+    request_rate: f64,
+
+    /// 最多注册的 peer 个数，注册表满时拒绝新的 peer
+    #[structopt(long, default_value = "65536")]
+    max_peers: usize,
+
+    /// 管理接口监听地址，提供注册的 peer、Prometheus 指标和注销 peer 的接口。
+    /// 没有认证，应只监听本机或内网地址
     #[structopt(long)]
-    addr2: SocketAddr,
+    admin: Option<SocketAddr>,
+
+    /// 注册信息有效期（秒），在 RegisterAck 中告知 peer，超过此时间没有重新注册的 peer 会被清除
+    #[structopt(long, default_value = "90")]
+    peer_ttl: u64,
+}
+
+impl Opt {
+    /// 命令行选项对应的配置
+    fn to_config(&self) -> Result<Config> {
+        let config = Config {
+            listen: vec![Listen {
+                addr: self.addr.unwrap(),
+                detect: self.addr2,
+            }],
+            cluster: self.cluster.clone(),
+            cluster_secret: self.cluster_secret.clone(),
+            registry: self.registry.clone(),
+            admin: self.admin,
+            peer_ttl: self.peer_ttl,
+            log: None,
+            limits: Limits {
+                rate: self.rate,
+                request_rate: self.request_rate,
+                max_peers: self.max_peers,
+                ..Limits::default()
+            },
+            auth: Auth::default(),
+            namespaces: Vec::new(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
 }
 
-const RECV_BUF_SIZE: usize = 256;
+/// 足够容纳带有命名空间和凭证的 Join
+const RECV_BUF_SIZE: usize = 512;
+
+/// 各个 socket 收到、等待处理的消息个数上限
+const PACKET_QUEUE_SIZE: usize = 1024;
+
+/// 一个 Members 消息中成员 id 的总长度上限，防止超过 MTU
+const MEMBERS_SIZE: usize = 1024;
+
+/// 保存注册信息快照的间隔
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 为其他服务器转发的 Request 等待 Response 的时间
+const CLUSTER_REQUEST_TTL: Duration = Duration::from_secs(30);
+
+/// 转发打洞请求后等待 Response 的时间，应小于 peer Lookup 的超时时间
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 检查未响应的打洞请求的间隔
+const PENDING_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// 限速允许的突发时长，突发消息数为速率乘以此时长
+const BURST_DURATION: f64 = 2.0;
+
+/// 限速记录的来源或 peer 个数上限
+const LIMITER_CAPACITY: usize = 65536;
+
+/// 清除过期注册信息和限速记录的间隔
+const GC_INTERVAL: Duration = Duration::from_secs(10);
 
 fn main() {
     let opt: Opt = Opt::from_args();
+    let config = match opt.config {
+        Some(ref path) => Config::load(path),
+        None => opt.to_config(),
+    };
+    // 配置文件指定日志级别时由 log::set_max_level 控制，重新加载时可以修改
+    if matches!(config, Ok(ref v) if v.log.is_some()) && var("RUST_LOG").is_err() {
+        set_var("RUST_LOG", "trace");
+    }
     init_logger();
     runtime(false).block_on(async {
-        if let Err(e) = run(opt).await {
+        let result = match config {
+            Ok(config) => run(config, opt.config).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
             error!("{}", e);
             exit(1);
         }
     })
 }
 
-async fn run(opt: Opt) -> Result<()> {
-    let sock = Socket::new(opt.addr).await?;
-    let sock2 = Socket::new(opt.addr2).await?;
-    info!("bind to {} {}", opt.addr, opt.addr2);
+async fn run(config: Config, path: Option<PathBuf>) -> Result<()> {
+    let (packets_tx, mut packets) = channel(PACKET_QUEUE_SIZE);
+    let (commands_tx, mut commands) = channel(16);
+    let mut server = Server::new(config, packets_tx, commands_tx).await?;
 
-    let mut buf = [0u8; RECV_BUF_SIZE];
-    let mut buf2 = [0u8; RECV_BUF_SIZE];
-    let mut peers = HashMap::new();
-    let mut peer_gc_at = Instant::now();
+    let mut pending_check = interval(PENDING_CHECK_INTERVAL);
+    let mut save = interval(SAVE_INTERVAL);
+    let mut gc = interval(GC_INTERVAL);
+    let mut hangup = Hangup::new()?;
+    let shutdown = shutdown();
+    tokio::pin!(shutdown);
 
     loop {
         tokio::select! {
-            recv = sock.recv_from(&mut buf) => {
-                let (msg, src) = cont!(recv);
-                match msg {
-                    // peer 查询外网地址
-                    Query => {
-                        cont!(sock.send_to(&Address(src), src).await);
+            Some(packet) = packets.recv() => server.handle(packet).await,
+            // 管理接口的命令
+            Some(command) = commands.recv() => server.command(command).await,
+            _ = pending_check.tick(), if !server.pending.is_empty() => server.check_pending().await,
+            _ = gc.tick() => server.gc(),
+            _ = save.tick() => {
+                if let Err(e) = server.save() {
+                    error!("{}", e);
+                }
+            }
+            // 重新加载配置文件，出错时保持原来的配置
+            _ = hangup.recv() => match path {
+                Some(ref path) => match Config::load(path) {
+                    Ok(config) => match server.apply(config).await {
+                        Ok(()) => info!("reload {}", path.display()),
+                        Err(e) => error!("{}", e),
+                    },
+                    Err(e) => error!("{}", e),
+                },
+                None => warn!("no config file to reload"),
+            },
+            _ = &mut shutdown => {
+                server.save()?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// socket 收到的消息
+struct Packet {
+    msg: Message,
+    src: SocketAddr,
+    sock: Arc<Socket>,
+    /// 收到消息的绑定地址
+    local: SocketAddr,
+    /// 是否是只用来检测 NAT 的 socket
+    detect: bool,
+}
+
+/// 一个绑定地址，删除时停止接收
+struct Listener {
+    sock: Arc<Socket>,
+    detect: bool,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// 管理接口
+struct Admin {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Admin {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// 已转发打洞请求、等待响应的查询
+struct PendingLookup {
+    /// 被查询的 peer 所在的命名空间
+    namespace: Vec<u8>,
+    /// 被查询的 peer
+    id: Vec<u8>,
+    /// 转发给被查询的 peer 的 nonce，只接受带有此 nonce 的 Response
+    nonce: u64,
+    deadline: Instant,
+    /// 收到查询的 socket，通过它回复发起查询的 peer
+    sock: Arc<Socket>,
+}
+
+/// 上线通知的订阅
+struct Subscription {
+    deadline: Instant,
+    /// 收到订阅的 socket，通过它通知订阅者
+    sock: Arc<Socket>,
+}
+
+/// 外网服务器状态
+struct Server {
+    config: Config,
+    /// 绑定地址 => socket
+    sockets: HashMap<SocketAddr, Listener>,
+    packets: Sender<Packet>,
+    registry: Registry,
+    sources: RateLimiter<IpAddr>,
+    targets: RateLimiter<SocketAddr>,
+    /// 为其他服务器转发了 Request 的查询：发起查询的 peer 的外网地址 => (所在服务器, 转发时间, nonce)
+    relayed: HashMap<SocketAddr, (SocketAddr, Instant, u64)>,
+    /// 等待被查询的 peer 响应的查询：发起查询的 peer 的外网地址 => 查询
+    pending: HashMap<SocketAddr, PendingLookup>,
+    /// 上线通知的订阅：(命名空间, 被订阅的 peer) => 订阅者的外网地址 => 订阅
+    subscriptions: HashMap<(Vec<u8>, Vec<u8>), HashMap<SocketAddr, Subscription>>,
+    /// 所有 peer 的订阅数
+    subscription_count: usize,
+    counters: Counters,
+    admin: Option<Admin>,
+    commands: Sender<Command>,
+}
+
+impl Server {
+    async fn new(
+        config: Config,
+        packets: Sender<Packet>,
+        commands: Sender<Command>,
+    ) -> Result<Self> {
+        let ttl = Duration::from_secs(config.peer_ttl);
+        let limits = &config.limits;
+        let registry = match config.registry {
+            Some(ref path) => Registry::load(path, ttl, limits.max_peers)?,
+            None => Registry::new(ttl, limits.max_peers),
+        };
+        let sources = RateLimiter::new(limits.rate, limits.rate * BURST_DURATION, LIMITER_CAPACITY);
+        let targets = RateLimiter::new(
+            limits.request_rate,
+            limits.request_rate * BURST_DURATION,
+            LIMITER_CAPACITY,
+        );
+        let mut server = Self {
+            config: config.clone(),
+            sockets: HashMap::new(),
+            packets,
+            registry,
+            sources,
+            targets,
+            relayed: HashMap::new(),
+            pending: HashMap::new(),
+            subscriptions: HashMap::new(),
+            subscription_count: 0,
+            counters: Counters::default(),
+            admin: None,
+            commands,
+        };
+        server.apply(config).await?;
+        Ok(server)
+    }
+
+    /// 使用新的配置。先绑定新的地址，任何一个失败时保持原来的配置
+    async fn apply(&mut self, config: Config) -> Result<()> {
+        let mut added = Vec::new();
+        for (addr, detect) in config.sockets() {
+            let sock = match self.sockets.get(&addr) {
+                Some(v) if v.detect == detect => continue,
+                // 用途改变，重新接收
+                Some(v) => Arc::clone(&v.sock),
+                None => Arc::new(Socket::new(addr).await?),
+            };
+            added.push((addr, detect, sock));
+        }
+        let admin = match config.admin {
+            Some(addr) if self.admin.as_ref().map(|v| v.addr) != Some(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .map_err(err!("cannot listen on {}", addr))?;
+                Some(Some((addr, listener)))
+            }
+            Some(_) => None,
+            None => Some(None),
+        };
+
+        let wanted: HashSet<_> = config.sockets().into_iter().map(|v| v.0).collect();
+        self.sockets.retain(|addr, _| {
+            let keep = wanted.contains(addr);
+            if !keep {
+                info!("stop listening on {}", addr);
+            }
+            keep
+        });
+        for (addr, detect, sock) in added {
+            info!(
+                "listen on {}{}",
+                addr,
+                if detect { " for detection" } else { "" }
+            );
+            let packets = self.packets.clone();
+            let task = tokio::spawn(listen(Arc::clone(&sock), addr, detect, packets));
+            self.sockets.insert(addr, Listener { sock, detect, task });
+        }
+        if let Some(admin) = admin {
+            self.admin = admin.map(|(addr, listener)| {
+                info!("admin listen on {}", addr);
+                let commands = self.commands.clone();
+                let task = tokio::spawn(async move {
+                    if let Err(e) = admin::serve(listener, commands).await {
+                        error!("{}", e);
                     }
-                    // peer 注册
-                    Register { id } => {
-                        peers.insert(id, (src, Instant::now()));
-                        cont!(sock.send_to(&RegisterAck, src).await);
+                });
+                Admin { addr, task }
+            });
+        }
+
+        let limits = &config.limits;
+        let ttl = Duration::from_secs(config.peer_ttl);
+        self.registry
+            .set_limits(ttl, limits.max_peers, config.quotas());
+        self.sources
+            .set_rate(limits.rate, limits.rate * BURST_DURATION);
+        self.targets
+            .set_rate(limits.request_rate, limits.request_rate * BURST_DURATION);
+        if let Some(level) = config.log_level() {
+            log::set_max_level(level);
+        }
+        if !config.cluster.is_empty() {
+            info!("cluster: {:?}", config.cluster);
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    /// 和集群中的其他服务器通信的 socket，即第一个监听地址
+    fn cluster_socket(&self) -> Arc<Socket> {
+        Arc::clone(&self.sockets[&self.config.listen[0].addr].sock)
+    }
+
+    /// peer 注册时连接的 socket，已不再监听时使用第一个监听地址
+    fn peer_socket(&self, local: Option<SocketAddr>) -> Arc<Socket> {
+        match local.and_then(|v| self.sockets.get(&v)) {
+            Some(v) if !v.detect => Arc::clone(&v.sock),
+            _ => self.cluster_socket(),
+        }
+    }
+
+    /// 使用集群密钥封装服务器之间的消息
+    fn seal(&self, msg: &Message) -> Message {
+        let secret = self.config.cluster_secret.as_deref().unwrap_or_default();
+        cluster::seal(secret.as_bytes(), msg)
+    }
+
+    /// 向集群中的一个服务器发送消息
+    async fn send_cluster(&self, msg: &Message, node: SocketAddr) {
+        send_to(&self.cluster_socket(), &self.seal(msg), node).await;
+    }
+
+    /// 向集群中的其他服务器发送消息
+    async fn broadcast(&self, msg: &Message) {
+        if self.config.cluster.is_empty() {
+            return;
+        }
+        let sock = self.cluster_socket();
+        let msg = self.seal(msg);
+        for node in &self.config.cluster {
+            send_to(&sock, &msg, *node).await;
+        }
+    }
+
+    async fn handle(&mut self, packet: Packet) {
+        let Packet {
+            msg,
+            src,
+            sock,
+            local,
+            detect,
+        } = packet;
+        // 只有来自集群中的服务器并且通过认证的消息才按服务器之间的消息处理，不限速
+        let (msg, from_cluster) = match msg {
+            Cluster {
+                time,
+                ref msg,
+                ref mac,
+            } if !detect && self.config.cluster.contains(&src) => {
+                let secret = self.config.cluster_secret.as_deref().unwrap_or_default();
+                match cluster::open(secret.as_bytes(), time, msg, mac) {
+                    Some(v) => (v, true),
+                    None => {
+                        debug!("drop cluster message from {}: authentication failed", src);
+                        self.counters.cluster_rejected += 1;
+                        return;
                     }
-                    // peer 查询另一个 peer 的外网地址
-                    Lookup { peer_id } => match peers.get(&peer_id) {
-                        Some((addr, _)) => {
-                            cont!(sock.send_to(&Request { peer_addr: src }, *addr).await);
+                }
+            }
+            msg => (msg, false),
+        };
+        if !from_cluster && !self.sources.check(src.ip()) {
+            debug!("drop {:?} from {}: rate limited", msg, src);
+            self.counters.rate_limited += 1;
+            return;
+        }
+        if detect {
+            match msg {
+                // peer 查询外网地址
+                Query => {
+                    self.counters.queries += 1;
+                    send_to(&sock, &Address(src), src).await;
+                }
+                Error { .. } => {}
+                // 原因保持简短，减小回复的放大倍数
+                _ => {
+                    let reason = "only Query is accepted";
+                    reply_error(&sock, src, ErrorCode::Unsupported, reason).await;
+                }
+            }
+            return;
+        }
+
+        let local = Some(local);
+        match msg {
+            // peer 查询外网地址
+            Query => {
+                self.counters.queries += 1;
+                send_to(&sock, &Address(src), src).await;
+            }
+            Register {
+                ref namespace,
+                ref id,
+            }
+            | Lookup {
+                ref namespace,
+                peer_id: ref id,
+            }
+            | Subscribe {
+                ref namespace,
+                peer_id: ref id,
+            } if !is_valid_id(id) || !is_valid_namespace(namespace) => {
+                reply_error(&sock, src, ErrorCode::InvalidId, invalid_id_reason()).await;
+            }
+            Join {
+                ref namespace,
+                ref network,
+                ref id,
+            } if !is_valid_id(network) || !is_valid_id(id) || !is_valid_namespace(namespace) => {
+                reply_error(&sock, src, ErrorCode::InvalidId, invalid_id_reason()).await;
+            }
+            // 不区分命名空间不存在和凭证错误
+            Register { ref namespace, .. } | Join { ref namespace, .. }
+                if !self.config.is_authorized(namespace, false) =>
+            {
+                let reason = unauthorized_reason(namespace);
+                reply_error(&sock, src, ErrorCode::Unauthorized, reason).await;
+            }
+            Lookup { ref namespace, .. } | Subscribe { ref namespace, .. }
+                if !self.config.is_authorized(namespace, true) =>
+            {
+                let reason = unauthorized_reason(namespace);
+                reply_error(&sock, src, ErrorCode::Unauthorized, reason).await;
+            }
+            Register {
+                ref namespace,
+                ref id,
+            }
+            | Join {
+                ref namespace,
+                ref id,
+                ..
+            } if namespace.name.is_empty() && !self.config.auth.is_allowed(id) => {
+                let reason = format!("{} is not allowed", String::from_utf8_lossy(id));
+                reply_error(&sock, src, ErrorCode::Unauthorized, reason).await;
+            }
+            // peer 注册
+            Register { namespace, id } => {
+                self.counters.registers += 1;
+                let ns = namespace.name;
+                if let Err(full) = self.registry.register(&ns, id.clone(), src, None, local) {
+                    self.counters.registry_full += 1;
+                    let reason = full_reason(full, &ns);
+                    reply_error(&sock, src, ErrorCode::RegistryFull, reason).await;
+                    return;
+                }
+                let ttl = self.config.peer_ttl;
+                send_to(&sock, &RegisterAck { ttl }, src).await;
+                self.notify(&ns, &id).await;
+                let msg = Sync {
+                    namespace: ns,
+                    id,
+                    addr: src,
+                    network: None,
+                };
+                self.broadcast(&msg).await;
+            }
+            // peer 加入网络，回复成员列表
+            Join {
+                namespace,
+                network,
+                id,
+            } => {
+                self.counters.joins += 1;
+                let ns = namespace.name;
+                let registry = &mut self.registry;
+                let members =
+                    match registry.join(&ns, network.clone(), id.clone(), src, None, local) {
+                        Ok(v) => v,
+                        Err(full) => {
+                            self.counters.registry_full += 1;
+                            let reason = full_reason(full, &ns);
+                            reply_error(&sock, src, ErrorCode::RegistryFull, reason).await;
+                            return;
                         }
-                        None => cont!(sock.send_to(&Peer { addr: None }, src).await),
+                    };
+                for ids in split_members(members) {
+                    send_to(&sock, &Members(ids), src).await;
+                }
+                self.notify(&ns, &id).await;
+                let msg = Sync {
+                    namespace: ns,
+                    id,
+                    addr: src,
+                    network: Some(network),
+                };
+                self.broadcast(&msg).await;
+            }
+            // peer 退出时注销，或者其他服务器转发注销
+            Unregister { namespace, id } => {
+                let owned = match self.registry.lookup(&namespace, &id) {
+                    Some(v) if from_cluster => v.node == Some(src),
+                    Some(v) => v.node.is_none() && v.addr == src,
+                    None => false,
+                };
+                if owned {
+                    info!("unregister {}", display_id(&namespace, &id));
+                    self.counters.unregisters += 1;
+                    self.registry.unregister(&namespace, &id);
+                    if !from_cluster {
+                        self.broadcast(&Unregister { namespace, id }).await;
+                    }
+                }
+            }
+            // 其他服务器同步注册信息
+            Sync {
+                namespace,
+                id,
+                addr,
+                network,
+            } if from_cluster => {
+                self.counters.syncs += 1;
+                let node = Some(src);
+                let result = match network {
+                    Some(network) => self
+                        .registry
+                        .join(&namespace, network, id.clone(), addr, node, None)
+                        .map(|_| ()),
+                    None => {
+                        let registry = &mut self.registry;
+                        registry.register(&namespace, id.clone(), addr, node, None)
+                    }
+                };
+                match result {
+                    Ok(()) => self.notify(&namespace, &id).await,
+                    Err(_) => self.counters.registry_full += 1,
+                }
+            }
+            // peer 查询另一个 peer 的外网地址
+            Lookup { namespace, peer_id } => self.lookup(sock, src, namespace.name, peer_id).await,
+            // peer 订阅另一个 peer 的上线通知
+            Subscribe { namespace, peer_id } => {
+                self.subscribe(sock, src, namespace.name, peer_id).await
+            }
+            // 其他服务器请求转发 Request
+            ClusterRequest {
+                peer_addr,
+                namespace,
+                id,
+                addr,
+            } if from_cluster => {
+                let now = Instant::now();
+                self.relayed
+                    .retain(|_, v| now.duration_since(v.1) < CLUSTER_REQUEST_TTL);
+                if self.relayed.len() >= self.config.limits.max_relayed {
+                    debug!(
+                        "drop cluster request from {}: too many relayed requests",
+                        src
+                    );
+                    self.counters.rate_limited += 1;
+                    return;
+                }
+                // 重试的查询使用同一个 nonce，被查询的 peer 重发的 Response 仍然有效
+                let nonce = match self.relayed.get(&peer_addr) {
+                    Some(&(node, _, nonce)) if node == src => nonce,
+                    _ => random_u64(),
+                };
+                self.relayed.insert(peer_addr, (src, now, nonce));
+                self.counters.cluster_requests += 1;
+                if self.targets.check(addr) {
+                    self.counters.requests += 1;
+                    let registration = self.registry.lookup(&namespace, &id);
+                    let local = registration.and_then(|v| v.local);
+                    let msg = Request {
+                        peer_addr,
+                        namespace,
+                        id,
+                        nonce,
+                    };
+                    send_to(&self.peer_socket(local), &msg, addr).await;
+                } else {
+                    self.counters.rate_limited += 1;
+                }
+            }
+            // peer 响应查询。只有收到 Request 的 peer 知道 nonce，其他地址不能冒充它响应
+            Response {
+                peer_addr,
+                nonce,
+                fingerprint,
+            } => {
+                let node = match self.relayed.get(&peer_addr) {
+                    Some(&(node, _, v)) if v == nonce => Some(node),
+                    _ => None,
+                };
+                let pending = match self.pending.get(&peer_addr) {
+                    Some(v) if v.nonce == nonce => self.pending.remove(&peer_addr),
+                    _ => None,
+                };
+                let reply_sock = match pending {
+                    Some(v) => {
+                        self.registry.set_suspect(&v.namespace, &v.id, false);
+                        v.sock
+                    }
+                    None if node.is_some() => sock.clone(),
+                    None => {
+                        debug!("drop response from {}: no matching lookup", src);
+                        return;
+                    }
+                };
+                self.counters.responses += 1;
+                send_to(&sock, &ResponseAck, src).await;
+                match node {
+                    // 发起查询的 peer 在其他服务器上，由该服务器转发
+                    Some(node) => {
+                        let msg = ClusterPeer {
+                            to: peer_addr,
+                            addr: src,
+                            fingerprint,
+                        };
+                        self.send_cluster(&msg, node).await;
                     }
-                    // peer 响应查询
-                    Response { peer_addr } => {
-                        cont!(sock.send_to(&ResponseAck, src).await);
-                        cont!(sock.send_to(&Peer { addr: Some(src) }, peer_addr).await);
+                    None => {
+                        let msg = Peer {
+                            addr: Some(src),
+                            fingerprint,
+                            reason: None,
+                        };
+                        send_to(&reply_sock, &msg, peer_addr).await;
                     }
-                    _ => {}
                 }
             }
-            recv = sock2.recv_from(&mut buf2) => {
-                let (msg, src) = cont!(recv);
-                match msg {
-                    // peer 查询外网地址
-                    Query => {
-                        cont!(sock2.send_to(&Address(src), src).await);
+            // 其他服务器请求转发 Peer
+            ClusterPeer {
+                to,
+                addr,
+                fingerprint,
+            } if from_cluster => {
+                self.counters.cluster_peers += 1;
+                let reply_sock = match self.pending.remove(&to) {
+                    Some(v) => {
+                        self.registry.set_suspect(&v.namespace, &v.id, false);
+                        v.sock
+                    }
+                    None => self.cluster_socket(),
+                };
+                let msg = Peer {
+                    addr: Some(addr),
+                    fingerprint,
+                    reason: None,
+                };
+                send_to(&reply_sock, &msg, to).await;
+            }
+            Sync { .. } | ClusterRequest { .. } | ClusterPeer { .. } | Cluster { .. } => {
+                let reason = format!("{} is not in the cluster", src);
+                reply_error(&sock, src, ErrorCode::Unauthorized, reason).await;
+            }
+            // 不回复错误，防止两个服务器之间循环
+            Error { code, reason } => warn!("error from {}: {}: {}", src, code, reason),
+            _ => {
+                let reason = "unexpected message";
+                reply_error(&sock, src, ErrorCode::Unsupported, reason).await;
+            }
+        }
+    }
 
-                        // 清除不活跃的　peer
-                        if peers.len() > 256 && peer_gc_at.elapsed() > Duration::from_secs(600) {
-                            peer_gc_at = peer_gc(&mut peers);
-                        }
+    /// 处理 peer 查询：转发打洞请求给被查询的 peer，或者由它注册所在的服务器转发
+    async fn lookup(
+        &mut self,
+        sock: Arc<Socket>,
+        src: SocketAddr,
+        namespace: Vec<u8>,
+        peer_id: Vec<u8>,
+    ) {
+        self.counters.lookups += 1;
+        let name = display_id(&namespace, &peer_id);
+        match self.registry.lookup(&namespace, &peer_id) {
+            Some(Registration { suspect: true, .. }) => {
+                let reason = format!("{} is not responding", name);
+                let msg = Peer {
+                    addr: None,
+                    fingerprint: None,
+                    reason: Some(reason),
+                };
+                send_to(&sock, &msg, src).await;
+            }
+            // 限制转发给同一个 peer 的打洞请求
+            Some(Registration { addr, .. }) if !self.targets.check(addr) => {
+                debug!("drop lookup from {}: too many requests to {}", src, addr);
+                self.counters.rate_limited += 1;
+            }
+            Some(Registration {
+                addr, node, local, ..
+            }) => {
+                // 重试的 Lookup 不延长等待时间，使用同一个 nonce
+                let nonce = match self.pending.get(&src) {
+                    Some(v) if v.namespace == namespace && v.id == peer_id => v.nonce,
+                    _ if self.pending.len() >= LIMITER_CAPACITY => {
+                        debug!("drop lookup from {}: too many pending lookups", src);
+                        self.counters.rate_limited += 1;
+                        return;
+                    }
+                    _ => {
+                        let nonce = random_u64();
+                        let pending = PendingLookup {
+                            namespace: namespace.clone(),
+                            id: peer_id.clone(),
+                            nonce,
+                            deadline: Instant::now() + LOOKUP_TIMEOUT,
+                            sock,
+                        };
+                        self.pending.insert(src, pending);
+                        nonce
+                    }
+                };
+                match node {
+                    // 注册在其他服务器上，由该服务器转发
+                    Some(node) => {
+                        let msg = ClusterRequest {
+                            peer_addr: src,
+                            namespace,
+                            id: peer_id,
+                            addr,
+                        };
+                        self.send_cluster(&msg, node).await;
+                    }
+                    None => {
+                        self.counters.requests += 1;
+                        let msg = Request {
+                            peer_addr: src,
+                            namespace,
+                            id: peer_id,
+                            nonce,
+                        };
+                        send_to(&self.peer_socket(local), &msg, addr).await;
                     }
-                    _ => {}
                 }
             }
+            None => {
+                self.counters.lookup_misses += 1;
+                let reason = format!("{} is not registered", name);
+                let msg = Peer {
+                    addr: None,
+                    fingerprint: None,
+                    reason: Some(reason),
+                };
+                send_to(&sock, &msg, src).await;
+            }
         }
     }
+
+    /// 订阅 peer 的上线通知，peer 已注册并且没有被标记为可疑时立即通知
+    async fn subscribe(
+        &mut self,
+        sock: Arc<Socket>,
+        src: SocketAddr,
+        namespace: Vec<u8>,
+        peer_id: Vec<u8>,
+    ) {
+        self.counters.subscribes += 1;
+        if matches!(self.registry.lookup(&namespace, &peer_id), Some(v) if !v.suspect) {
+            self.counters.notifications += 1;
+            send_to(&sock, &Online { peer_id }, src).await;
+            return;
+        }
+        let key = (namespace, peer_id);
+        let exists = matches!(self.subscriptions.get(&key), Some(v) if v.contains_key(&src));
+        if !exists && self.subscription_count >= self.config.limits.max_subscriptions {
+            self.counters.registry_full += 1;
+            let reason = "too many subscriptions";
+            reply_error(&sock, src, ErrorCode::RegistryFull, reason).await;
+            return;
+        }
+        let ttl = self.config.peer_ttl;
+        let subscription = Subscription {
+            deadline: Instant::now() + Duration::from_secs(ttl),
+            sock: Arc::clone(&sock),
+        };
+        let subscribers = self.subscriptions.entry(key).or_default();
+        if subscribers.insert(src, subscription).is_none() {
+            self.subscription_count += 1;
+        }
+        send_to(&sock, &SubscribeAck { ttl }, src).await;
+    }
+
+    /// peer 注册后通知订阅者，结束订阅
+    async fn notify(&mut self, namespace: &[u8], id: &[u8]) {
+        if self.subscriptions.is_empty() {
+            return;
+        }
+        let key = (namespace.to_vec(), id.to_vec());
+        let Some(subscribers) = self.subscriptions.remove(&key) else {
+            return;
+        };
+        self.subscription_count -= subscribers.len();
+        info!(
+            "{} is online, notify {} subscribers",
+            display_id(namespace, id),
+            subscribers.len()
+        );
+        let now = Instant::now();
+        let msg = Online { peer_id: key.1 };
+        for (addr, v) in subscribers {
+            if v.deadline > now {
+                self.counters.notifications += 1;
+                send_to(&v.sock, &msg, addr).await;
+            }
+        }
+    }
+
+    /// 被查询的 peer 没有响应，通知发起查询的 peer，标记为可疑
+    async fn check_pending(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, v)| v.deadline <= now)
+            .map(|(k, _)| *k)
+            .collect();
+        for src in expired {
+            let v = self.pending.remove(&src).unwrap();
+            let name = display_id(&v.namespace, &v.id);
+            info!("{} did not respond to the request from {}", name, src);
+            self.counters.lookup_timeouts += 1;
+            self.registry.set_suspect(&v.namespace, &v.id, true);
+            let reason = format!("{} did not respond", name);
+            let msg = Peer {
+                addr: None,
+                fingerprint: None,
+                reason: Some(reason),
+            };
+            send_to(&v.sock, &msg, src).await;
+        }
+    }
+
+    async fn command(&mut self, command: Command) {
+        match command {
+            Command::Status(reply) => {
+                let status = Status {
+                    peers: self
+                        .registry
+                        .iter()
+                        .map(|(ns, id, v)| PeerStatus {
+                            namespace: ns.clone(),
+                            id: id.clone(),
+                            addr: v.addr,
+                            age: v.seen.elapsed(),
+                            node: v.node,
+                            suspect: v.suspect,
+                        })
+                        .collect(),
+                    networks: self.registry.networks(),
+                    subscriptions: self.subscription_count,
+                    counters: self.counters.clone(),
+                };
+                let _ = reply.send(status);
+            }
+            Command::Evict(namespace, id, reply) => {
+                let registration = self.registry.lookup(&namespace, &id);
+                if let Some(v) = registration {
+                    info!("evict {}", display_id(&namespace, &id));
+                    self.registry.unregister(&namespace, &id);
+                    // 只有注册在本服务器上的 peer 才能在其他服务器上注销
+                    if v.node.is_none() {
+                        self.broadcast(&Unregister { namespace, id }).await;
+                    }
+                }
+                let _ = reply.send(registration.is_some());
+            }
+        }
+    }
+
+    /// 清除不活跃的 peer、过期的订阅和限速记录
+    fn gc(&mut self) {
+        self.registry.gc();
+        let now = Instant::now();
+        self.subscriptions.retain(|_, subscribers| {
+            subscribers.retain(|_, v| v.deadline > now);
+            !subscribers.is_empty()
+        });
+        self.subscription_count = self.subscriptions.values().map(|v| v.len()).sum();
+        self.sources.gc();
+        self.targets.gc();
+    }
+
+    /// 保存注册信息快照
+    fn save(&mut self) -> Result<()> {
+        if let Some(ref path) = self.config.registry {
+            self.registry.save(path)?;
+            debug!("save {} peers to {}", self.registry.len(), path.display());
+        }
+        Ok(())
+    }
 }
 
-// 清除不活跃的　peer
-fn peer_gc(peers: &mut HashMap<Vec<u8>, (SocketAddr, Instant)>) -> Instant {
-    let now = Instant::now();
-    let mut gc = Vec::new();
-    for (k, v) in &*peers {
-        if now.duration_since(v.1) > Duration::from_secs(600) {
-            gc.push(k.clone());
+/// 接收 socket 上的消息，交给主循环处理
+async fn listen(sock: Arc<Socket>, local: SocketAddr, detect: bool, packets: Sender<Packet>) {
+    let mut buf = [0u8; RECV_BUF_SIZE];
+    loop {
+        match sock.recv_from(&mut buf).await {
+            Ok((msg, src)) => {
+                let sock = Arc::clone(&sock);
+                let packet = Packet {
+                    msg,
+                    src,
+                    sock,
+                    local,
+                    detect,
+                };
+                if packets.send(packet).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => error!("{}", e),
         }
     }
-    for v in &gc {
-        peers.remove(v);
+}
+
+/// 发送消息，失败时只记录日志
+async fn send_to(sock: &Socket, msg: &Message, addr: SocketAddr) {
+    if let Err(e) = sock.send_to(msg, addr).await {
+        error!("send to {}: {}", addr, e);
+    }
+}
+
+/// 回复错误
+async fn reply_error(sock: &Socket, addr: SocketAddr, code: ErrorCode, reason: impl Into<String>) {
+    let msg = Error {
+        code,
+        reason: reason.into(),
+    };
+    send_to(sock, &msg, addr).await;
+}
+
+/// id 和网络名称不能为空，也不能超过 `MAX_ID_LEN`
+fn is_valid_id(id: &[u8]) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN
+}
+
+fn invalid_id_reason() -> String {
+    format!("id must be 1 to {} bytes", MAX_ID_LEN)
+}
+
+/// 命名空间名称和凭证不能超过 `MAX_ID_LEN`，名称为空表示默认命名空间
+fn is_valid_namespace(namespace: &Namespace) -> bool {
+    namespace.name.len() <= MAX_ID_LEN && namespace.token.len() <= MAX_ID_LEN
+}
+
+fn unauthorized_reason(namespace: &Namespace) -> String {
+    let name = String::from_utf8_lossy(&namespace.name);
+    format!("not authorized for namespace {}", name)
+}
+
+fn full_reason(full: Full, namespace: &[u8]) -> String {
+    match full {
+        Full::Registry => "registry is full".to_string(),
+        Full::Namespace => {
+            let name = String::from_utf8_lossy(namespace);
+            format!("namespace {} is full", name)
+        }
+    }
+}
+
+/// 把成员列表分成多个 Members 消息
+fn split_members(members: &Network) -> Vec<Vec<Vec<u8>>> {
+    let mut chunks = vec![Vec::new()];
+    let mut size = 0;
+    for id in members.keys() {
+        if size + id.len() > MEMBERS_SIZE && size > 0 {
+            chunks.push(Vec::new());
+            size = 0;
+        }
+        size += id.len();
+        chunks.last_mut().unwrap().push(id.clone());
     }
-    now
+    chunks
 }
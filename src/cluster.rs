@@ -0,0 +1,104 @@
+//! 外网服务器之间的消息认证
+//!
+//! 集群中的服务器使用同一个密钥，发送的消息封装为带有 HMAC 的 `Message::Cluster`，
+//! 接收方只处理能通过验证的消息，不依赖可以伪造的 UDP 来源地址
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{Decode, Encode, Message};
+
+/// 消息的有效期（秒），超过时丢弃，限制重放。集群中服务器的时钟偏差应小于此值
+pub const MESSAGE_TTL: u64 = 30;
+
+/// 使用密钥 `secret` 封装服务器之间的消息
+pub fn seal(secret: &[u8], msg: &Message) -> Message {
+    seal_at(secret, msg, unix_now())
+}
+
+/// 验证并解出 `Message::Cluster` 中的消息，验证失败、已过期或者不是服务器之间的消息时返回 `None`
+pub fn open(secret: &[u8], time: u64, msg: &[u8], mac: &[u8]) -> Option<Message> {
+    open_at(secret, time, msg, mac, unix_now())
+}
+
+fn seal_at(secret: &[u8], msg: &Message, time: u64) -> Message {
+    let msg = msg.encode();
+    let mac = hmac(secret, time, &msg).finalize().into_bytes().to_vec();
+    Message::Cluster { time, msg, mac }
+}
+
+fn open_at(secret: &[u8], time: u64, msg: &[u8], mac: &[u8], now: u64) -> Option<Message> {
+    if time.abs_diff(now) > MESSAGE_TTL {
+        return None;
+    }
+    hmac(secret, time, msg).verify_slice(mac).ok()?;
+    match Message::decode(msg)? {
+        msg @ (Message::Sync { .. }
+        | Message::ClusterRequest { .. }
+        | Message::ClusterPeer { .. }
+        | Message::Unregister { .. }) => Some(msg),
+        _ => None,
+    }
+}
+
+fn hmac(secret: &[u8], time: u64, msg: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&time.to_be_bytes());
+    mac.update(msg);
+    mac
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync() -> Message {
+        Message::Sync {
+            namespace: b"team-a".to_vec(),
+            id: b"bar".to_vec(),
+            addr: "1.2.3.4:5".parse().unwrap(),
+            network: None,
+        }
+    }
+
+    fn parts(msg: Message) -> (u64, Vec<u8>, Vec<u8>) {
+        match msg {
+            Message::Cluster { time, msg, mac } => (time, msg, mac),
+            _ => panic!("not sealed"),
+        }
+    }
+
+    #[test]
+    fn test_open() {
+        let (time, msg, mac) = parts(seal_at(b"secret", &sync(), 1000));
+        assert!(matches!(
+            open_at(b"secret", time, &msg, &mac, 1000 + MESSAGE_TTL),
+            Some(Message::Sync { .. })
+        ));
+        assert!(open_at(b"secret", time, &msg, &mac, 1000 + MESSAGE_TTL + 1).is_none());
+        assert!(open_at(b"secret", time, &msg, &mac, 1000 - MESSAGE_TTL - 1).is_none());
+        assert!(open_at(b"other", time, &msg, &mac, 1000).is_none());
+        // 修改时间戳或者消息
+        assert!(open_at(b"secret", time + 1, &msg, &mac, 1000).is_none());
+        let mut forged = msg.clone();
+        forged[0] ^= 1;
+        assert!(open_at(b"secret", time, &forged, &mac, 1000).is_none());
+        assert!(open_at(b"secret", time, &msg, &mac[..16], 1000).is_none());
+    }
+
+    #[test]
+    fn test_peer_message() {
+        // 只能封装服务器之间的消息
+        let (time, msg, mac) = parts(seal_at(b"secret", &Message::Query, 1000));
+        assert!(open_at(b"secret", time, &msg, &mac, 1000).is_none());
+    }
+}
@@ -0,0 +1,363 @@
+//! 外网服务器配置文件
+//!
+//! TOML 格式，例如：
+//!
+//! ```toml
+//! registry = "/var/lib/uhp/registry"
+//! admin = "127.0.0.1:9100"
+//! peer_ttl = 90
+//! log = "info"
+//! cluster = ["10.0.0.2:4567"]
+//! cluster_secret = "cluster-secret"
+//!
+//! [[listen]]
+//! addr = "0.0.0.0:4567"
+//! detect = "0.0.0.0:6789"
+//!
+//! [[listen]]
+//! addr = "[::]:4567"
+//! detect = "[::]:6789"
+//!
+//! [limits]
+//! rate = 20
+//! request_rate = 5
+//! max_peers = 65536
+//! max_relayed = 65536
+//! max_subscriptions = 65536
+//!
+//! [auth]
+//! allow = ["alice", "bob"]
+//!
+//! [[namespace]]
+//! name = "team-a"
+//! token = "secret"
+//! max_peers = 1000
+//! public = false
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::hint::black_box;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::mesh::MEMBER_TTL;
+use crate::{Namespace, MAX_ID_LEN};
+
+/// 外网服务器配置
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// 监听地址，第一个地址同时用来和集群中的其他服务器通信
+    pub listen: Vec<Listen>,
+    /// 其他外网服务器的第一个监听地址
+    #[serde(default)]
+    pub cluster: Vec<SocketAddr>,
+    /// 集群中所有服务器共用的密钥，认证服务器之间的消息，配置了 `cluster` 时必须指定
+    pub cluster_secret: Option<String>,
+    /// 注册信息快照文件
+    pub registry: Option<PathBuf>,
+    /// 管理接口监听地址
+    pub admin: Option<SocketAddr>,
+    /// 注册信息有效期（秒）
+    #[serde(default = "default_peer_ttl")]
+    pub peer_ttl: u64,
+    /// 日志级别：off, error, warn, info, debug, trace
+    pub log: Option<String>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub auth: Auth,
+    /// 默认命名空间之外的命名空间
+    #[serde(default, rename = "namespace")]
+    pub namespaces: Vec<Tenant>,
+}
+
+/// 一个监听地址
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Listen {
+    /// 处理所有消息，peer 的 `--addr`
+    pub addr: SocketAddr,
+    /// 和 `addr` 配对检测对称型 NAT，只回复 Query，peer 的 `--addr2`
+    pub detect: Option<SocketAddr>,
+}
+
+/// 限速和容量
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// 每个来源 IP 每秒最多处理的消息数
+    pub rate: f64,
+    /// 每个 peer 每秒最多收到的打洞请求数
+    pub request_rate: f64,
+    /// 最多注册的 peer 个数
+    pub max_peers: usize,
+    /// 最多同时为其他服务器转发的打洞请求数
+    pub max_relayed: usize,
+    /// 最多同时等待的上线通知订阅数
+    pub max_subscriptions: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            rate: 20.0,
+            request_rate: 5.0,
+            max_peers: 65536,
+            max_relayed: 65536,
+            max_subscriptions: 65536,
+        }
+    }
+}
+
+/// 默认命名空间的注册限制
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    /// 允许注册的 id，为空时不限制
+    pub allow: Vec<String>,
+}
+
+/// 一个命名空间（租户），其中的 id 和其他命名空间互不冲突
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Tenant {
+    pub name: String,
+    /// 注册、加入网络时需要的凭证，为空时不需要
+    #[serde(default)]
+    pub token: String,
+    /// 最多注册的 peer 个数，不指定时只受 `limits.max_peers` 限制
+    pub max_peers: Option<usize>,
+    /// 是否公开，公开的命名空间中的 peer 不需要凭证就可以查询
+    #[serde(default)]
+    pub public: bool,
+}
+
+impl Auth {
+    /// 是否允许以 `id` 注册
+    pub fn is_allowed(&self, id: &[u8]) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|v| v.as_bytes() == id)
+    }
+}
+
+/// 比较凭证，耗时只和长度有关，不会泄露相同前缀的长度
+pub(crate) fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y));
+    black_box(diff) == 0 && a.len() == b.len()
+}
+
+fn default_peer_ttl() -> u64 {
+    90
+}
+
+impl Config {
+    /// 从文件加载并检查配置
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        let s = read_to_string(path).map_err(err!("cannot read {}", path.display()))?;
+        let config: Self = toml::from_str(&s).map_err(err!("invalid config {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 检查配置
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if self.listen.is_empty() {
+            invalid("listen is empty".to_string()).map_err(err!())?;
+        }
+        // 网络成员按 MEMBER_TTL 的间隔 Join，不能比注册信息更快过期
+        if self.peer_ttl < MEMBER_TTL.as_secs() {
+            let msg = format!("peer_ttl must be at least {}", MEMBER_TTL.as_secs());
+            invalid(msg).map_err(err!())?;
+        }
+        // 来源地址可以伪造，服务器之间的消息必须认证
+        if !self.cluster.is_empty()
+            && self
+                .cluster_secret
+                .as_deref()
+                .unwrap_or_default()
+                .is_empty()
+        {
+            invalid("cluster requires cluster_secret".to_string()).map_err(err!())?;
+        }
+        if self.limits.rate <= 0.0 || self.limits.request_rate <= 0.0 {
+            invalid("rate must be positive".to_string()).map_err(err!())?;
+        }
+        let mut names = HashSet::new();
+        for tenant in &self.namespaces {
+            let name = &tenant.name;
+            if name.is_empty() || name.len() > MAX_ID_LEN || tenant.token.len() > MAX_ID_LEN {
+                let msg = format!("namespace and token must be 1 to {} bytes", MAX_ID_LEN);
+                invalid(msg).map_err(err!("namespace {}", name))?;
+            }
+            if !names.insert(name) {
+                invalid("duplicate namespace".to_string()).map_err(err!("namespace {}", name))?;
+            }
+        }
+        if let Some(ref log) = self.log {
+            if LevelFilter::from_str(log).is_err() {
+                invalid(format!("invalid log level {}", log)).map_err(err!())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 检查命名空间的凭证，默认命名空间不需要凭证。
+    /// `lookup` 表示查询，公开的命名空间查询时不需要凭证
+    pub fn is_authorized(&self, namespace: &Namespace, lookup: bool) -> bool {
+        if namespace.name.is_empty() {
+            return true;
+        }
+        match self
+            .namespaces
+            .iter()
+            .find(|v| v.name.as_bytes() == namespace.name)
+        {
+            Some(v) => (lookup && v.public) || token_eq(v.token.as_bytes(), &namespace.token),
+            None => false,
+        }
+    }
+
+    /// 各命名空间最多注册的 peer 个数
+    pub fn quotas(&self) -> HashMap<Vec<u8>, usize> {
+        self.namespaces
+            .iter()
+            .filter_map(|v| Some((v.name.clone().into_bytes(), v.max_peers?)))
+            .collect()
+    }
+
+    /// 配置的日志级别
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.log
+            .as_deref()
+            .and_then(|v| LevelFilter::from_str(v).ok())
+    }
+
+    /// 所有绑定地址，以及是否只用来检测 NAT
+    pub fn sockets(&self) -> Vec<(SocketAddr, bool)> {
+        let mut v = Vec::new();
+        for listen in &self.listen {
+            v.push((listen.addr, false));
+            if let Some(detect) = listen.detect {
+                v.push((detect, true));
+            }
+        }
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(s: &str) -> Config {
+        toml::from_str(s).unwrap()
+    }
+
+    fn namespace(name: &str, token: &str) -> Namespace {
+        Namespace {
+            name: name.as_bytes().to_vec(),
+            token: token.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_token_eq() {
+        assert!(token_eq(b"", b""));
+        assert!(token_eq(b"secret", b"secret"));
+        assert!(!token_eq(b"secret", b"secreT"));
+        assert!(!token_eq(b"secret", b"secret1"));
+        assert!(!token_eq(b"secret", b"sec"));
+        assert!(!token_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let config = config(
+            r#"
+            [[listen]]
+            addr = "0.0.0.0:4567"
+
+            [[namespace]]
+            name = "a"
+            token = "secret"
+
+            [[namespace]]
+            name = "b"
+            token = "secret"
+            public = true
+            "#,
+        );
+        assert!(config.is_authorized(&namespace("", ""), false));
+        assert!(config.is_authorized(&namespace("a", "secret"), false));
+        assert!(!config.is_authorized(&namespace("a", "wrong"), false));
+        assert!(!config.is_authorized(&namespace("a", ""), true));
+        assert!(config.is_authorized(&namespace("b", ""), true));
+        assert!(!config.is_authorized(&namespace("b", ""), false));
+        assert!(!config.is_authorized(&namespace("c", ""), true));
+    }
+
+    #[test]
+    fn test_validate() {
+        let listen = "[[listen]]\naddr = \"0.0.0.0:4567\"\n";
+        let valid = [
+            String::new(),
+            "cluster = [\"10.0.0.2:4567\"]\ncluster_secret = \"s\"".to_string(),
+            "log = \"debug\"".to_string(),
+            "[[namespace]]\nname = \"a\"\n[[namespace]]\nname = \"b\"".to_string(),
+        ];
+        for s in valid {
+            let s = format!("{}\n{}", s, listen);
+            config(&s).validate().unwrap();
+        }
+
+        let long = "a".repeat(MAX_ID_LEN + 1);
+        let cases = [
+            ("listen = []".to_string(), "listen is empty"),
+            (format!("peer_ttl = 1\n{}", listen), "peer_ttl"),
+            (
+                format!("cluster = [\"10.0.0.2:4567\"]\n{}", listen),
+                "cluster_secret",
+            ),
+            (
+                format!(
+                    "cluster = [\"10.0.0.2:4567\"]\ncluster_secret = \"\"\n{}",
+                    listen
+                ),
+                "cluster_secret",
+            ),
+            (format!("{}[limits]\nrate = 0", listen), "positive"),
+            (format!("{}[limits]\nrequest_rate = -1", listen), "positive"),
+            (format!("{}[[namespace]]\nname = \"\"", listen), "1 to"),
+            (
+                format!("{}[[namespace]]\nname = \"{}\"", listen, long),
+                "1 to",
+            ),
+            (
+                format!(
+                    "{}[[namespace]]\nname = \"a\"\ntoken = \"{}\"",
+                    listen, long
+                ),
+                "1 to",
+            ),
+            (
+                format!(
+                    "{}[[namespace]]\nname = \"a\"\n[[namespace]]\nname = \"a\"",
+                    listen
+                ),
+                "duplicate",
+            ),
+            (format!("log = \"verbose\"\n{}", listen), "log level"),
+        ];
+        for (s, msg) in cases {
+            let e = config(&s).validate().unwrap_err().to_string();
+            assert!(e.contains(msg), "{:?}: {}", s, e);
+        }
+    }
+}
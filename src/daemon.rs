@@ -0,0 +1,212 @@
+//! peer 守护进程配置文件，在一个进程、一个 socket 上注册多个 id
+//!
+//! TOML 格式，例如：
+//!
+//! ```toml
+//! [[id]]
+//! id = "alice"
+//! receive = "/srv/alice"
+//! conflict = "rename"
+//! accept_name = ["bob", "carol"]
+//! max_size = 1024
+//!
+//! [[id]]
+//! id = "builds"
+//! namespace = "team-a"
+//! token = "secret"
+//! serve = "/srv/artifacts"
+//! allow_forward = ["127.0.0.1:22", "db.lan:*"]
+//! quic = true
+//! ```
+
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::file_transfer::{AllowForward, Conflict};
+use crate::{Namespace, MAX_ID_LEN};
+
+/// 守护进程配置
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// 注册的 id
+    #[serde(rename = "id")]
+    pub ids: Vec<IdConfig>,
+}
+
+/// 一个 id 的凭证和处理打洞请求的方式
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IdConfig {
+    pub id: String,
+    /// 所在的命名空间，不指定时使用默认命名空间
+    pub namespace: Option<String>,
+    /// 命名空间的凭证
+    pub token: Option<String>,
+    /// 接收文件保存目录
+    pub receive: Option<PathBuf>,
+    /// 同名文件处理方式：overwrite, skip, rename, ask
+    #[serde(default = "default_conflict", deserialize_with = "parse_conflict")]
+    pub conflict: Conflict,
+    /// 对外提供的目录
+    pub serve: Option<PathBuf>,
+    /// 允许对方转发 TCP 连接和 UDP 数据报的目标地址，为空时不允许转发
+    #[serde(default, deserialize_with = "parse_targets")]
+    pub allow_forward: Vec<AllowForward>,
+    /// 是否使用 QUIC 传输
+    #[serde(default)]
+    pub quic: bool,
+    /// 只接收声明了这些名称的发送端发送的文件，为空时不限制。名称没有经过验证
+    #[serde(default)]
+    pub accept_name: Vec<String>,
+    /// 接收文件的大小上限（MiB）
+    pub max_size: Option<u64>,
+}
+
+fn default_conflict() -> Conflict {
+    Conflict::Overwrite
+}
+
+fn parse_conflict<'de, D: Deserializer<'de>>(d: D) -> Result<Conflict, D::Error> {
+    let s = String::deserialize(d)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+fn parse_targets<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<AllowForward>, D::Error> {
+    let v = Vec::<String>::deserialize(d)?;
+    v.iter()
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+impl IdConfig {
+    pub fn namespace(&self) -> Namespace {
+        Namespace {
+            name: self.namespace.clone().unwrap_or_default().into_bytes(),
+            token: self.token.clone().unwrap_or_default().into_bytes(),
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// 从文件加载并检查配置
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        let s = read_to_string(path).map_err(err!("cannot read {}", path.display()))?;
+        let config: Self = toml::from_str(&s).map_err(err!("invalid config {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 检查配置
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if self.ids.is_empty() {
+            invalid("no id is configured".to_string()).map_err(err!())?;
+        }
+        let mut ids = HashSet::new();
+        for v in &self.ids {
+            if v.id.is_empty() || v.id.len() > MAX_ID_LEN {
+                let msg = format!("id must be 1 to {} bytes", MAX_ID_LEN);
+                invalid(msg).map_err(err!("id {}", v.id))?;
+            }
+            if v.token.is_some() && v.namespace.is_none() {
+                let msg = "token requires namespace".to_string();
+                invalid(msg).map_err(err!("id {}", v.id))?;
+            }
+            if v.receive.is_none() && v.serve.is_none() && v.allow_forward.is_empty() {
+                let msg = "one of receive, serve and allow_forward is required".to_string();
+                invalid(msg).map_err(err!("id {}", v.id))?;
+            }
+            // 守护进程一直运行，不能只接收一次
+            if matches!(v.receive, Some(ref dir) if dir == Path::new("-")) {
+                let msg = "cannot receive to stdout".to_string();
+                invalid(msg).map_err(err!("id {}", v.id))?;
+            }
+            if !ids.insert((&v.namespace, &v.id)) {
+                invalid("duplicate id".to_string()).map_err(err!("id {}", v.id))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(s: &str) -> Result<DaemonConfig, String> {
+        let config: DaemonConfig = toml::from_str(s).map_err(|e| e.to_string())?;
+        config.validate().map_err(|e| e.to_string())?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_valid() {
+        let config = validate(
+            r#"
+            [[id]]
+            id = "alice"
+            receive = "/srv/alice"
+            conflict = "rename"
+            accept_name = ["bob"]
+
+            [[id]]
+            id = "alice"
+            namespace = "team-a"
+            token = "secret"
+            allow_forward = ["127.0.0.1:22"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.ids[0].conflict, Conflict::Rename);
+        assert_eq!(config.ids[0].namespace(), Namespace::default());
+        assert_eq!(config.ids[1].conflict, Conflict::Overwrite);
+        assert_eq!(config.ids[1].namespace().token, b"secret");
+        assert!(config.ids[0].allow_forward.is_empty());
+        assert_eq!(config.ids[1].allow_forward[0].to_string(), "127.0.0.1:22");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let long = "a".repeat(MAX_ID_LEN + 1);
+        let cases = [
+            ("id = []", "no id"),
+            ("[[id]]\nid = \"\"\nserve = \"/srv\"", "1 to"),
+            (
+                &format!("[[id]]\nid = \"{}\"\nserve = \"/srv\"", long),
+                "1 to",
+            ),
+            (
+                "[[id]]\nid = \"a\"\ntoken = \"t\"\nserve = \"/srv\"",
+                "requires namespace",
+            ),
+            ("[[id]]\nid = \"a\"", "is required"),
+            ("[[id]]\nid = \"a\"\nreceive = \"-\"", "stdout"),
+            (
+                "[[id]]\nid = \"a\"\nserve = \"/a\"\n[[id]]\nid = \"a\"\nserve = \"/b\"",
+                "duplicate",
+            ),
+            (
+                "[[id]]\nid = \"a\"\nserve = \"/srv\"\nconflict = \"x\"",
+                "conflict",
+            ),
+            (
+                "[[id]]\nid = \"a\"\nserve = \"/srv\"\nprompt = true",
+                "prompt",
+            ),
+            ("[[id]]\nid = \"a\"\nallow_forward = true", "sequence"),
+            (
+                "[[id]]\nid = \"a\"\nallow_forward = [\"host\"]",
+                "forward target",
+            ),
+        ];
+        for (s, msg) in cases {
+            let e = validate(s).unwrap_err();
+            assert!(e.contains(msg), "{:?}: {}", s, e);
+        }
+    }
+}
@@ -1,9 +1,21 @@
+pub use accept::{Accept, Offer, Policy};
+pub use forward::{forward, forward_udp, tunnel, AllowForward, Forward, UDP_IDLE_DURATION};
+pub use mailbox::{deposit, Mailbox};
 use message::*;
-pub use receive::receive;
-pub use send::send;
+pub use message::{Conflict, Resolution};
+pub use receive::{receive, receive_stream};
+pub use relay::relay;
+pub use send::{send, send_stream};
+pub use serve::{fetch, list, respond, respond_stream, Service};
 
+mod accept;
 mod bit_array;
 mod block;
+mod forward;
+mod journal;
+mod mailbox;
 mod message;
 mod receive;
+mod relay;
 mod send;
+mod serve;
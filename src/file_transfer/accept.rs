@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use log::info;
+use tokio::io::{stderr, stdin, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+
+use crate::file_transfer::serve::deny;
+use crate::file_transfer::Message;
+use crate::Datagram;
+
+/// 等待决定是否接收的时间，超时后拒绝
+const DECIDE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 对方请求发送的文件
+#[derive(Debug, Clone)]
+pub struct Offer {
+    /// 发送端声明的名称，没有经过验证，未声明时为空
+    pub from: String,
+    /// 发送端的连接，地址和通道编号
+    pub peer: String,
+    /// 文件名或数据流名称
+    pub name: String,
+    /// 文件大小，数据流为 `None`
+    pub size: Option<u64>,
+}
+
+impl Offer {
+    /// `req` 为 Message::Request 或 Message::StreamRequest
+    fn new(req: &Message, peer: String) -> Self {
+        let (from, name, size) = match req {
+            Message::Request(v) => (v.from.clone(), v.name.clone(), Some(v.id.size)),
+            Message::StreamRequest { from, name, .. } => (from.clone(), name.clone(), None),
+            _ => unreachable!(),
+        };
+        Self {
+            from,
+            peer,
+            name,
+            size,
+        }
+    }
+}
+
+/// 决定是否接收对方发送的文件，拒绝时返回原因，原因会发送给发送端
+#[async_trait]
+pub trait Accept: Send + Sync {
+    async fn accept(&self, offer: &Offer) -> Result<(), String>;
+}
+
+#[async_trait]
+impl<F> Accept for F
+where
+    F: Fn(&Offer) -> Result<(), String> + Send + Sync,
+{
+    async fn accept(&self, offer: &Offer) -> Result<(), String> {
+        self(offer)
+    }
+}
+
+/// 内置的接收策略，依次检查发送端名称、文件大小，最后由用户确认。
+/// 发送端名称由发送端自己声明，只用来过滤，不能代替命名空间凭证
+#[derive(Default)]
+pub struct Policy {
+    /// 接收的发送端名称，为空时不限制
+    pub names: Vec<String>,
+    /// 文件大小上限，长度未知的数据流同样拒绝
+    pub max_size: Option<u64>,
+    /// 是否在终端询问用户
+    pub prompt: bool,
+    /// 标准输入的每一行，同时只询问一个文件
+    stdin: Mutex<Option<UnboundedReceiver<String>>>,
+}
+
+impl Policy {
+    pub fn new(names: Vec<String>, max_size: Option<u64>, prompt: bool) -> Self {
+        Self {
+            names,
+            max_size,
+            prompt,
+            stdin: Mutex::new(None),
+        }
+    }
+
+    /// 在标准错误输出询问用户，从标准输入读取回答。
+    /// 由单独的任务读取标准输入，询问超时被取消时不会丢失读到一半的行
+    async fn ask(&self, offer: &Offer) -> Result<(), String> {
+        let mut lines = self.stdin.lock().await;
+        let lines = lines.get_or_insert_with(read_lines);
+        // 丢弃超时之后才输入的回答
+        while lines.try_recv().is_ok() {}
+        let size = match offer.size {
+            Some(v) => format!("{} bytes", v),
+            None => "stream".to_string(),
+        };
+        let from = match offer.from.as_str() {
+            "" => offer.peer.clone(),
+            v => format!("{} ({})", v, offer.peer),
+        };
+        let question = format!("accept {} [{}] from {}? [y/N] ", offer.name, size, from);
+        let mut err = stderr();
+        let _ = err.write_all(question.as_bytes()).await;
+        let _ = err.flush().await;
+
+        match lines.recv().await {
+            Some(answer) if matches!(answer.trim(), "y" | "Y" | "yes") => Ok(()),
+            _ => Err("rejected by receiver".to_string()),
+        }
+    }
+}
+
+/// 启动读取标准输入的任务，标准输入结束时通道关闭
+fn read_lines() -> UnboundedReceiver<String> {
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[async_trait]
+impl Accept for Policy {
+    async fn accept(&self, offer: &Offer) -> Result<(), String> {
+        if !self.names.is_empty() && !self.names.contains(&offer.from) {
+            return Err(format!("sender name {:?} is not accepted", offer.from));
+        }
+        if let Some(max) = self.max_size {
+            match offer.size {
+                Some(v) if v > max => return Err(format!("file is larger than {} bytes", max)),
+                Some(_) => {}
+                None => return Err("stream size is unknown".to_string()),
+            }
+        }
+        if self.prompt {
+            return self.ask(offer).await;
+        }
+        Ok(())
+    }
+}
+
+/// 由 `accept` 决定是否接收发送请求 `req`，拒绝时通知发送端，返回是否接收。
+/// 决定之前对方重发的请求回复 Message::Pending，对方继续等待
+pub(crate) async fn check_offer<S: Datagram>(
+    sock: &S,
+    buf: &mut [u8],
+    req: &Message,
+    accept: &dyn Accept,
+) -> crate::Result<bool> {
+    let offer = Offer::new(req, sock.peer());
+    let decision = timeout(DECIDE_TIMEOUT, accept.accept(&offer));
+    tokio::pin!(decision);
+    let result = loop {
+        tokio::select! {
+            result = &mut decision => {
+                break result.unwrap_or_else(|_| Err("receiver did not answer".to_string()));
+            }
+            msg = sock.recv(buf) => {
+                if let Message::Request(_) | Message::StreamRequest { .. } = msg.map_err(err!())? {
+                    sock.send(&Message::Pending).await.map_err(err!())?;
+                }
+            }
+        }
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(reason) => {
+            info!("reject {} from {}", offer.name, offer.peer);
+            deny(sock, buf, reason).await?;
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(from: &str, size: Option<u64>) -> Offer {
+        Offer {
+            from: from.to_string(),
+            peer: "127.0.0.1:1000".to_string(),
+            name: "a".to_string(),
+            size,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy() {
+        let policy = Policy::default();
+        assert!(policy.accept(&offer("", None)).await.is_ok());
+
+        let policy = Policy::new(vec!["alice".to_string(), "bob".to_string()], None, false);
+        assert!(policy.accept(&offer("bob", Some(1))).await.is_ok());
+        assert!(policy.accept(&offer("carol", Some(1))).await.is_err());
+        assert!(policy.accept(&offer("", Some(1))).await.is_err());
+
+        let policy = Policy::new(vec![], Some(100), false);
+        assert!(policy.accept(&offer("", Some(100))).await.is_ok());
+        assert!(policy.accept(&offer("", Some(101))).await.is_err());
+        assert!(policy.accept(&offer("", None)).await.is_err());
+
+        // 名称不符时不检查大小
+        let policy = Policy::new(vec!["alice".to_string()], Some(100), false);
+        let reason = policy.accept(&offer("bob", Some(101))).await.unwrap_err();
+        assert!(reason.contains("bob"), "{}", reason);
+    }
+
+    #[tokio::test]
+    async fn test_closure() {
+        let accept: &dyn Accept = &|offer: &Offer| match offer.name.ends_with(".exe") {
+            true => Err("executable".to_string()),
+            false => Ok(()),
+        };
+        let mut v = offer("", Some(1));
+        assert!(accept.accept(&v).await.is_ok());
+        v.name = "a.exe".to_string();
+        assert_eq!(accept.accept(&v).await, Err("executable".to_string()));
+    }
+}
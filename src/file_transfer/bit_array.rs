@@ -1,22 +1,15 @@
 use std::fmt::{Debug, Formatter};
 
+use serde::{Deserialize, Serialize};
+
 /// bit 数组
+#[derive(Default, Serialize, Deserialize)]
 pub struct BitArray {
     vec: Vec<u64>,
     len: u32,
 }
 
-impl Default for BitArray {
-    fn default() -> Self {
-        Self {
-            vec: Vec::new(),
-            len: 0,
-        }
-    }
-}
-
 impl BitArray {
-    #[allow(dead_code)]
     pub fn new(len: u32) -> Self {
         let vec_len = len / 64 + (len % 64).min(1);
         let mut arr = Self {
@@ -46,7 +39,7 @@ impl BitArray {
 
         let position = index / 64;
         let offset = index % 64;
-        (self.vec[position as usize] & (1 << 63 - offset)) > 0
+        (self.vec[position as usize] & (1 << (63 - offset))) > 0
     }
 
     pub fn set(&mut self, index: u32) {
@@ -54,25 +47,32 @@ impl BitArray {
 
         let position = index / 64;
         let offset = index % 64;
-        self.vec[position as usize] |= 1 << 63 - offset
+        self.vec[position as usize] |= 1 << (63 - offset)
     }
 
     pub fn collect_unset(&self) -> Vec<u32> {
-        let mut index = 0;
         let mut vec = Vec::new();
-        for v in &self.vec {
+        for (index, v) in self.vec.iter().enumerate() {
             if *v != u64::MAX {
                 for i in (0..=63).rev() {
                     if (*v & 1 << i) == 0 {
-                        vec.push(index * 64 + 63 - i);
+                        vec.push(index as u32 * 64 + 63 - i);
                     }
                 }
             }
-            index += 1;
         }
         vec
     }
 
+    /// 第一个未设置的 bit
+    pub fn first_unset(&self) -> Option<u32> {
+        self.vec
+            .iter()
+            .enumerate()
+            .find(|(_, v)| **v != u64::MAX)
+            .map(|(index, v)| index as u32 * 64 + v.leading_ones())
+    }
+
     fn fill_unused(&mut self) {
         let unused = self.vec.len() * 64 - self.len as usize;
         if unused > 0 {
@@ -1,10 +1,15 @@
 use std::cmp::Ordering::{Equal, Less};
 use std::fs::{rename, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::slice::Chunks;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::file_transfer::bit_array::BitArray;
+use crate::file_transfer::journal::{journal_path, Journal};
+use crate::file_transfer::FileId;
 
 /// 分块读文件
 pub struct BlockReader {
@@ -38,8 +43,7 @@ impl BlockReader {
         }
 
         let (last_block, last_block_size) = last_block_index_size(file_size, block_size);
-        let mut buf = Vec::with_capacity(block_size as usize);
-        unsafe { buf.set_len(buf.capacity()) };
+        let buf = vec![0; block_size as usize];
 
         Ok(Self {
             file,
@@ -53,7 +57,7 @@ impl BlockReader {
     }
 
     /// 读取一个分块。返回 `None` 表示没有更多分块了
-    pub fn read(&mut self) -> crate::Result<Option<Block>> {
+    pub fn read(&mut self) -> crate::Result<Option<Block<'_>>> {
         let len = match self.next_block.cmp(&self.last_block) {
             Less => self.block_size as usize,
             Equal => self.last_block_size as usize,
@@ -82,6 +86,62 @@ fn last_block_index_size(file_size: u64, block_size: u32) -> (u32, u32) {
     }
 }
 
+/// 分块读数据流，数据流长度未知
+pub struct StreamReader<R> {
+    reader: R,
+    /// chunk 分块大小
+    chunk_size: u16,
+    /// 下一个分块
+    next_block: u32,
+    /// 已读到数据流结尾
+    eof: bool,
+    /// block buffer
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> StreamReader<R> {
+    pub fn new(reader: R, block_size: u32, chunk_size: u16) -> Self {
+        Self {
+            reader,
+            chunk_size,
+            next_block: 0,
+            eof: false,
+            buf: vec![0; block_size as usize],
+        }
+    }
+
+    /// 读取一个分块，同时返回是否是最后一个分块。返回 `None` 表示没有更多分块了
+    ///
+    /// 数据流长度恰好是 block 大小的整数倍时，最后一个分块为空
+    pub async fn read(&mut self) -> crate::Result<Option<(Block<'_>, bool)>> {
+        if self.eof {
+            return Ok(None);
+        }
+
+        let mut len = 0;
+        while len < self.buf.len() {
+            let n = self
+                .reader
+                .read(&mut self.buf[len..])
+                .await
+                .map_err(err!())?;
+            if n == 0 {
+                self.eof = true;
+                break;
+            }
+            len += n;
+        }
+
+        let block = Block {
+            index: self.next_block,
+            block: &self.buf[..len],
+            chunk_size: self.chunk_size,
+        };
+        self.next_block += 1;
+        Ok(Some((block, self.eof)))
+    }
+}
+
 /// 读分块
 pub struct Block<'a> {
     /// block index
@@ -97,8 +157,13 @@ impl<'a> Block<'a> {
         self.index
     }
 
+    /// block 大小
+    pub fn size(&self) -> u32 {
+        self.block.len() as u32
+    }
+
     /// chunk 分块 iterator
-    pub fn chunks(&self) -> Chunks<u8> {
+    pub fn chunks(&self) -> Chunks<'_, u8> {
         self.block.chunks(self.chunk_size as usize)
     }
 
@@ -114,12 +179,20 @@ impl<'a> Block<'a> {
     }
 }
 
+/// 写入这么多个 block 后落盘并保存日志
+const SYNC_BLOCKS: u32 = 64;
+
+/// 距上次落盘超过此时间时，写入 block 后落盘并保存日志
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
 /// 分块写文件
 pub struct BlockWriter {
     /// 文件路径
     path: PathBuf,
     /// 文件
     file: File,
+    /// 断点续传日志
+    journal: Journal,
     /// block 分块大小
     block_size: u32,
     /// 最后一个 block 大小
@@ -134,50 +207,56 @@ pub struct BlockWriter {
     buf: Vec<u8>,
     /// 记录 chunk 是否写入
     write_flag: BitArray,
+    /// 上次落盘之后写入的 block 个数
+    unsynced: u32,
+    /// 上次落盘的时间
+    synced_at: Instant,
 }
 
 impl BlockWriter {
     pub fn new(
         path: PathBuf,
-        file_size: u64,
+        id: FileId,
         block_size: u32,
         chunk_size: u16,
         resume: bool,
     ) -> crate::Result<Option<Self>> {
-        if file_size == 0 {
+        if id.size == 0 {
             write_open(&path, false)?;
             return Ok(None);
         }
 
+        let (last_block, last_block_size) = last_block_index_size(id.size, block_size);
         let part = part_path(&path);
-        let (file, next_block) = if resume && part.exists() {
-            // 遇到同名文件会有问题，这里不考虑这种情况
-            let mut file = write_open(&part, true)?;
-            let size = file.metadata().map_err(err!())?.len();
-            match size.cmp(&file_size) {
-                Less => {
-                    let next_block = size / block_size as u64;
-                    let offset = next_block * block_size as u64;
-                    file.seek(SeekFrom::Start(offset)).map_err(err!())?;
-                    (file, next_block as u32)
-                }
-                Equal => {
-                    rename_part_file(&part, &path)?;
-                    return Ok(None);
-                }
-                _ => (write_open(&part, false)?, 0),
-            }
+        let journal_path = journal_path(&path);
+        let journal = if resume && part.exists() {
+            Journal::load(journal_path.clone(), id, block_size)?
         } else {
-            (write_open(&part, false)?, 0)
+            None
+        };
+        let (file, journal) = match journal {
+            Some(journal) => (write_open(&part, true)?, journal),
+            None => {
+                let journal = Journal::create(journal_path, id, block_size, last_block + 1)?;
+                (write_open(&part, false)?, journal)
+            }
         };
 
-        let (last_block, last_block_size) = last_block_index_size(file_size, block_size);
-        let mut buf = Vec::with_capacity(block_size as usize);
-        unsafe { buf.set_len(buf.capacity()) };
+        let next_block = match journal.first_missing() {
+            Some(v) => v,
+            None => {
+                rename_part_file(&part, &path)?;
+                journal.remove()?;
+                return Ok(None);
+            }
+        };
+
+        let buf = vec![0; block_size as usize];
 
         Ok(Some(Self {
             path,
             file,
+            journal,
             block_size,
             last_block_size,
             chunk_size,
@@ -185,19 +264,24 @@ impl BlockWriter {
             last_block,
             buf,
             write_flag: BitArray::default(),
+            unsynced: 0,
+            synced_at: Instant::now(),
         }))
     }
 
-    pub fn next_block(&mut self) -> Option<BlockBuffer> {
+    pub fn next_block(&mut self) -> Option<BlockBuffer<'_>> {
+        while self.next_block <= self.last_block && self.journal.is_written(self.next_block) {
+            self.next_block += 1;
+        }
+
         let block_size = match self.next_block.cmp(&self.last_block) {
             Less => self.block_size as usize,
             Equal => self.last_block_size as usize,
             _ => return None,
         } as u32;
 
-        let chunk_count =
-            block_size / self.chunk_size as u32 + 1.min(block_size % self.chunk_size as u32);
-        self.write_flag.reset(chunk_count);
+        self.write_flag
+            .reset(chunk_count(block_size, self.chunk_size));
 
         let (last_chunk, last_chunk_size) = last_chunk_index_size(block_size, self.chunk_size);
 
@@ -209,17 +293,20 @@ impl BlockWriter {
         })
     }
 
-    pub fn rename_file(&self) -> crate::Result<()> {
+    /// 文件接收完毕，落盘后重命名 `.part` 文件并删除日志
+    pub fn rename_file(self) -> crate::Result<()> {
+        self.file.sync_data().map_err(err!())?;
         let part = part_path(&self.path);
-        rename_part_file(&part, &self.path)
+        rename_part_file(&part, &self.path)?;
+        self.journal.remove()
     }
 }
 
 fn rename_part_file(part: &Path, name: &Path) -> crate::Result<()> {
-    rename(&part, &name).map_err(err!("rename {} to {}", part.display(), name.display()))
+    rename(part, name).map_err(err!("rename {} to {}", part.display(), name.display()))
 }
 
-fn part_path(path: &Path) -> PathBuf {
+pub fn part_path(path: &Path) -> PathBuf {
     match path.extension() {
         Some(ext) => path.with_extension(ext.to_str().unwrap().to_string() + ".part"),
         None => path.with_extension("part"),
@@ -248,13 +335,28 @@ impl<'a> BlockBuffer<'a> {
         self.writer.next_block
     }
 
-    /// 写入文件
+    /// 写入文件。每隔 `SYNC_BLOCKS` 个 block 或 `SYNC_INTERVAL` 落盘一次，数据落盘后再保存日志，
+    /// 中断时最多重传最近未落盘的 block
     pub fn commit(&mut self) -> crate::Result<()> {
+        let index = self.writer.next_block;
         self.writer.next_block += 1;
-        self.writer
+
+        let offset = index as u64 * self.writer.block_size as u64;
+        let writer = &mut *self.writer;
+        writer.file.seek(SeekFrom::Start(offset)).map_err(err!())?;
+        writer
             .file
-            .write_all(&self.writer.buf[..self.block_size as usize])
-            .map_err(err!())
+            .write_all(&writer.buf[..self.block_size as usize])
+            .map_err(err!())?;
+        writer.journal.mark_written(index);
+        writer.unsynced += 1;
+        if writer.unsynced >= SYNC_BLOCKS || writer.synced_at.elapsed() >= SYNC_INTERVAL {
+            writer.file.sync_data().map_err(err!())?;
+            writer.journal.save()?;
+            writer.unsynced = 0;
+            writer.synced_at = Instant::now();
+        }
+        Ok(())
     }
 
     /// 写　chunk
@@ -277,6 +379,113 @@ impl<'a> BlockBuffer<'a> {
     }
 }
 
+/// 分块写数据流，数据流长度未知
+pub struct StreamWriter<W> {
+    writer: W,
+    /// block 分块大小
+    block_size: u32,
+    /// chunk 分块大小
+    chunk_size: u16,
+    /// 下一个 block
+    next_block: u32,
+    /// block buffer
+    buf: Vec<u8>,
+    /// 记录 chunk 是否写入
+    write_flag: BitArray,
+}
+
+impl<W: AsyncWrite + Unpin> StreamWriter<W> {
+    pub fn new(writer: W, block_size: u32, chunk_size: u16) -> Self {
+        Self {
+            writer,
+            block_size,
+            chunk_size,
+            next_block: 0,
+            buf: vec![0; block_size as usize],
+            write_flag: BitArray::default(),
+        }
+    }
+
+    pub fn next_block(&mut self) -> StreamBuffer<'_, W> {
+        let chunk_count = chunk_count(self.block_size, self.chunk_size);
+        self.write_flag.reset(chunk_count);
+        StreamBuffer {
+            writer: self,
+            block_size: None,
+        }
+    }
+
+    /// 数据流接收完毕
+    pub async fn finish(mut self) -> crate::Result<W> {
+        self.writer.flush().await.map_err(err!())?;
+        Ok(self.writer)
+    }
+}
+
+/// 写数据流分块，分块大小在收到分块结束消息后才知道
+pub struct StreamBuffer<'a, W> {
+    writer: &'a mut StreamWriter<W>,
+    block_size: Option<u32>,
+}
+
+impl<'a, W: AsyncWrite + Unpin> StreamBuffer<'a, W> {
+    pub fn index(&self) -> u32 {
+        self.writer.next_block
+    }
+
+    /// 写　chunk。chunk 和数据长度由对方决定，超出范围时丢弃并返回 false
+    pub fn write(&mut self, chunk: u32, data: &[u8]) -> bool {
+        let chunk_count = chunk_count(self.writer.block_size, self.writer.chunk_size);
+        let start = self.writer.chunk_size as usize * chunk as usize;
+        if chunk >= chunk_count
+            || data.len() > self.writer.chunk_size as usize
+            || start + data.len() > self.writer.buf.len()
+        {
+            return false;
+        }
+
+        if !self.writer.write_flag.is_set(chunk) {
+            self.writer.write_flag.set(chunk);
+            self.writer.buf[start..start + data.len()].copy_from_slice(data);
+        }
+        true
+    }
+
+    /// 设置 block 大小，超过 block 分块大小时出错
+    pub fn set_size(&mut self, block_size: u32) -> crate::Result<()> {
+        if block_size > self.writer.block_size {
+            let e = io::Error::new(ErrorKind::InvalidData, "block size out of range");
+            Err(e).map_err(err!("block size {}", block_size))?;
+        }
+        self.block_size = Some(block_size);
+        Ok(())
+    }
+
+    /// 获取缺少的 chunk，需先调用 `set_size`
+    pub fn get_missing_chunk(&self) -> Vec<u32> {
+        let chunk_count = chunk_count(self.block_size.unwrap(), self.writer.chunk_size);
+        let mut missing = self.writer.write_flag.collect_unset();
+        missing.retain(|v| *v < chunk_count);
+        missing
+    }
+
+    /// 写入数据流
+    pub async fn commit(&mut self) -> crate::Result<()> {
+        let block_size = self.block_size.unwrap() as usize;
+        self.writer.next_block += 1;
+        self.writer
+            .writer
+            .write_all(&self.writer.buf[..block_size])
+            .await
+            .map_err(err!())
+    }
+}
+
+/// block 的 chunk 个数
+fn chunk_count(block_size: u32, chunk_size: u16) -> u32 {
+    block_size / chunk_size as u32 + 1.min(block_size % chunk_size as u32)
+}
+
 /// 最后一个分块的 index 和大小
 fn last_chunk_index_size(block_size: u32, chunk_size: u16) -> (u32, u16) {
     let q = block_size / chunk_size as u32;
@@ -287,3 +496,29 @@ fn last_chunk_index_size(block_size: u32, chunk_size: u16) -> (u32, u16) {
         (q, r as u16)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stream_buffer() {
+        // block 10 字节，chunk 4 字节，共 3 个 chunk
+        let mut writer = StreamWriter::new(Vec::new(), 10, 4);
+        let mut block = writer.next_block();
+        assert!(block.write(0, b"abcd"));
+        assert!(block.write(2, b"ij"));
+        // 超出范围或者长度不对的 chunk 被丢弃
+        assert!(!block.write(3, b""));
+        assert!(!block.write(u32::MAX, b"x"));
+        assert!(!block.write(1, b"efghx"));
+        assert!(!block.write(2, b"ijk"));
+        assert!(block.set_size(11).is_err());
+        block.set_size(10).unwrap();
+        assert_eq!(block.get_missing_chunk(), vec![1]);
+        assert!(block.write(1, b"efgh"));
+        assert!(block.get_missing_chunk().is_empty());
+        block.commit().await.unwrap();
+        assert_eq!(writer.finish().await.unwrap(), b"abcdefghij");
+    }
+}
@@ -0,0 +1,449 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{debug, info};
+use tokio::io::copy_bidirectional;
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::file_transfer::message::Payload;
+use crate::file_transfer::serve::deny;
+use crate::file_transfer::Message;
+use crate::stream::Stream;
+use crate::tun::Tun;
+use crate::{perform, Datagram, Decode, Operation};
+
+/// 服务端连接目标地址的超时时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// UDP 转发空闲时间，超过此时间没有数据报时结束转发
+pub const UDP_IDLE_DURATION: Duration = Duration::from_secs(60);
+
+/// UDP 数据报最大长度
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// TCP 端口转发：本地端口 `port` 的连接转发到对方可访问的 `host:remote_port`
+#[derive(Clone, Debug)]
+pub struct Forward {
+    pub port: u16,
+    pub host: String,
+    pub remote_port: u16,
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    /// 格式：`本地端口:目标主机:目标端口`，IPv6 地址用 `[]` 括起来
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid forward {}, expect port:host:port", s);
+        let (port, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let (host, remote_port) = rest.rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            port: port.parse().map_err(|_| invalid())?,
+            host: host.to_string(),
+            remote_port: remote_port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Display for Forward {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "{}:[{}]:{}", self.port, self.host, self.remote_port)
+        } else {
+            write!(f, "{}:{}:{}", self.port, self.host, self.remote_port)
+        }
+    }
+}
+
+/// 允许对方转发到的目标地址，端口为 `None` 时允许该主机的所有端口
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllowForward {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl AllowForward {
+    /// 是否允许转发到 `host:port`。IP 地址按地址比较，域名不区分大小写，不解析域名
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let host_matches = match (self.host.parse::<IpAddr>(), host.parse::<IpAddr>()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => self.host.eq_ignore_ascii_case(host),
+        };
+        host_matches && self.port.is_none_or(|v| v == port)
+    }
+}
+
+impl FromStr for AllowForward {
+    type Err = String;
+
+    /// 格式：`主机:端口` 或 `主机:*`，IPv6 地址用 `[]` 括起来
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid forward target {}, expect host:port or host:*", s);
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = match port {
+            "*" => None,
+            v => Some(v.parse().map_err(|_| invalid())?),
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl Display for AllowForward {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:", self.host)?,
+            false => write!(f, "{}:", self.host)?,
+        }
+        match self.port {
+            Some(v) => write!(f, "{}", v),
+            None => write!(f, "*"),
+        }
+    }
+}
+
+/// 检查是否允许转发到 `host:port`，`allow` 为空时不允许任何转发
+pub(crate) fn check_target(allow: &[AllowForward], host: &str, port: u16) -> Result<(), String> {
+    if allow.is_empty() {
+        return Err("forwarding is disabled".to_string());
+    }
+    match allow.iter().any(|v| v.matches(host, port)) {
+        true => Ok(()),
+        false => Err(format!("forwarding to {}:{} is not allowed", host, port)),
+    }
+}
+
+/// 请求服务端连接 `host:port`，在通道上转发本地连接 `tcp` 的数据
+pub async fn forward<S>(sock: S, mut tcp: TcpStream, host: String, port: u16) -> crate::Result<()>
+where
+    S: Datagram + 'static,
+{
+    let target = format!("{}:{}", host, port);
+    let mut buf = vec![0u8; 512];
+    let mut op = SendForward {
+        sock: &sock,
+        buf: &mut buf,
+        msg: Message::Forward { host, port },
+    };
+    match perform(&mut op)
+        .await
+        .map_err(err!("forward to {}", target))?
+    {
+        Message::Denied(reason) => {
+            sock.send(&Message::DeniedAck).await.map_err(err!())?;
+            Err(io::Error::new(ErrorKind::PermissionDenied, reason)).map_err(err!("denied"))?
+        }
+        _ => info!("forward to {}", target),
+    }
+
+    let mut stream = Stream::new(sock);
+    let (tx, rx) = copy_bidirectional(&mut tcp, &mut stream)
+        .await
+        .map_err(err!("forward to {}", target))?;
+    info!("forward to {} closed, sent {} received {}", target, tx, rx);
+    Ok(())
+}
+
+/// 处理 Forward 请求：连接 `host:port`，在通道上转发数据
+pub(crate) async fn accept_forward<S>(
+    sock: S,
+    buf: &mut [u8],
+    host: String,
+    port: u16,
+) -> crate::Result<()>
+where
+    S: Datagram + 'static,
+{
+    let target = format!("{}:{}", host, port);
+    info!("forward to {}", target);
+    let mut tcp = match timeout(CONNECT_TIMEOUT, TcpStream::connect(&target)).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => return deny(&sock, buf, format!("connect to {}: {}", target, e)).await,
+        Err(_) => return deny(&sock, buf, format!("connect to {}: timed out", target)).await,
+    };
+
+    sock.send(&Message::ForwardAck).await.map_err(err!())?;
+    let mut stream = Stream::new(Acked(sock));
+    let (tx, rx) = copy_bidirectional(&mut tcp, &mut stream)
+        .await
+        .map_err(err!("forward to {}", target))?;
+    info!("forward to {} closed, sent {} received {}", target, tx, rx);
+    Ok(())
+}
+
+/// 请求服务端向 `host:port` 转发数据报。本地 `local` 收到的来自 `src` 的数据报由 `rx` 传入，
+/// 目标地址的回复从 `local` 发回 `src`
+pub async fn forward_udp<S: Datagram>(
+    sock: S,
+    local: Arc<UdpSocket>,
+    src: SocketAddr,
+    rx: &mut Receiver<Vec<u8>>,
+    host: String,
+    port: u16,
+) -> crate::Result<()> {
+    let target = format!("{}:{}", host, port);
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut op = SendForward {
+        sock: &sock,
+        buf: &mut buf,
+        msg: Message::ForwardUdp { host, port },
+    };
+    match perform(&mut op)
+        .await
+        .map_err(err!("forward udp to {}", target))?
+    {
+        Message::Denied(reason) => {
+            sock.send(&Message::DeniedAck).await.map_err(err!())?;
+            Err(io::Error::new(ErrorKind::PermissionDenied, reason)).map_err(err!("denied"))?
+        }
+        _ => info!("forward udp from {} to {}", src, target),
+    }
+
+    loop {
+        tokio::select! {
+            data = rx.recv() => match data {
+                Some(v) => sock.send(&Payload::new(&v)).await.map_err(err!())?,
+                None => return Ok(()),
+            },
+            recv = sock.recv_bytes(&mut buf) => {
+                let n = recv.map_err(err!())?;
+                if let Some((Message::Datagram, len)) = Message::trailing_decode(&buf[..n]) {
+                    local.send_to(&buf[n - len..n], src).await.map_err(err!())?;
+                }
+            }
+            _ = sleep(UDP_IDLE_DURATION) => {
+                info!("forward udp from {} to {} expired", src, target);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// 处理 ForwardUdp 请求：在通道和 `host:port` 之间转发数据报
+pub(crate) async fn accept_forward_udp<S: Datagram>(
+    sock: S,
+    buf: &mut [u8],
+    host: String,
+    port: u16,
+) -> crate::Result<()> {
+    let target = format!("{}:{}", host, port);
+    info!("forward udp to {}", target);
+    let udp = match connect_udp(&target).await {
+        Ok(v) => v,
+        Err(e) => return deny(&sock, buf, format!("connect to {}: {}", target, e)).await,
+    };
+
+    sock.send(&Message::ForwardAck).await.map_err(err!())?;
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut data = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            recv = sock.recv_bytes(&mut buf) => {
+                let n = recv.map_err(err!())?;
+                match Message::trailing_decode(&buf[..n]) {
+                    Some((Message::Datagram, len)) => {
+                        if let Err(e) = udp.send(&buf[n - len..n]).await {
+                            debug!("send to {}: {}", target, e);
+                        }
+                    }
+                    // ForwardAck 丢失，对方重发了请求
+                    Some((Message::ForwardUdp { .. }, 0)) => {
+                        sock.send(&Message::ForwardAck).await.map_err(err!())?;
+                    }
+                    _ => {}
+                }
+            }
+            recv = udp.recv(&mut data) => match recv {
+                Ok(n) => sock.send(&Payload::new(&data[..n])).await.map_err(err!())?,
+                // 目标端口不可达等错误，和 UDP 一样忽略
+                Err(e) => debug!("receive from {}: {}", target, e),
+            },
+            _ = sleep(UDP_IDLE_DURATION) => {
+                info!("forward udp to {} expired", target);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// 创建连接到 `target` 的 UDP socket
+async fn connect_udp(target: &str) -> io::Result<UdpSocket> {
+    let addr = match lookup_host(target).await?.next() {
+        Some(v) => v,
+        None => return Err(io::Error::other("cannot resolve host")),
+    };
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let udp = UdpSocket::bind(bind).await?;
+    udp.connect(addr).await?;
+    Ok(udp)
+}
+
+/// 请求和服务端的 TUN 设备之间转发 IP 包，本地的 IP 包来自 `tun`
+pub async fn tunnel<S: Datagram>(sock: S, tun: &Tun) -> crate::Result<()> {
+    let mut buf = vec![0u8; 512];
+    let mut op = SendForward {
+        sock: &sock,
+        buf: &mut buf,
+        msg: Message::Tunnel,
+    };
+    match perform(&mut op).await.map_err(err!("tunnel"))? {
+        Message::Denied(reason) => {
+            sock.send(&Message::DeniedAck).await.map_err(err!())?;
+            Err(io::Error::new(ErrorKind::PermissionDenied, reason)).map_err(err!("denied"))?
+        }
+        _ => info!("tunnel {} to {}", tun.name(), sock.peer()),
+    }
+    relay_packets(&sock, tun).await
+}
+
+/// 处理 Tunnel 请求，同一时间只允许一个隧道使用 TUN 设备
+pub(crate) async fn accept_tunnel<S: Datagram>(
+    sock: S,
+    buf: &mut [u8],
+    tun: &Tun,
+) -> crate::Result<()> {
+    let _guard = match tun.acquire() {
+        Some(v) => v,
+        None => return deny(&sock, buf, format!("{} is in use", tun.name())).await,
+    };
+    info!("tunnel {} to {}", tun.name(), sock.peer());
+    sock.send(&Message::ForwardAck).await.map_err(err!())?;
+    relay_packets(&sock, tun).await
+}
+
+/// 在通道和 TUN 设备之间转发 IP 包
+async fn relay_packets<S: Datagram>(sock: &S, tun: &Tun) -> crate::Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut packet = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            recv = sock.recv_bytes(&mut buf) => {
+                let n = recv.map_err(err!())?;
+                match Message::trailing_decode(&buf[..n]) {
+                    Some((Message::Datagram, len)) => {
+                        if let Err(e) = tun.write(&buf[n - len..n]).await {
+                            debug!("write to {}: {}", tun.name(), e);
+                        }
+                    }
+                    // ForwardAck 丢失，对方重发了请求
+                    Some((Message::Tunnel, 0)) => {
+                        sock.send(&Message::ForwardAck).await.map_err(err!())?;
+                    }
+                    _ => {}
+                }
+            }
+            recv = tun.read(&mut packet) => {
+                let n = recv.map_err(err!())?;
+                sock.send(&Payload::new(&packet[..n])).await.map_err(err!())?;
+            }
+        }
+    }
+}
+
+/// 回复重复的 Forward 请求，防止 ForwardAck 丢失后对方一直等待
+struct Acked<S>(S);
+
+#[async_trait]
+impl<S: Datagram> Datagram for Acked<S> {
+    fn peer(&self) -> String {
+        self.0.peer()
+    }
+
+    async fn send_bytes(&self, data: &[u8]) -> io::Result<()> {
+        self.0.send_bytes(data).await
+    }
+
+    async fn recv_bytes(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.0.recv_bytes(buf).await?;
+            match Message::decode(&buf[..n]) {
+                Some(Message::Forward { .. }) => {
+                    debug!("resend ForwardAck to {}", self.0.peer());
+                    self.0.send(&Message::ForwardAck).await?;
+                }
+                _ => return Ok(n),
+            }
+        }
+    }
+}
+
+/// 发送 Forward、ForwardUdp 或 Tunnel 请求
+struct SendForward<'a, S> {
+    sock: &'a S,
+    buf: &'a mut [u8],
+    msg: Message,
+}
+
+#[async_trait]
+impl<'a, S: Datagram> Operation<Message> for SendForward<'a, S> {
+    /// 服务端需要先连接目标地址，等待时间比其他请求长
+    const RETRY_COUNT: usize = 40;
+
+    async fn poll(&mut self) -> io::Result<()> {
+        self.sock.send(&self.msg).await
+    }
+
+    async fn resolve(&mut self) -> io::Result<Message> {
+        loop {
+            let msg = self.sock.recv(self.buf).await?;
+            if let Message::ForwardAck | Message::Denied(_) = msg {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for s in ["127.0.0.1:22", "db.lan:*", "[::1]:5432"] {
+            assert_eq!(s.parse::<AllowForward>().unwrap().to_string(), s);
+        }
+        for s in ["", "host", ":22", "host:", "host:x", "host:65536"] {
+            assert!(s.parse::<AllowForward>().is_err(), "{}", s);
+        }
+        let v: Forward = "2222:[::1]:22".parse().unwrap();
+        assert_eq!((v.port, v.host.as_str(), v.remote_port), (2222, "::1", 22));
+    }
+
+    #[test]
+    fn test_check_target() {
+        let allow: Vec<AllowForward> = ["127.0.0.1:22", "DB.lan:*", "[::1]:80"]
+            .iter()
+            .map(|v| v.parse().unwrap())
+            .collect();
+        assert!(check_target(&allow, "127.0.0.1", 22).is_ok());
+        assert!(check_target(&allow, "127.0.0.1", 23).is_err());
+        assert!(check_target(&allow, "db.LAN", 5432).is_ok());
+        assert!(check_target(&allow, "0:0::1", 80).is_ok());
+        // 不解析域名
+        assert!(check_target(&allow, "localhost", 22).is_err());
+        assert!(check_target(&allow, "127.0.0.2", 22).is_err());
+        assert_eq!(
+            check_target(&[], "127.0.0.1", 22),
+            Err("forwarding is disabled".to_string())
+        );
+    }
+}
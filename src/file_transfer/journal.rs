@@ -0,0 +1,194 @@
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_transfer::bit_array::BitArray;
+use crate::file_transfer::FileId;
+
+/// 断点续传日志
+///
+/// 保存在 `.part` 文件旁边，记录源文件标识和已写入的 block，
+/// 续传时据此判断 `.part` 文件是否属于同一个文件以及从哪个 block 开始
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    state: State,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    /// 源文件标识
+    id: FileId,
+    /// block 大小
+    block_size: u32,
+    /// 已写入的 block
+    blocks: BitArray,
+}
+
+impl Journal {
+    /// 创建新日志，覆盖已存在的日志
+    pub fn create(
+        path: PathBuf,
+        id: FileId,
+        block_size: u32,
+        block_count: u32,
+    ) -> crate::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(err!("cannot open {}", path.display()))?;
+        let state = State {
+            id,
+            block_size,
+            blocks: BitArray::new(block_count),
+        };
+        let mut journal = Self { path, file, state };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// 加载日志。日志不存在、已损坏或与源文件不匹配时返回 `None`
+    pub fn load(path: PathBuf, id: FileId, block_size: u32) -> crate::Result<Option<Self>> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).map_err(err!("cannot open {}", path.display())),
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(err!())?;
+
+        match bincode::deserialize::<State>(&data) {
+            Ok(state) if state.id == id && state.block_size == block_size => {
+                Ok(Some(Self { path, file, state }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 第一个未写入的 block
+    pub fn first_missing(&self) -> Option<u32> {
+        self.state.blocks.first_unset()
+    }
+
+    pub fn is_written(&self, block: u32) -> bool {
+        self.state.blocks.is_set(block)
+    }
+
+    /// 记录 block 已写入，调用 `save` 后才写入日志文件
+    pub fn mark_written(&mut self, block: u32) {
+        self.state.blocks.set(block);
+    }
+
+    /// 删除日志
+    pub fn remove(self) -> crate::Result<()> {
+        remove_file(&self.path).map_err(err!("cannot remove {}", self.path.display()))
+    }
+
+    /// 保存日志，已记录的 block 必须已经落盘
+    pub fn save(&mut self) -> crate::Result<()> {
+        // 日志大小不变，原地覆盖
+        let data = bincode::serialize(&self.state).unwrap();
+        self.file.seek(SeekFrom::Start(0)).map_err(err!())?;
+        self.file.write_all(&data).map_err(err!())?;
+        self.file.sync_data().map_err(err!())
+    }
+}
+
+/// 日志路径
+pub fn journal_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(".journal");
+    path.with_file_name(name)
+}
+
+/// 计算文件的 FNV-1a 哈希
+pub fn file_hash(file: &mut File) -> io::Result<u64> {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut hash = OFFSET_BASIS;
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for b in &buf[..n] {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::*;
+
+    fn id(hash: u64) -> FileId {
+        FileId {
+            size: 100,
+            mtime: 1,
+            hash,
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir().join(format!("journal-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = journal_path(&dir.join("a.txt"));
+        assert_eq!(path, dir.join("a.txt.journal"));
+        assert!(Journal::load(path.clone(), id(1), 10).unwrap().is_none());
+
+        let mut journal = Journal::create(path.clone(), id(1), 10, 3).unwrap();
+        assert_eq!(journal.first_missing(), Some(0));
+        journal.mark_written(0);
+        journal.mark_written(2);
+        // 未保存的记录不会被加载
+        let loaded = Journal::load(path.clone(), id(1), 10).unwrap().unwrap();
+        assert_eq!(loaded.first_missing(), Some(0));
+        journal.save().unwrap();
+
+        let mut loaded = Journal::load(path.clone(), id(1), 10).unwrap().unwrap();
+        assert!(loaded.is_written(0) && !loaded.is_written(1) && loaded.is_written(2));
+        assert_eq!(loaded.first_missing(), Some(1));
+        loaded.mark_written(1);
+        assert_eq!(loaded.first_missing(), None);
+
+        // 源文件或 block 大小不同
+        assert!(Journal::load(path.clone(), id(2), 10).unwrap().is_none());
+        assert!(Journal::load(path.clone(), id(1), 20).unwrap().is_none());
+
+        loaded.remove().unwrap();
+        assert!(!path.exists());
+
+        write(&path, b"corrupted").unwrap();
+        assert!(Journal::load(path.clone(), id(1), 10).unwrap().is_none());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_hash() {
+        let path = std::env::temp_dir().join(format!("hash-{}", std::process::id()));
+        write(&path, b"").unwrap();
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(file_hash(&mut file).unwrap(), 0xcbf29ce484222325);
+
+        // FNV-1a 的标准测试向量
+        write(&path, b"foobar").unwrap();
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(3)).unwrap();
+        assert_eq!(file_hash(&mut file).unwrap(), 0x85944171f73967e8);
+        assert_eq!(file.stream_position().unwrap(), 0);
+        remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir, remove_file, write, DirEntry};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use tokio::time::sleep;
+
+use crate::config::token_eq;
+use crate::file_transfer::block::part_path;
+use crate::file_transfer::journal::journal_path;
+use crate::file_transfer::receive::{receive_request, renamed, valid_name};
+use crate::file_transfer::serve::{accepted, deny, Service};
+use crate::file_transfer::{send, Conflict, Message};
+use crate::{perform, Datagram, Operation, MAX_ID_LEN};
+
+/// 读取超时时间
+const READ_TIMEOUT: u64 = 5;
+
+/// 保存发送端名称的目录，`<dir>/.from/<接收端>/<文件名>` 保存对应文件的发送端名称。
+/// 接收端 id 不能以 `.` 开头，不会和接收端目录冲突
+const FROM_DIR: &str = ".from";
+
+/// 暂存目录：接收发送给不在线 peer 的文件，对方上线后再发送给它。
+/// 每个接收端一个子目录，文件总大小不超过配额，超过期限的文件被删除。
+/// 只通过加密的通道接收和转交，但文件在暂存 peer 上以明文保存
+#[derive(Debug)]
+pub struct Mailbox {
+    dir: PathBuf,
+    /// 所在命名空间的凭证，发送端需要提供
+    token: Vec<u8>,
+    /// 文件总大小上限
+    quota: u64,
+    /// 文件保存期限
+    ttl: Duration,
+    /// 正在接收的文件
+    receiving: Mutex<Receiving>,
+}
+
+/// 正在接收的文件，接收之前按文件大小预留配额
+#[derive(Debug, Default)]
+struct Receiving {
+    /// 预留的配额
+    size: u64,
+    /// 文件路径
+    paths: HashSet<PathBuf>,
+}
+
+/// 待转交的文件
+#[derive(Debug)]
+pub struct Deposit {
+    pub path: PathBuf,
+    /// 发送端声明的名称
+    pub from: String,
+}
+
+/// 为正在接收的文件预留的配额，drop 时释放
+struct Reservation<'a> {
+    mailbox: &'a Mailbox,
+    path: PathBuf,
+    size: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut receiving = self.mailbox.receiving.lock().unwrap();
+        receiving.size -= self.size;
+        receiving.paths.remove(&self.path);
+    }
+}
+
+impl Mailbox {
+    pub fn new(dir: PathBuf, token: Vec<u8>, quota: u64, ttl: Duration) -> crate::Result<Self> {
+        create_dir_all(&dir).map_err(err!("cannot create {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            token,
+            quota,
+            ttl,
+            receiving: Mutex::new(Receiving::default()),
+        })
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// 有待转交文件的接收端
+    pub fn recipients(&self) -> Vec<Vec<u8>> {
+        let mut recipients = Vec::new();
+        for entry in entries(&self.dir) {
+            let to = entry.file_name().to_string_lossy().to_string().into_bytes();
+            if entry.path().is_dir() && !self.pending(&to).is_empty() {
+                recipients.push(to);
+            }
+        }
+        recipients
+    }
+
+    /// 待转交给 `to` 的文件，不包括还在接收的文件
+    pub fn pending(&self, to: &[u8]) -> Vec<Deposit> {
+        let dir = match self.recipient_dir(to) {
+            Some(v) => v,
+            None => return Vec::new(),
+        };
+        let files: Vec<_> = entries(&dir).map(|v| v.path()).collect();
+        // 还在接收的文件有日志和 `.part` 文件
+        let receiving: HashSet<_> = files
+            .iter()
+            .filter(|v| v.extension().is_some_and(|ext| ext == "journal"))
+            .flat_map(|v| {
+                let path = v.with_extension("");
+                [part_path(&path), journal_path(&path)]
+            })
+            .collect();
+        let mut files: Vec<_> = files
+            .into_iter()
+            .filter(|v| v.is_file() && !receiving.contains(v))
+            .collect();
+        files.sort();
+        files
+            .into_iter()
+            .map(|path| {
+                let from = self
+                    .sender_path(&path)
+                    .and_then(|v| read_to_string(v).ok())
+                    .unwrap_or_default();
+                Deposit { path, from }
+            })
+            .collect()
+    }
+
+    /// 删除已转交的文件和它的发送端名称
+    pub fn remove(&self, path: &Path) {
+        if let Err(e) = remove_file(path) {
+            warn!("cannot remove {}: {}", path.display(), e);
+        }
+        if let Some(from) = self.sender_path(path) {
+            let _ = remove_file(&from);
+            let _ = from.parent().map(remove_dir);
+        }
+    }
+
+    /// 删除超过保存期限的文件和空的接收端目录
+    pub fn expire(&self) {
+        let now = SystemTime::now();
+        for entry in entries(&self.dir) {
+            let dir = entry.path();
+            if !dir.is_dir() || entry.file_name() == FROM_DIR {
+                continue;
+            }
+            for file in entries(&dir) {
+                let expired = file
+                    .metadata()
+                    .and_then(|v| v.modified())
+                    .is_ok_and(|v| now.duration_since(v).unwrap_or_default() > self.ttl);
+                if expired {
+                    info!("{} expired", file.path().display());
+                    self.remove(&file.path());
+                }
+            }
+            // 不为空时删除失败
+            let _ = remove_dir(&dir);
+        }
+    }
+
+    /// 所有文件的总大小，不包括正在接收的文件，它们已经预留了配额
+    fn usage(&self, receiving: &Receiving) -> u64 {
+        let excluded: HashSet<_> = receiving
+            .paths
+            .iter()
+            .flat_map(|v| [part_path(v), journal_path(v), v.clone()])
+            .collect();
+        entries(&self.dir)
+            .filter(|v| v.path().is_dir() && v.file_name() != FROM_DIR)
+            .flat_map(|v| entries(&v.path()))
+            .filter(|v| !excluded.contains(&v.path()))
+            .filter_map(|v| v.metadata().ok())
+            .map(|v| v.len())
+            .sum()
+    }
+
+    /// 已用完配额
+    fn full(&self) -> bool {
+        let receiving = self.receiving.lock().unwrap();
+        self.usage(&receiving).saturating_add(receiving.size) >= self.quota
+    }
+
+    /// 为接收 `dir` 中大小为 `size` 的文件 `name` 预留配额。
+    /// 已存在同名文件或者同名文件正在接收时重命名
+    fn reserve(&self, dir: &Path, name: &str, size: u64) -> Option<Reservation<'_>> {
+        let mut receiving = self.receiving.lock().unwrap();
+        let usage = self.usage(&receiving).saturating_add(receiving.size);
+        if usage.checked_add(size).is_none_or(|v| v > self.quota) {
+            return None;
+        }
+
+        let free = |v: &str| {
+            let path = dir.join(v);
+            !path.exists() && !receiving.paths.contains(&path)
+        };
+        let mut path = dir.join(name);
+        let mut n = 1;
+        while !free(&path.file_name().unwrap().to_string_lossy()) {
+            path = dir.join(renamed(name, n));
+            n += 1;
+        }
+        receiving.size += size;
+        receiving.paths.insert(path.clone());
+        Some(Reservation {
+            mailbox: self,
+            path,
+            size,
+        })
+    }
+
+    /// 保存文件 `path` 的发送端名称的路径
+    fn sender_path(&self, path: &Path) -> Option<PathBuf> {
+        let to = path.parent()?.file_name()?;
+        let name = path.file_name()?;
+        Some(self.dir.join(FROM_DIR).join(to).join(name))
+    }
+
+    /// 接收端 `to` 的目录，`to` 不能作为目录名时返回 `None`
+    fn recipient_dir(&self, to: &[u8]) -> Option<PathBuf> {
+        let name = std::str::from_utf8(to).ok()?;
+        let valid = !name.is_empty()
+            && name.len() <= MAX_ID_LEN
+            && !name.starts_with('.')
+            && !name.contains(['/', '\\', '\0']);
+        valid.then(|| self.dir.join(name))
+    }
+}
+
+/// 目录中的项，忽略读取错误
+fn entries(dir: &Path) -> impl Iterator<Item = DirEntry> {
+    read_dir(dir).into_iter().flatten().flatten()
+}
+
+/// 通过暂存 peer 发送文件给 `to`，`to` 上线后由暂存 peer 转交。`token` 为所在命名空间的凭证，
+/// `from` 为发送端声明的名称。通道没有加密时不发送
+pub async fn deposit<S: Datagram>(
+    sock: S,
+    to: Vec<u8>,
+    token: Vec<u8>,
+    path: &Path,
+    from: &str,
+) -> crate::Result<()> {
+    let name = String::from_utf8_lossy(&to).to_string();
+    if !sock.is_encrypted() {
+        let e = io::Error::new(ErrorKind::PermissionDenied, "mailbox does not use quic");
+        Err(e).map_err(err!("deposit for {}", name))?;
+    }
+    let mut buf = vec![0u8; 512];
+    let mut op = SendDeposit {
+        sock: &sock,
+        buf: &mut buf,
+        msg: Message::Deposit { to, token },
+    };
+    match perform(&mut op)
+        .await
+        .map_err(err!("deposit for {}", name))?
+    {
+        Message::Denied(reason) => {
+            sock.send(&Message::DeniedAck).await.map_err(err!())?;
+            Err(io::Error::new(ErrorKind::PermissionDenied, reason)).map_err(err!("denied"))
+        }
+        _ => {
+            info!("deposit {} for {} at {}", path.display(), name, sock.peer());
+            send(sock, path, Conflict::Ask, from).await
+        }
+    }
+}
+
+/// 处理 Deposit 请求：把之后发送的文件保存到 `to` 的目录，同时保存发送端声明的名称，
+/// 转交时使用。只接受加密通道上、凭证正确的请求和长度已知的文件，
+/// 保存后总大小超过配额或者 `service.accept` 拒绝时不保存
+pub(crate) async fn accept_deposit<S: Datagram>(
+    sock: S,
+    buf: &mut [u8],
+    to: Vec<u8>,
+    token: Vec<u8>,
+    mailbox: &Mailbox,
+    service: &Service,
+) -> crate::Result<()> {
+    let name = String::from_utf8_lossy(&to).to_string();
+    if !sock.is_encrypted() {
+        return deny(&sock, buf, "mailbox requires quic".to_string()).await;
+    }
+    if !token_eq(&mailbox.token, &token) {
+        return deny(&sock, buf, "unauthorized".to_string()).await;
+    }
+    let dir = match mailbox.recipient_dir(&to) {
+        Some(v) => v,
+        None => return deny(&sock, buf, format!("invalid recipient {}", name)).await,
+    };
+    if mailbox.full() {
+        return deny(&sock, buf, "mailbox is full".to_string()).await;
+    }
+    sock.send(&Message::DepositAck).await.map_err(err!())?;
+
+    let mut req = wait_request(&sock, buf).await?;
+    let (file, size, from) = match req {
+        Message::Request(ref v) => (v.name.clone(), v.id.size, v.from.clone()),
+        _ => return deny(&sock, buf, "mailbox only accepts files".to_string()).await,
+    };
+    if !valid_name(&file) {
+        return deny(&sock, buf, format!("invalid file name {:?}", file)).await;
+    }
+    if !accepted(&sock, buf, &req, service).await? {
+        return Ok(());
+    }
+    let reservation = match mailbox.reserve(&dir, &file, size) {
+        Some(v) => v,
+        None => return deny(&sock, buf, "mailbox is full".to_string()).await,
+    };
+    // 预留时已经避开同名文件
+    let path = reservation.path.clone();
+    if let Message::Request(ref mut v) = req {
+        v.name = path.file_name().unwrap().to_string_lossy().to_string();
+    }
+    create_dir_all(&dir).map_err(err!("cannot create {}", dir.display()))?;
+    info!("deposit from {} for {}", sock.peer(), name);
+    receive_request(&sock, buf, req, dir, Conflict::Overwrite).await?;
+
+    if let Some(sender_path) = mailbox.sender_path(&path) {
+        let parent = sender_path.parent().unwrap();
+        create_dir_all(parent).map_err(err!("cannot create {}", parent.display()))?;
+        write(&sender_path, from).map_err(err!("cannot write {}", sender_path.display()))?;
+    }
+    Ok(())
+}
+
+/// 等待发送请求，DepositAck 丢失时重发
+async fn wait_request<S: Datagram>(sock: &S, buf: &mut [u8]) -> crate::Result<Message> {
+    loop {
+        tokio::select! {
+            msg = sock.recv(buf) => match msg.map_err(err!())? {
+                Message::Deposit { .. } => {
+                    debug!("resend DepositAck to {}", sock.peer());
+                    sock.send(&Message::DepositAck).await.map_err(err!())?;
+                }
+                msg @ (Message::Request(_) | Message::StreamRequest { .. }) => return Ok(msg),
+                _ => {}
+            },
+            _ = sleep(Duration::from_secs(READ_TIMEOUT)) => {
+                Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!())?
+            }
+        }
+    }
+}
+
+/// 发送 Deposit 请求
+struct SendDeposit<'a, S> {
+    sock: &'a S,
+    buf: &'a mut [u8],
+    msg: Message,
+}
+
+#[async_trait]
+impl<'a, S: Datagram> Operation<Message> for SendDeposit<'a, S> {
+    async fn poll(&mut self) -> io::Result<()> {
+        self.sock.send(&self.msg).await
+    }
+
+    async fn resolve(&mut self) -> io::Result<Message> {
+        loop {
+            let msg = self.sock.recv(self.buf).await?;
+            if let Message::DepositAck | Message::Denied(_) = msg {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use super::*;
+
+    fn mailbox(name: &str, quota: u64) -> Mailbox {
+        let dir = std::env::temp_dir().join(format!("mailbox-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        Mailbox::new(dir, Vec::new(), quota, Duration::from_secs(3600)).unwrap()
+    }
+
+    #[test]
+    fn test_recipient_dir() {
+        let mailbox = mailbox("recipient", 100);
+        assert!(mailbox.recipient_dir(b"bar").is_some());
+        for v in [&b""[..], b".from", b"..", b"a/b", b"a\\b", &[0xff]] {
+            assert!(mailbox.recipient_dir(v).is_none(), "{:?}", v);
+        }
+        remove_dir_all(&mailbox.dir).unwrap();
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mailbox = mailbox("reserve", 100);
+        let dir = mailbox.recipient_dir(b"bar").unwrap();
+        create_dir_all(&dir).unwrap();
+        write(dir.join("a.txt"), [0u8; 30]).unwrap();
+
+        // 同名文件已存在或正在接收时重命名
+        let first = mailbox.reserve(&dir, "a.txt", 40).unwrap();
+        assert_eq!(first.path, dir.join("a.1.txt"));
+        let second = mailbox.reserve(&dir, "a.txt", 20).unwrap();
+        assert_eq!(second.path, dir.join("a.2.txt"));
+
+        // 已用 30，预留 60
+        assert!(mailbox.reserve(&dir, "b", 11).is_none());
+        assert!(mailbox.reserve(&dir, "b", u64::MAX).is_none());
+        assert!(!mailbox.full());
+
+        // 正在接收的文件不重复计算
+        write(part_path(&first.path), [0u8; 40]).unwrap();
+        let third = mailbox.reserve(&dir, "b", 10).unwrap();
+        assert!(mailbox.full());
+        drop(third);
+
+        drop(first);
+        drop(second);
+        assert_eq!(mailbox.receiving.lock().unwrap().size, 0);
+        assert!(mailbox.reserve(&dir, "b", 30).is_some());
+        remove_dir_all(&mailbox.dir).unwrap();
+    }
+
+    #[test]
+    fn test_pending() {
+        let mailbox = mailbox("pending", 100);
+        let dir = mailbox.recipient_dir(b"bar").unwrap();
+        create_dir_all(&dir).unwrap();
+        let sender = mailbox.sender_path(&dir.join("a")).unwrap();
+        create_dir_all(sender.parent().unwrap()).unwrap();
+        write(dir.join("a"), b"1").unwrap();
+        write(&sender, "alice").unwrap();
+        write(dir.join("b"), b"2").unwrap();
+        // 还在接收的文件
+        write(part_path(&dir.join("c")), b"3").unwrap();
+        write(journal_path(&dir.join("c")), b"").unwrap();
+
+        let pending = mailbox.pending(b"bar");
+        let files: Vec<_> = pending
+            .iter()
+            .map(|v| (v.path.clone(), v.from.as_str()))
+            .collect();
+        assert_eq!(files, [(dir.join("a"), "alice"), (dir.join("b"), "")]);
+        assert_eq!(mailbox.recipients(), [b"bar".to_vec()]);
+
+        mailbox.remove(&dir.join("a"));
+        assert!(!sender.exists());
+        assert_eq!(mailbox.pending(b"bar").len(), 1);
+        remove_dir_all(&mailbox.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deposit_unencrypted() {
+        // UDP socket 没有加密，不发送
+        let sock = crate::Socket::new_unspecified().await.unwrap();
+        let e = deposit(sock, b"bar".to_vec(), Vec::new(), Path::new("f"), "")
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("does not use quic"), "{}", e);
+    }
+}
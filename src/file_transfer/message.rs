@@ -1,23 +1,85 @@
 use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
 
 use bincode::{DefaultOptions, Options};
 use serde::{Deserialize, Serialize};
 
 use crate::{Decode, Encode};
 
+/// 文件标识
+///
+/// 断点续传时用来判断已接收的部分是否属于同一个文件
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileId {
+    /// 文件大小
+    pub size: u64,
+    /// 修改时间，UNIX 时间戳（秒）
+    pub mtime: u64,
+    /// 文件内容哈希
+    pub hash: u64,
+}
+
+/// 接收端已存在同名文件时的处理方式
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// 覆盖
+    Overwrite,
+    /// 跳过
+    Skip,
+    /// 加后缀重命名
+    Rename,
+    /// 由发送端决定
+    Ask,
+}
+
+impl FromStr for Conflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(Self::Overwrite),
+            "skip" => Ok(Self::Skip),
+            "rename" => Ok(Self::Rename),
+            "ask" => Ok(Self::Ask),
+            _ => Err(format!("invalid conflict policy: {}", s)),
+        }
+    }
+}
+
+/// 接收端对同名文件的实际处理结果
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// 已覆盖
+    Overwrite,
+    /// 已跳过
+    Skip,
+    /// 已重命名为新文件名
+    Rename(String),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
     /// 文件名
     pub name: String,
-    /// 文件大小
-    pub size: u64,
+    /// 文件标识
+    pub id: FileId,
     /// 断点续传
     pub resume: bool,
+    /// 接收端策略为 `Conflict::Ask` 时采用的处理方式
+    pub conflict: Conflict,
+    /// 发送端声明的名称，接收端据此决定是否接收
+    pub from: String,
 }
 
 impl Request {
-    pub fn new(name: String, size: u64, resume: bool) -> Self {
-        Self { name, size, resume }
+    pub fn new(name: String, id: FileId, resume: bool, conflict: Conflict, from: String) -> Self {
+        Self {
+            name,
+            id,
+            resume,
+            conflict,
+            from,
+        }
     }
 }
 
@@ -51,9 +113,28 @@ pub enum Message {
     /// 发送端请求发送文件
     Request(Request),
 
+    /// 发送端请求发送数据流，数据流长度未知，以 StreamEnd 标记结束
+    StreamRequest {
+        /// 数据流名称，接收端保存为文件时作为文件名
+        name: String,
+        /// 接收端策略为 `Conflict::Ask` 时采用的处理方式
+        conflict: Conflict,
+        /// 发送端声明的名称
+        from: String,
+    },
+
+    /// 接收端正在决定是否接收，发送端继续等待
+    Pending,
+
     /// 接收端确认接收文件
     Response(Response),
 
+    /// 接收端通知同名文件的处理结果
+    Conflict(Resolution),
+
+    /// 发送端确认收到 Conflict 消息
+    ConflictAck,
+
     /// 文件分块数据
     FilePart {
         /// chunk 所属 block
@@ -66,6 +147,12 @@ pub enum Message {
     /// 发送端通知 block 发送完毕
     BlockComplete(u32),
 
+    /// 发送端通知数据流最后一个 block 发送完毕，代替 BlockComplete
+    StreamEnd {
+        block: u32,
+        size: u32, // 最后一个 block 的大小，可能为 0
+    },
+
     /// 接收端确认 block 已完整接收
     BlockCompleteAck(u32),
 
@@ -81,6 +168,46 @@ pub enum Message {
 
     /// 发送端确认收到 File 消息
     FileCompleteAck,
+
+    /// 请求获取服务端目录列表，服务端以数据流发送列表
+    List { path: String },
+
+    /// 请求获取服务端文件，服务端以 Message::Request 开始发送文件
+    Fetch { path: String },
+
+    /// 服务端拒绝 Request、StreamRequest、List、Fetch、Forward、ForwardUdp、Tunnel、Relay 或 Deposit 请求
+    Denied(String),
+
+    /// 确认收到 Denied 消息
+    DeniedAck,
+
+    /// 请求服务端连接 `host:port`，之后在通道上转发 TCP 数据
+    Forward { host: String, port: u16 },
+
+    /// 服务端已连接，开始转发
+    ForwardAck,
+
+    /// 请求服务端向 `host:port` 转发 UDP 数据报，服务端同样回复 ForwardAck
+    ForwardUdp { host: String, port: u16 },
+
+    /// 转发的 UDP 数据报或 IP 包，数据附加在消息后
+    Datagram,
+
+    /// 请求和服务端的 TUN 设备之间转发 IP 包，服务端同样回复 ForwardAck
+    Tunnel,
+
+    /// 请求网络成员把通道中继到和它直连的成员 `to`，之后的数据原样转发
+    Relay { to: Vec<u8> },
+
+    /// 中继成员已打开到 `to` 的通道
+    RelayAck,
+
+    /// 请求暂存 peer 保存之后发送的文件，`to` 上线后再转交给它。
+    /// `token` 为暂存 peer 所在命名空间的凭证，`to` 在同一个命名空间中
+    Deposit { to: Vec<u8>, token: Vec<u8> },
+
+    /// 暂存 peer 接受暂存，之后以 Message::Request 开始发送文件
+    DepositAck,
 }
 
 impl Message {
@@ -136,3 +263,28 @@ impl<'a> Encode for Chunk<'a> {
         v
     }
 }
+
+/// 转发的 UDP 数据报或 IP 包
+pub struct Payload<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Debug for Payload<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Datagram({} bytes)", self.data.len())
+    }
+}
+
+impl<'a> Encode for Payload<'a> {
+    fn encode(&self) -> Vec<u8> {
+        let mut v = Message::Datagram.encode();
+        v.extend_from_slice(self.data);
+        v
+    }
+}
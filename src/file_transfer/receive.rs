@@ -1,14 +1,16 @@
 use std::io::{self, ErrorKind};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
 use log::{debug, info};
+use tokio::fs::{rename, File};
+use tokio::io::AsyncWrite;
 use tokio::time::{sleep, Duration};
 
-use crate::file_transfer::block::BlockWriter;
-use crate::file_transfer::message::Request;
-use crate::file_transfer::{Message, Response};
-use crate::{perform, Operation, Socket};
+use crate::file_transfer::block::{part_path, BlockWriter, StreamWriter};
+use crate::file_transfer::serve::deny;
+use crate::file_transfer::{Conflict, Message, Resolution, Response};
+use crate::{perform, Datagram, Operation};
 
 /// block 大小为 1 MiB
 const BLOCK_SIZE: u32 = 1048576;
@@ -19,42 +21,103 @@ const CHUNK_SIZE: u16 = 496;
 /// Message::Chunk 大小
 const CHUNK_HEAD_SIZE: usize = 12;
 
+/// 接收缓冲区大小
+pub const RECV_BUF_SIZE: usize = CHUNK_HEAD_SIZE + CHUNK_SIZE as usize;
+
 /// 读取超时时间
 const READ_TIMEOUT: u64 = 5;
 
 /// 接收文件
-pub async fn receive(sock: Socket, path: PathBuf) -> crate::Result<()> {
-    let mut buf = vec![0u8; CHUNK_HEAD_SIZE + CHUNK_SIZE as usize];
+///
+/// `conflict` 指定保存目录中已存在同名文件时的处理方式。
+/// 发送端发送的是数据流时，保存为同名文件
+pub async fn receive<S: Datagram>(sock: S, path: PathBuf, conflict: Conflict) -> crate::Result<()> {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let req = wait_request(&sock, &mut buf).await?;
+    receive_request(&sock, &mut buf, req, path, conflict).await
+}
+
+/// 处理发送请求，`req` 为 Message::Request 或 Message::StreamRequest
+pub async fn receive_request<S: Datagram>(
+    sock: &S,
+    buf: &mut [u8],
+    req: Message,
+    path: PathBuf,
+    conflict: Conflict,
+) -> crate::Result<()> {
+    let (name, requested) = match req {
+        Message::Request(ref req) => (req.name.clone(), req.conflict),
+        Message::StreamRequest {
+            ref name, conflict, ..
+        } => (name.clone(), conflict),
+        _ => unreachable!(),
+    };
+
+    // 文件名由发送端指定，只能是保存目录中的文件
+    if !valid_name(&name) {
+        return deny(sock, buf, format!("invalid file name {:?}", name)).await;
+    }
+
+    info!("receiving {}", name);
 
-    let req: Request = tokio::select! {
-        req = read_request(&sock, &mut buf) => {
-            req?
+    let mut name = name;
+    if path.join(&name).exists() {
+        let resolution = resolve_conflict(&path, &name, conflict, requested);
+        info!("{} already exists: {:?}", name, resolution);
+
+        let mut op = SendConflict {
+            sock,
+            buf,
+            resolution: resolution.clone(),
+        };
+        perform(&mut op).await.map_err(err!())?;
+        match resolution {
+            Resolution::Overwrite => {}
+            Resolution::Skip => return Ok(()),
+            Resolution::Rename(v) => name = v,
         }
-        _ = sleep(Duration::from_secs(READ_TIMEOUT)) => {
-            Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!())?
+    }
+
+    let req = match req {
+        Message::Request(req) => req,
+        _ => {
+            let path = path.join(&name);
+            let part = part_path(&path);
+            let file = File::create(&part)
+                .await
+                .map_err(err!("cannot open {}", part.display()))?;
+            let mut writer = StreamWriter::new(file, BLOCK_SIZE, CHUNK_SIZE);
+            receive_stream_blocks(sock, buf, &mut writer, None).await?;
+            writer.finish().await?;
+            rename(&part, &path).await.map_err(err!(
+                "rename {} to {}",
+                part.display(),
+                path.display()
+            ))?;
+            return complete(sock, buf, &name).await;
         }
     };
 
-    info!("receiving {}", req.name);
-
     let mut writer =
-        match BlockWriter::new(path.join(&req.name), req.size, BLOCK_SIZE, CHUNK_SIZE, true)? {
+        match BlockWriter::new(path.join(&name), req.id, BLOCK_SIZE, CHUNK_SIZE, req.resume)? {
             Some(v) => v,
-            None => return complete(&sock, &mut buf, &req.name).await,
+            None => return complete(sock, buf, &name).await,
         };
 
     let mut block = writer.next_block().unwrap();
     let mut op = SendResponse {
-        sock: &sock,
-        buf: &mut buf,
+        sock,
+        buf,
         block: block.index(),
     };
-    let first_chunk = perform(&mut op).await.map_err(err!())?;
-    block.write(first_chunk.chunk, &buf[first_chunk.start..first_chunk.end]);
+    let first = perform(&mut op).await.map_err(err!())?;
+    if let Message::FilePart { chunk, .. } = first.msg {
+        block.write(chunk, &buf[first.start..first.end]);
+    }
 
     loop {
         tokio::select! {
-            msg = read_message(&sock, &mut buf) => {
+            msg = read_message(sock, buf) => {
                 match msg.map_err(err!())? {
                     (Message::FilePart { block: b, chunk }, data) if b == block.index() => block.write(chunk, data),
                     (Message::BlockComplete(b), _) if b == block.index() => {
@@ -67,11 +130,7 @@ pub async fn receive(sock: Socket, path: PathBuf) -> crate::Result<()> {
                                 None => break,
                             };
                         } else {
-                            let count = missing.len() as u32;
-                            for v in missing.as_slice().chunks(100) {
-                                let msg = Message::BlockMissingChunk { block: b, chunk: v.to_vec(), count };
-                                sock.send(&msg).await.map_err(err!())?;
-                            }
+                            send_missing_chunk(sock, b, missing).await?;
                         }
                     }
                     // 发送端未收到 Message::BlockCompleteAck(b)
@@ -88,23 +147,216 @@ pub async fn receive(sock: Socket, path: PathBuf) -> crate::Result<()> {
     }
 
     writer.rename_file()?;
-    complete(&sock, &mut buf, &req.name).await
+    complete(sock, buf, &name).await
+}
+
+/// 接收数据流，写入 `writer`
+///
+/// 发送端发送的是文件时，写入文件内容
+pub async fn receive_stream<S, W>(sock: S, writer: W) -> crate::Result<()>
+where
+    S: Datagram,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let req = wait_request(&sock, &mut buf).await?;
+    receive_stream_request(&sock, &mut buf, req, writer).await
+}
+
+/// 处理发送请求，写入 `writer`。`req` 为 Message::Request 或 Message::StreamRequest
+pub async fn receive_stream_request<S, W>(
+    sock: &S,
+    buf: &mut [u8],
+    req: Message,
+    writer: W,
+) -> crate::Result<()>
+where
+    S: Datagram,
+    W: AsyncWrite + Unpin,
+{
+    let (name, size) = match req {
+        Message::Request(req) => (req.name, Some(req.id.size)),
+        Message::StreamRequest { name, .. } => (name, None),
+        _ => unreachable!(),
+    };
+
+    info!("receiving {}", name);
+
+    let mut writer = StreamWriter::new(writer, BLOCK_SIZE, CHUNK_SIZE);
+    if size != Some(0) {
+        receive_stream_blocks(sock, buf, &mut writer, size).await?;
+    }
+    writer.finish().await?;
+    complete(sock, buf, &name).await
+}
+
+/// 接收数据流分块。`size` 为 `None` 表示长度未知，以 Message::StreamEnd 结束
+async fn receive_stream_blocks<S, W>(
+    sock: &S,
+    buf: &mut [u8],
+    writer: &mut StreamWriter<W>,
+    size: Option<u64>,
+) -> crate::Result<()>
+where
+    S: Datagram,
+    W: AsyncWrite + Unpin,
+{
+    // 长度已知时，根据长度计算 block 大小
+    let block_size = |index: u32| match size {
+        Some(size) => (size - index as u64 * BLOCK_SIZE as u64).min(BLOCK_SIZE as u64) as u32,
+        None => BLOCK_SIZE,
+    };
+    let last_block = size.map(|v| ((v - 1) / BLOCK_SIZE as u64) as u32);
+
+    let mut block = writer.next_block();
+    let mut op = SendResponse {
+        sock,
+        buf,
+        block: block.index(),
+    };
+    let first = perform(&mut op).await.map_err(err!())?;
+    let mut first = Some((first.msg, first.start, first.end));
+
+    loop {
+        let (msg, data) = match first.take() {
+            Some((msg, start, end)) => (msg, &buf[start..end]),
+            None => tokio::select! {
+                msg = read_message(sock, buf) => msg.map_err(err!())?,
+                _ = sleep(Duration::from_secs(READ_TIMEOUT)) => {
+                    Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!())?
+                }
+            },
+        };
+
+        let (b, end) = match msg {
+            Message::FilePart { block: b, chunk } if b == block.index() => {
+                if !block.write(chunk, data) {
+                    debug!("drop chunk {} of block {} from {}", chunk, b, sock.peer());
+                }
+                continue;
+            }
+            Message::BlockComplete(b) if b == block.index() => {
+                block.set_size(block_size(b))?;
+                (b, Some(b) == last_block)
+            }
+            Message::StreamEnd { block: b, size } if b == block.index() => {
+                block.set_size(size)?;
+                (b, true)
+            }
+            // 发送端未收到 Message::BlockCompleteAck(b)
+            Message::BlockComplete(b) | Message::StreamEnd { block: b, .. }
+                if b + 1 == block.index() =>
+            {
+                sock.send(&Message::BlockCompleteAck(b))
+                    .await
+                    .map_err(err!())?;
+                continue;
+            }
+            _ => continue,
+        };
+
+        let missing = block.get_missing_chunk();
+        if missing.is_empty() {
+            sock.send(&Message::BlockCompleteAck(b))
+                .await
+                .map_err(err!())?;
+            block.commit().await?;
+            if end {
+                return Ok(());
+            }
+            block = writer.next_block();
+        } else {
+            send_missing_chunk(sock, b, missing).await?;
+        }
+    }
+}
+
+/// 通知发送端缺少的 chunk
+async fn send_missing_chunk<S: Datagram>(
+    sock: &S,
+    block: u32,
+    missing: Vec<u32>,
+) -> crate::Result<()> {
+    let count = missing.len() as u32;
+    for v in missing.as_slice().chunks(100) {
+        let msg = Message::BlockMissingChunk {
+            block,
+            chunk: v.to_vec(),
+            count,
+        };
+        sock.send(&msg).await.map_err(err!())?;
+    }
+    Ok(())
+}
+
+/// 等待发送请求，返回 Message::Request 或 Message::StreamRequest
+async fn wait_request<S: Datagram>(sock: &S, buf: &mut [u8]) -> crate::Result<Message> {
+    tokio::select! {
+        req = read_request(sock, buf) => {
+            req
+        }
+        _ = sleep(Duration::from_secs(READ_TIMEOUT)) => {
+            Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!())
+        }
+    }
+}
+
+/// 文件名不能为空、`.` 或 `..`，不能包含路径分隔符
+pub(crate) fn valid_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\\', '\0'])
+}
+
+/// 确定同名文件的处理方式
+fn resolve_conflict(dir: &Path, name: &str, conflict: Conflict, requested: Conflict) -> Resolution {
+    let conflict = match (conflict, requested) {
+        (Conflict::Ask, Conflict::Ask) => Conflict::Rename,
+        (Conflict::Ask, v) => v,
+        (v, _) => v,
+    };
+    match conflict {
+        Conflict::Overwrite => Resolution::Overwrite,
+        Conflict::Skip => Resolution::Skip,
+        _ => {
+            let mut n = 1;
+            loop {
+                let v = renamed(name, n);
+                if !dir.join(&v).exists() {
+                    return Resolution::Rename(v);
+                }
+                n += 1;
+            }
+        }
+    }
+}
+
+/// 重命名同名文件，在扩展名之前加上编号 `n`
+pub(crate) fn renamed(name: &str, n: u32) -> String {
+    let path = Path::new(name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, n),
+    }
 }
 
 /// 读取发送请求
-async fn read_request(sock: &Socket, buf: &mut [u8]) -> crate::Result<Request> {
+pub(crate) async fn read_request<S: Datagram>(sock: &S, buf: &mut [u8]) -> crate::Result<Message> {
     loop {
-        if let Message::Request(req) = sock.recv(buf).await.map_err(err!())? {
-            return Ok(req);
+        let msg = sock.recv(buf).await.map_err(err!())?;
+        if let Message::Request(_) | Message::StreamRequest { .. } = msg {
+            return Ok(msg);
         }
     }
 }
 
-async fn read_message<'a>(sock: &Socket, buf: &'a mut [u8]) -> io::Result<(Message, &'a [u8])> {
+async fn read_message<'a, S: Datagram>(
+    sock: &S,
+    buf: &'a mut [u8],
+) -> io::Result<(Message, &'a [u8])> {
     loop {
-        let n = sock.as_ref().recv(buf).await?;
+        let n = sock.recv_bytes(buf).await?;
         if let Some((msg, remain)) = Message::trailing_decode(&buf[..n]) {
-            let addr = sock.connected_addr().unwrap();
+            let addr = sock.peer();
             debug!("receive {:?} from {}", msg, addr);
 
             match msg {
@@ -116,7 +368,7 @@ async fn read_message<'a>(sock: &Socket, buf: &'a mut [u8]) -> io::Result<(Messa
     }
 }
 
-async fn complete(sock: &Socket, buf: &mut [u8], filename: &str) -> crate::Result<()> {
+async fn complete<S: Datagram>(sock: &S, buf: &mut [u8], filename: &str) -> crate::Result<()> {
     let mut op = SendComplete { sock, buf };
     perform(&mut op).await.map_err(err!())?;
     info!("receive {} complete", filename);
@@ -124,13 +376,13 @@ async fn complete(sock: &Socket, buf: &mut [u8], filename: &str) -> crate::Resul
 }
 
 /// 发送传输完成消息
-struct SendComplete<'a> {
-    sock: &'a Socket,
+struct SendComplete<'a, S> {
+    sock: &'a S,
     buf: &'a mut [u8],
 }
 
 #[async_trait]
-impl<'a> Operation<()> for SendComplete<'a> {
+impl<'a, S: Datagram> Operation<()> for SendComplete<'a, S> {
     async fn poll(&mut self) -> io::Result<()> {
         self.sock.send(&Message::FileComplete).await
     }
@@ -148,43 +400,150 @@ impl<'a> Operation<()> for SendComplete<'a> {
     }
 }
 
+/// 通知发送端同名文件的处理结果
+struct SendConflict<'a, S> {
+    sock: &'a S,
+    buf: &'a mut [u8],
+    resolution: Resolution,
+}
+
+#[async_trait]
+impl<'a, S: Datagram> Operation<()> for SendConflict<'a, S> {
+    async fn poll(&mut self) -> io::Result<()> {
+        let msg = Message::Conflict(self.resolution.clone());
+        self.sock.send(&msg).await
+    }
+
+    async fn resolve(&mut self) -> io::Result<()> {
+        loop {
+            if let Message::ConflictAck = self.sock.recv(self.buf).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn result(&mut self) -> Option<()> {
+        Some(())
+    }
+}
+
 /// 发送响应消息
-struct SendResponse<'a> {
-    sock: &'a Socket,
+struct SendResponse<'a, S> {
+    sock: &'a S,
     buf: &'a mut [u8],
     block: u32,
 }
 
-struct FirstChunk {
-    chunk: u32,
+/// 发送端收到响应后发送的第一个消息，`start..end` 为附带的数据
+struct FirstMessage {
+    msg: Message,
     start: usize,
     end: usize,
 }
 
 #[async_trait]
-impl<'a> Operation<FirstChunk> for SendResponse<'a> {
+impl<'a, S: Datagram> Operation<FirstMessage> for SendResponse<'a, S> {
     async fn poll(&mut self) -> io::Result<()> {
         let resp = Response::new(BLOCK_SIZE, CHUNK_SIZE, self.block);
         self.sock.send(&Message::Response(resp)).await
     }
 
-    async fn resolve(&mut self) -> io::Result<FirstChunk> {
+    async fn resolve(&mut self) -> io::Result<FirstMessage> {
         loop {
-            let n = self.sock.as_ref().recv(self.buf).await?;
+            let n = self.sock.recv_bytes(self.buf).await?;
             if let Some((msg, remain)) = Message::trailing_decode(&self.buf[..n]) {
-                let addr = self.sock.connected_addr().unwrap();
+                let addr = self.sock.peer();
                 debug!("receive {:?} from {}", msg, addr);
                 match msg {
-                    Message::FilePart { block, chunk } if block == self.block => {
-                        return Ok(FirstChunk {
-                            chunk,
+                    Message::FilePart { block, .. } if block == self.block => {
+                        return Ok(FirstMessage {
+                            msg,
                             start: n - remain,
                             end: n,
                         });
                     }
+                    // 数据流为空
+                    Message::StreamEnd { block, .. } if block == self.block && remain == 0 => {
+                        return Ok(FirstMessage {
+                            msg,
+                            start: n,
+                            end: n,
+                        });
+                    }
                     _ => {}
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::*;
+
+    /// 临时目录，包含文件 `files`
+    fn temp_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("receive-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        for v in files {
+            write(dir.join(v), b"").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("a.txt"));
+        assert!(valid_name(".bashrc"));
+        assert!(valid_name("a..b"));
+        for v in ["", ".", "..", "a/b", "../a", "/etc/passwd", "a\\b", "a\0"] {
+            assert!(!valid_name(v), "{:?}", v);
+        }
+    }
+
+    #[test]
+    fn test_resolve_conflict() {
+        let dir = temp_dir("conflict", &["a.txt", "a.1.txt", "b", ".bashrc"]);
+        let resolve = |name, conflict, requested| resolve_conflict(&dir, name, conflict, requested);
+
+        assert_eq!(
+            resolve("a.txt", Conflict::Overwrite, Conflict::Rename),
+            Resolution::Overwrite
+        );
+        assert_eq!(
+            resolve("a.txt", Conflict::Skip, Conflict::Overwrite),
+            Resolution::Skip
+        );
+        assert_eq!(
+            resolve("a.txt", Conflict::Rename, Conflict::Ask),
+            Resolution::Rename("a.2.txt".to_string())
+        );
+        assert_eq!(
+            resolve("b", Conflict::Rename, Conflict::Ask),
+            Resolution::Rename("b.1".to_string())
+        );
+        assert_eq!(
+            resolve(".bashrc", Conflict::Rename, Conflict::Ask),
+            Resolution::Rename(".bashrc.1".to_string())
+        );
+        // 接收端询问时由发送端决定，都询问时重命名
+        assert_eq!(
+            resolve("b", Conflict::Ask, Conflict::Skip),
+            Resolution::Skip
+        );
+        assert_eq!(
+            resolve("b", Conflict::Ask, Conflict::Ask),
+            Resolution::Rename("b.1".to_string())
+        );
+        // 没有文件名部分时不会 panic
+        assert_eq!(
+            resolve("..", Conflict::Rename, Conflict::Ask),
+            Resolution::Rename(".1".to_string())
+        );
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
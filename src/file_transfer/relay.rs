@@ -0,0 +1,101 @@
+use std::io::{self, ErrorKind};
+
+use async_trait::async_trait;
+use log::{debug, info};
+use tokio::time::{sleep, Duration};
+
+use crate::file_transfer::serve::deny;
+use crate::file_transfer::Message;
+use crate::mesh::Mesh;
+use crate::{perform, Datagram, Decode, Operation};
+
+/// 中继空闲时间，超过此时间双方都没有数据时结束中继
+const RELAY_IDLE_DURATION: Duration = Duration::from_secs(60);
+
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// 请求中继成员把通道 `sock` 中继到成员 `to`，成功后 `sock` 可以当作到 `to` 的通道使用
+pub async fn relay<S: Datagram>(sock: &S, to: Vec<u8>) -> crate::Result<()> {
+    let name = String::from_utf8_lossy(&to).to_string();
+    let mut buf = vec![0u8; 512];
+    let mut op = SendRelay {
+        sock,
+        buf: &mut buf,
+        msg: Message::Relay { to },
+    };
+    match perform(&mut op).await.map_err(err!("relay to {}", name))? {
+        Message::Denied(reason) => {
+            sock.send(&Message::DeniedAck).await.map_err(err!())?;
+            Err(io::Error::new(ErrorKind::PermissionDenied, reason)).map_err(err!("denied"))
+        }
+        _ => {
+            info!("relay to {} via {}", name, sock.peer());
+            Ok(())
+        }
+    }
+}
+
+/// 处理 Relay 请求：打开到 `to` 的通道，在两个通道之间原样转发数据
+pub(crate) async fn accept_relay<S: Datagram>(
+    sock: S,
+    buf: &mut [u8],
+    to: Vec<u8>,
+    mesh: &Mesh,
+) -> crate::Result<()> {
+    let name = String::from_utf8_lossy(&to).to_string();
+    let session = match mesh.session(&to) {
+        Some(v) => v,
+        None => return deny(&sock, buf, format!("{} is not connected", name)).await,
+    };
+    let target = session.open().await?;
+    info!("relay {} to {}", sock.peer(), name);
+    sock.send(&Message::RelayAck).await.map_err(err!())?;
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut data = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            recv = sock.recv_bytes(&mut buf) => {
+                let n = recv.map_err(err!())?;
+                // RelayAck 丢失，对方重发了请求
+                if let Some(Message::Relay { .. }) = Message::decode(&buf[..n]) {
+                    debug!("resend RelayAck to {}", sock.peer());
+                    sock.send(&Message::RelayAck).await.map_err(err!())?;
+                    continue;
+                }
+                target.send_bytes(&buf[..n]).await.map_err(err!())?;
+            }
+            recv = target.recv_bytes(&mut data) => {
+                let n = recv.map_err(err!())?;
+                sock.send_bytes(&data[..n]).await.map_err(err!())?;
+            }
+            _ = sleep(RELAY_IDLE_DURATION) => {
+                info!("relay {} to {} expired", sock.peer(), name);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// 发送 Relay 请求
+struct SendRelay<'a, S> {
+    sock: &'a S,
+    buf: &'a mut [u8],
+    msg: Message,
+}
+
+#[async_trait]
+impl<'a, S: Datagram> Operation<Message> for SendRelay<'a, S> {
+    async fn poll(&mut self) -> io::Result<()> {
+        self.sock.send(&self.msg).await
+    }
+
+    async fn resolve(&mut self) -> io::Result<Message> {
+        loop {
+            let msg = self.sock.recv(self.buf).await?;
+            if let Message::RelayAck | Message::Denied(_) = msg {
+                return Ok(msg);
+            }
+        }
+    }
+}
@@ -3,30 +3,59 @@ use std::fs::File;
 use std::io;
 use std::io::ErrorKind;
 use std::path::Path;
+use std::time::UNIX_EPOCH;
 
 use async_trait::async_trait;
 use log::info;
+use tokio::io::AsyncRead;
+use tokio::task::spawn_blocking;
 use tokio::time::{sleep, Duration, Instant};
 
-use crate::file_transfer::block::{Block, BlockReader};
+use crate::file_transfer::block::{Block, BlockReader, StreamReader};
+use crate::file_transfer::journal::file_hash;
 use crate::file_transfer::message::Chunk;
-use crate::file_transfer::{Message, Request, Response};
-use crate::{perform, Operation, Socket};
+use crate::file_transfer::{Conflict, FileId, Message, Request, Resolution, Response};
+use crate::{perform, Datagram, Operation};
 
 /// 读取超时时间
 const READ_TIMEOUT: u64 = 5;
 
+/// 接收端正在决定是否接收时，间隔此时间重发请求
+const PENDING_INTERVAL: Duration = Duration::from_millis(200);
+
 /// 发送文件
-pub async fn send(sock: Socket, path: &Path) -> crate::Result<()> {
-    let file = File::open(&path).map_err(err!("cannot open {}", path.display()))?;
-    let file_size = path.metadata().map_err(err!())?.len();
+///
+/// `conflict` 指定接收端策略为 `Conflict::Ask` 时同名文件的处理方式，
+/// `from` 为发送端声明的名称，接收端据此决定是否接收
+pub async fn send<S: Datagram>(
+    sock: S,
+    path: &Path,
+    conflict: Conflict,
+    from: &str,
+) -> crate::Result<()> {
+    let file = File::open(path).map_err(err!("cannot open {}", path.display()))?;
+    // 计算哈希需要读取整个文件，不阻塞其他任务
+    let (file, id) = spawn_blocking(move || {
+        let mut file = file;
+        let id = file_id(&mut file);
+        (file, id)
+    })
+    .await
+    .map_err(err!())?;
+    let id = id.map_err(err!())?;
+    let file_size = id.size;
     let name = path.file_name().unwrap().to_string_lossy().to_string();
 
     info!("sending {}", path.display());
 
     let mut buf = vec![0; 512];
-    let mut op = SendRequest::new(&sock, &mut buf, name, file_size);
-    let response = match perform(&mut op).await.map_err(err!("send request"))? {
+    let msg = Message::Request(Request::new(name, id, true, conflict, from.to_string()));
+    let mut op = SendRequest::new(&sock, &mut buf, msg);
+    let response = request(&mut op).await.map_err(err!("send request"))?;
+    if !report_resolution(op.resolution.take(), &path.display()) {
+        return Ok(());
+    }
+    let response = match response {
         Some(v) => v,
         None => {
             info!("send {} complete", path.display());
@@ -43,19 +72,98 @@ pub async fn send(sock: Socket, path: &Path) -> crate::Result<()> {
         response.chunk_size,
         response.start_block,
     )?;
+    while let Some(block) = reader.read()? {
+        send_block(&sock, &mut buf, block, false, &mut st).await?;
+    }
+    wait_complete(&sock, &mut buf).await?;
+
+    info!("send {} complete", path.display());
+    info!("{}", st);
+    Ok(())
+}
+
+/// 发送数据流，数据流长度未知
+///
+/// `name` 为数据流名称，接收端保存为文件时作为文件名
+pub async fn send_stream<S, R>(
+    sock: S,
+    reader: R,
+    name: String,
+    conflict: Conflict,
+    from: &str,
+) -> crate::Result<()>
+where
+    S: Datagram,
+    R: AsyncRead + Unpin,
+{
+    info!("sending {}", name);
+
+    let mut buf = vec![0; 512];
+    let msg = Message::StreamRequest {
+        name: name.clone(),
+        conflict,
+        from: from.to_string(),
+    };
+    let mut op = SendRequest::new(&sock, &mut buf, msg);
+    let response = request(&mut op).await.map_err(err!("send request"))?;
+    if !report_resolution(op.resolution.take(), &name) {
+        return Ok(());
+    }
+    let response = match response {
+        Some(v) => v,
+        None => Err(io::Error::from(ErrorKind::InvalidData)).map_err(err!("send request"))?,
+    };
+
+    let mut st = Statistic::default();
+    let mut reader = StreamReader::new(reader, response.block_size, response.chunk_size);
+    while let Some((block, end)) = reader.read().await? {
+        send_block(&sock, &mut buf, block, end, &mut st).await?;
+    }
+    wait_complete(&sock, &mut buf).await?;
+
+    info!("send {} complete", name);
+    info!("{}", st);
+    Ok(())
+}
+
+/// 发送请求，接收端正在决定是否接收时一直等待，直到接收端确认或者拒绝
+async fn request<S: Datagram>(op: &mut SendRequest<'_, S>) -> io::Result<Option<Response>> {
     loop {
-        match reader.read()? {
-            Some(block) => send_block(&sock, &mut buf, block, &mut st).await?,
-            None => break,
+        let response = perform(op).await?;
+        if !op.pending {
+            return Ok(response);
+        }
+        if !op.waiting {
+            info!("waiting for receiver to accept");
+            op.waiting = true;
         }
+        op.pending = false;
+        sleep(PENDING_INTERVAL).await;
     }
+}
 
+/// 记录接收端对同名文件的处理结果，返回是否需要继续发送
+fn report_resolution(resolution: Option<Resolution>, name: &impl Display) -> bool {
+    match resolution {
+        Some(Resolution::Overwrite) => info!("receiver overwrites existing file"),
+        Some(Resolution::Skip) => {
+            info!("receiver skips {}: file exists", name);
+            return false;
+        }
+        Some(Resolution::Rename(v)) => info!("receiver saves file as {}", v),
+        None => {}
+    }
+    true
+}
+
+/// 等待接收端通知传输完成
+async fn wait_complete<S: Datagram>(sock: &S, buf: &mut [u8]) -> crate::Result<()> {
     loop {
         tokio::select! {
-            msg = sock.recv(&mut buf) => {
+            msg = sock.recv(buf) => {
                 if let Message::FileComplete = msg.map_err(err!())? {
                     sock.send(&Message::FileCompleteAck).await.map_err(err!())?;
-                    break;
+                    return Ok(());
                 }
             }
             _ = sleep(Duration::from_secs(READ_TIMEOUT)) => {
@@ -63,17 +171,29 @@ pub async fn send(sock: Socket, path: &Path) -> crate::Result<()> {
             }
         }
     }
+}
 
-    info!("send {} complete", path.display());
-    info!("{}", st);
-    Ok(())
+/// 获取文件标识
+fn file_id(file: &mut File) -> io::Result<FileId> {
+    let metadata = file.metadata()?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_secs());
+    let hash = file_hash(file)?;
+    Ok(FileId {
+        size: metadata.len(),
+        mtime,
+        hash,
+    })
 }
 
-/// 发送分块
-async fn send_block(
-    sock: &Socket,
+/// 发送分块，`end` 表示是否是数据流最后一个分块
+async fn send_block<S: Datagram>(
+    sock: &S,
     buf: &mut [u8],
     block: Block<'_>,
+    end: bool,
     st: &mut Statistic,
 ) -> crate::Result<()> {
     let mut chunk = 0;
@@ -85,7 +205,7 @@ async fn send_block(
     st.chunk += chunk as u64;
 
     loop {
-        let mut op = SendBlockComplete::new(&sock, buf, block.index());
+        let mut op = SendBlockComplete::new(sock, buf, block.index(), end.then(|| block.size()));
         let missing = perform(&mut op).await.map_err(err!())?;
         if missing.is_empty() {
             break;
@@ -94,7 +214,7 @@ async fn send_block(
         for c in missing {
             let data = block
                 .get_chunk(c)
-                .expect(&format!("chunk {} out of range", c));
+                .unwrap_or_else(|| panic!("chunk {} out of range", c));
             let msg = Chunk::new(block.index(), c, data);
             sock.send(&msg).await.map_err(err!())?;
         }
@@ -105,22 +225,34 @@ async fn send_block(
 }
 
 /// 发送文件传输请求
-struct SendRequest<'a> {
-    sock: &'a Socket,
+struct SendRequest<'a, S> {
+    sock: &'a S,
     buf: &'a mut [u8],
     msg: Message,
+    /// 接收端对同名文件的处理结果
+    resolution: Option<Resolution>,
+    /// 收到 Message::Pending，接收端还没有决定是否接收
+    pending: bool,
+    /// 已经在等待接收端决定
+    waiting: bool,
 }
 
-impl<'a> SendRequest<'a> {
-    fn new(sock: &'a Socket, buf: &'a mut [u8], name: String, size: u64) -> Self {
-        let resume = true;
-        let msg = Message::Request(Request::new(name, size, resume));
-        Self { sock, buf, msg }
+impl<'a, S: Datagram> SendRequest<'a, S> {
+    /// `msg` 为 Message::Request 或 Message::StreamRequest
+    fn new(sock: &'a S, buf: &'a mut [u8], msg: Message) -> Self {
+        Self {
+            sock,
+            buf,
+            msg,
+            resolution: None,
+            pending: false,
+            waiting: false,
+        }
     }
 }
 
 #[async_trait]
-impl<'a> Operation<Option<Response>> for SendRequest<'a> {
+impl<'a, S: Datagram> Operation<Option<Response>> for SendRequest<'a, S> {
     async fn poll(&mut self) -> std::io::Result<()> {
         self.sock.send(&self.msg).await
     }
@@ -130,6 +262,22 @@ impl<'a> Operation<Option<Response>> for SendRequest<'a> {
             match self.sock.recv(self.buf).await? {
                 Message::Response(response) => return Ok(Some(response)),
                 Message::FileComplete => return Ok(None),
+                Message::Pending => {
+                    self.pending = true;
+                    return Ok(None);
+                }
+                Message::Denied(reason) => {
+                    self.sock.send(&Message::DeniedAck).await?;
+                    return Err(io::Error::new(ErrorKind::PermissionDenied, reason));
+                }
+                Message::Conflict(resolution) => {
+                    self.sock.send(&Message::ConflictAck).await?;
+                    let skip = resolution == Resolution::Skip;
+                    self.resolution = Some(resolution);
+                    if skip {
+                        return Ok(None);
+                    }
+                }
                 _ => {}
             }
         }
@@ -137,33 +285,43 @@ impl<'a> Operation<Option<Response>> for SendRequest<'a> {
 }
 
 /// 发送分块完成消息
-struct SendBlockComplete<'a> {
-    sock: &'a Socket,
+struct SendBlockComplete<'a, S> {
+    sock: &'a S,
     buf: &'a mut [u8],
     block: u32,
+    /// 数据流最后一个分块的大小
+    end: Option<u32>,
     missing_chunk: Option<Vec<u32>>,
 }
 
-impl<'a> SendBlockComplete<'a> {
-    fn new(sock: &'a Socket, buf: &'a mut [u8], block: u32) -> Self {
+impl<'a, S: Datagram> SendBlockComplete<'a, S> {
+    fn new(sock: &'a S, buf: &'a mut [u8], block: u32, end: Option<u32>) -> Self {
         Self {
             sock,
             buf,
             block,
+            end,
             missing_chunk: None,
         }
     }
 }
 
 #[async_trait]
-impl<'a> Operation<Vec<u32>> for SendBlockComplete<'a> {
+impl<'a, S: Datagram> Operation<Vec<u32>> for SendBlockComplete<'a, S> {
     async fn poll(&mut self) -> std::io::Result<()> {
-        self.sock.send(&Message::BlockComplete(self.block)).await
+        let msg = match self.end {
+            Some(size) => Message::StreamEnd {
+                block: self.block,
+                size,
+            },
+            None => Message::BlockComplete(self.block),
+        };
+        self.sock.send(&msg).await
     }
 
     async fn resolve(&mut self) -> std::io::Result<Vec<u32>> {
         loop {
-            match self.sock.recv(&mut self.buf).await? {
+            match self.sock.recv(self.buf).await? {
                 Message::BlockCompleteAck(block) if block == self.block => return Ok(vec![]),
                 Message::BlockMissingChunk {
                     block,
@@ -0,0 +1,324 @@
+use std::fs::{canonicalize, read_dir};
+use std::io::{self, Cursor, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::info;
+use tokio::io::{stdout, AsyncWrite};
+use tokio::time::{sleep, Duration};
+
+use crate::file_transfer::accept::{check_offer, Accept};
+use crate::file_transfer::forward::{
+    accept_forward, accept_forward_udp, accept_tunnel, check_target, AllowForward,
+};
+use crate::file_transfer::mailbox::{accept_deposit, Mailbox};
+use crate::file_transfer::receive::{
+    read_request, receive_request, receive_stream_request, RECV_BUF_SIZE,
+};
+use crate::file_transfer::relay::accept_relay;
+use crate::file_transfer::{send, send_stream, Conflict, Message};
+use crate::mesh::Mesh;
+use crate::tun::Tun;
+use crate::{perform, Datagram, Operation};
+
+/// 读取超时时间
+const READ_TIMEOUT: u64 = 5;
+
+/// 打洞被动方处理对方请求的方式
+#[derive(Clone)]
+pub struct Service {
+    /// 接收文件的保存目录，`None` 表示不接收文件
+    pub receive: Option<PathBuf>,
+    /// 同名文件处理方式
+    pub conflict: Conflict,
+    /// 对外提供的目录，`None` 表示不提供
+    pub serve: Option<PathBuf>,
+    /// 允许对方转发 TCP 连接和 UDP 数据报的目标地址，为空时不允许转发
+    pub forward: Vec<AllowForward>,
+    /// 对方可以连接的 TUN 设备
+    pub tun: Option<Arc<Tun>>,
+    /// 为其他网络成员中继时所在的网络，`None` 表示不中继
+    pub relay: Option<Arc<Mesh>>,
+    /// 为不在线的 peer 暂存文件的目录，`None` 表示不暂存
+    pub mailbox: Option<Arc<Mailbox>>,
+    /// 决定是否接收对方发送的文件，`None` 表示全部接收
+    pub accept: Option<Arc<dyn Accept>>,
+}
+
+/// 处理对方的一个请求：接收文件，获取目录列表、文件，转发 TCP 连接、UDP 数据报，连接 TUN 设备，
+/// 中继到其他网络成员，或者为不在线的 peer 暂存文件
+pub async fn respond<S: Datagram + 'static>(sock: S, service: &Service) -> crate::Result<()> {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+
+    let msg = tokio::select! {
+        msg = read_command(&sock, &mut buf) => {
+            msg?
+        }
+        _ = sleep(Duration::from_secs(READ_TIMEOUT)) => {
+            Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!())?
+        }
+    };
+
+    match msg {
+        Message::Forward { host, port } => {
+            return match check_target(&service.forward, &host, port) {
+                Ok(()) => accept_forward(sock, &mut buf, host, port).await,
+                Err(reason) => deny(&sock, &mut buf, reason).await,
+            };
+        }
+        Message::ForwardUdp { host, port } => {
+            return match check_target(&service.forward, &host, port) {
+                Ok(()) => accept_forward_udp(sock, &mut buf, host, port).await,
+                Err(reason) => deny(&sock, &mut buf, reason).await,
+            };
+        }
+        Message::Tunnel => {
+            return match &service.tun {
+                Some(tun) => accept_tunnel(sock, &mut buf, tun).await,
+                None => deny(&sock, &mut buf, "tunnel is disabled".to_string()).await,
+            };
+        }
+        Message::Relay { to } => {
+            return match &service.relay {
+                Some(mesh) => accept_relay(sock, &mut buf, to, mesh).await,
+                None => deny(&sock, &mut buf, "relay is disabled".to_string()).await,
+            };
+        }
+        Message::Deposit { to, token } => {
+            return match &service.mailbox {
+                Some(mailbox) => accept_deposit(sock, &mut buf, to, token, mailbox, service).await,
+                None => deny(&sock, &mut buf, "mailbox is disabled".to_string()).await,
+            };
+        }
+        _ => {}
+    }
+
+    match (msg, &service.receive, &service.serve) {
+        (msg @ (Message::Request(_) | Message::StreamRequest { .. }), Some(dir), _) => {
+            if !accepted(&sock, &mut buf, &msg, service).await? {
+                return Ok(());
+            }
+            receive_request(&sock, &mut buf, msg, dir.clone(), service.conflict).await
+        }
+        (Message::Request(_) | Message::StreamRequest { .. }, None, _) => {
+            deny(&sock, &mut buf, "receiving is disabled".to_string()).await
+        }
+        (msg, _, Some(dir)) => serve(sock, buf, msg, dir).await,
+        (_, _, None) => deny(&sock, &mut buf, "serving is disabled".to_string()).await,
+    }
+}
+
+/// 接收对方发送的一个文件或数据流，写入 `writer`。接收前由 `service.accept` 决定是否接收
+pub async fn respond_stream<S, W>(sock: S, writer: W, service: &Service) -> crate::Result<()>
+where
+    S: Datagram,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let req = tokio::select! {
+        req = read_request(&sock, &mut buf) => {
+            req?
+        }
+        _ = sleep(Duration::from_secs(READ_TIMEOUT)) => {
+            Err(io::Error::from(ErrorKind::TimedOut)).map_err(err!())?
+        }
+    };
+    if !accepted(&sock, &mut buf, &req, service).await? {
+        return Ok(());
+    }
+    receive_stream_request(&sock, &mut buf, req, writer).await
+}
+
+/// 由 `service.accept` 决定是否接收发送请求 `req`，拒绝时已通知对方
+pub(crate) async fn accepted<S: Datagram>(
+    sock: &S,
+    buf: &mut [u8],
+    req: &Message,
+    service: &Service,
+) -> crate::Result<bool> {
+    match service.accept {
+        Some(ref accept) => check_offer(sock, buf, req, accept.as_ref()).await,
+        None => Ok(true),
+    }
+}
+
+/// 处理 List 或 Fetch 请求，只能访问目录 `dir` 中的文件
+async fn serve<S: Datagram>(
+    sock: S,
+    mut buf: Vec<u8>,
+    msg: Message,
+    dir: &Path,
+) -> crate::Result<()> {
+    match msg {
+        Message::List { path } => {
+            info!("list {}", path);
+            match list_dir(dir, &path) {
+                Ok(v) => send_stream(sock, Cursor::new(v), path, Conflict::Ask, "").await,
+                Err(e) => deny(&sock, &mut buf, e).await,
+            }
+        }
+        Message::Fetch { path } => {
+            info!("fetch {}", path);
+            match resolve_path(dir, &path) {
+                Ok(v) if v.is_file() => send(sock, &v, Conflict::Ask, "").await,
+                Ok(_) => deny(&sock, &mut buf, format!("{} is not a file", path)).await,
+                Err(e) => deny(&sock, &mut buf, e).await,
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// 获取服务端目录列表，写到标准输出
+pub async fn list<S: Datagram>(sock: S, path: String) -> crate::Result<()> {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let req = command(&sock, &mut buf, Message::List { path }).await?;
+    receive_stream_request(&sock, &mut buf, req, stdout()).await
+}
+
+/// 获取服务端文件，保存到目录 `dir`。`dir` 为 `None` 时写到标准输出
+pub async fn fetch<S: Datagram>(
+    sock: S,
+    path: String,
+    dir: Option<PathBuf>,
+    conflict: Conflict,
+) -> crate::Result<()> {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let req = command(&sock, &mut buf, Message::Fetch { path }).await?;
+    match dir {
+        Some(dir) => receive_request(&sock, &mut buf, req, dir, conflict).await,
+        None => receive_stream_request(&sock, &mut buf, req, stdout()).await,
+    }
+}
+
+/// 发送 List 或 Fetch 请求，返回服务端的发送请求
+async fn command<S: Datagram>(sock: &S, buf: &mut [u8], msg: Message) -> crate::Result<Message> {
+    let mut op = SendCommand { sock, buf, msg };
+    match perform(&mut op).await.map_err(err!())? {
+        Message::Denied(reason) => {
+            sock.send(&Message::DeniedAck).await.map_err(err!())?;
+            Err(io::Error::new(ErrorKind::PermissionDenied, reason)).map_err(err!("denied"))
+        }
+        req => Ok(req),
+    }
+}
+
+/// 读取请求：Request、StreamRequest、List、Fetch、Forward、ForwardUdp、Tunnel、Relay 或 Deposit
+async fn read_command<S: Datagram>(sock: &S, buf: &mut [u8]) -> crate::Result<Message> {
+    loop {
+        let msg = sock.recv(buf).await.map_err(err!())?;
+        if let Message::Request(_)
+        | Message::StreamRequest { .. }
+        | Message::List { .. }
+        | Message::Fetch { .. }
+        | Message::Forward { .. }
+        | Message::ForwardUdp { .. }
+        | Message::Tunnel
+        | Message::Relay { .. }
+        | Message::Deposit { .. } = msg
+        {
+            return Ok(msg);
+        }
+    }
+}
+
+/// 拒绝请求
+pub(crate) async fn deny<S: Datagram>(
+    sock: &S,
+    buf: &mut [u8],
+    reason: String,
+) -> crate::Result<()> {
+    info!("deny: {}", reason);
+    let mut op = SendDenied { sock, buf, reason };
+    perform(&mut op).await.map_err(err!())
+}
+
+/// 把请求路径解析为 `dir` 中的路径，不允许访问 `dir` 以外的文件
+fn resolve_path(dir: &Path, path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+    if !relative
+        .components()
+        .all(|v| matches!(v, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!("invalid path {}", path));
+    }
+
+    let root = canonicalize(dir).map_err(|e| e.to_string())?;
+    let full = canonicalize(root.join(relative)).map_err(|_| format!("{} not found", path))?;
+    if full.starts_with(&root) {
+        Ok(full)
+    } else {
+        Err(format!("invalid path {}", path))
+    }
+}
+
+/// 目录列表，每行一项：目录以 `/` 结尾，文件后跟 tab 和文件大小
+fn list_dir(dir: &Path, path: &str) -> Result<Vec<u8>, String> {
+    let full = resolve_path(dir, path)?;
+    let mut entries = Vec::new();
+    for entry in read_dir(&full).map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let metadata = match entry.metadata() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        if metadata.is_dir() {
+            entries.push(format!("{}/\n", name));
+        } else {
+            entries.push(format!("{}\t{}\n", name, metadata.len()));
+        }
+    }
+    entries.sort();
+    Ok(entries.concat().into_bytes())
+}
+
+/// 发送 List 或 Fetch 请求
+struct SendCommand<'a, S> {
+    sock: &'a S,
+    buf: &'a mut [u8],
+    msg: Message,
+}
+
+#[async_trait]
+impl<'a, S: Datagram> Operation<Message> for SendCommand<'a, S> {
+    async fn poll(&mut self) -> io::Result<()> {
+        self.sock.send(&self.msg).await
+    }
+
+    async fn resolve(&mut self) -> io::Result<Message> {
+        loop {
+            let msg = self.sock.recv(self.buf).await?;
+            if let Message::Request(_) | Message::StreamRequest { .. } | Message::Denied(_) = msg {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+/// 发送拒绝消息
+struct SendDenied<'a, S> {
+    sock: &'a S,
+    buf: &'a mut [u8],
+    reason: String,
+}
+
+#[async_trait]
+impl<'a, S: Datagram> Operation<()> for SendDenied<'a, S> {
+    async fn poll(&mut self) -> io::Result<()> {
+        self.sock.send(&Message::Denied(self.reason.clone())).await
+    }
+
+    async fn resolve(&mut self) -> io::Result<()> {
+        loop {
+            if let Message::DeniedAck = self.sock.recv(self.buf).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn result(&mut self) -> Option<()> {
+        Some(())
+    }
+}
@@ -5,8 +5,21 @@ pub use socket::*;
 
 #[macro_use]
 mod error;
+pub mod admin;
+pub mod cluster;
+pub mod config;
+pub mod daemon;
 pub mod file_transfer;
+pub mod mesh;
 mod message;
+pub mod mux;
 mod operation;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod rate_limit;
+pub mod registry;
 mod socket;
+pub mod stream;
+pub mod transport;
+pub mod tun;
 pub mod util;
@@ -0,0 +1,298 @@
+//! 多个 peer 组成的网络
+//!
+//! 同一网络中的 peer 从外网服务器获取成员列表，和其他成员打洞建立会话。
+//! 每个会话上有一个控制通道，定期交换各自直连的成员，据此计算路由表：
+//! 直连的成员直接访问，其他成员通过和它直连、且愿意中继的成员访问。
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bincode::{DefaultOptions, Options};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::file_transfer::relay;
+use crate::transport::{Channel, Transport};
+use crate::{Datagram, Decode, Encode};
+
+/// 超过此时间没有出现在外网服务器的成员列表中，认为已离开网络
+pub const MEMBER_TTL: Duration = Duration::from_secs(30);
+
+/// 交换直连成员的间隔
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 超过此时间没有收到控制消息，认为会话已断开
+const SESSION_DEAD_DURATION: Duration = Duration::from_secs(30);
+
+const RECV_BUF_SIZE: usize = 65536;
+
+/// 控制通道消息
+#[derive(Serialize, Deserialize, Debug)]
+enum Message {
+    /// 本端 id、直连的成员，以及是否为其他成员中继
+    Neighbors {
+        id: Vec<u8>,
+        neighbors: Vec<Vec<u8>>,
+        relay: bool,
+    },
+}
+
+impl Encode for Message {
+    fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+}
+
+impl Decode for Message {
+    fn decode(data: &[u8]) -> Option<Self> {
+        DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(data)
+            .ok()
+    }
+}
+
+/// 到一个成员的路由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    /// 已直连
+    Direct,
+    /// 通过和它直连的中继成员访问
+    Via(Vec<u8>),
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Direct => write!(f, "direct"),
+            Self::Via(id) => write!(f, "via {}", String::from_utf8_lossy(id)),
+        }
+    }
+}
+
+/// 和一个成员的会话
+struct Session {
+    transport: Arc<Transport>,
+    /// 对方直连的成员
+    neighbors: Vec<Vec<u8>>,
+    /// 对方是否为其他成员中继
+    relay: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// 成员，以及最近一次出现在成员列表中的时间
+    members: HashMap<Vec<u8>, Instant>,
+    sessions: HashMap<Vec<u8>, Session>,
+    /// 上次计算的路由表，用来记录变化
+    routes: BTreeMap<Vec<u8>, Option<Route>>,
+}
+
+/// 成员视图和路由表
+pub struct Mesh {
+    id: Vec<u8>,
+    /// 是否为其他成员中继
+    relay: bool,
+    state: Mutex<State>,
+}
+
+impl Mesh {
+    pub fn new(id: Vec<u8>, relay: bool) -> Self {
+        Self {
+            id,
+            relay,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// 更新外网服务器返回的成员列表，列表可能只是一部分
+    pub fn update_members(&self, ids: Vec<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        for id in ids {
+            if id != self.id {
+                state.members.insert(id, now);
+            }
+        }
+        state
+            .members
+            .retain(|_, seen| now.duration_since(*seen) < MEMBER_TTL);
+        self.update_routes(&mut state);
+    }
+
+    /// 当前的成员，不包括自己
+    pub fn members(&self) -> Vec<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state.members.keys().cloned().collect()
+    }
+
+    /// 是否已和成员直连
+    pub fn is_connected(&self, id: &[u8]) -> bool {
+        self.state.lock().unwrap().sessions.contains_key(id)
+    }
+
+    /// 和成员的会话
+    pub fn session(&self, id: &[u8]) -> Option<Arc<Transport>> {
+        let state = self.state.lock().unwrap();
+        state.sessions.get(id).map(|v| Arc::clone(&v.transport))
+    }
+
+    /// 到成员的路由，不可达时返回 `None`
+    pub fn route(&self, id: &[u8]) -> Option<Route> {
+        route(&self.state.lock().unwrap(), id)
+    }
+
+    /// 打开到成员的通道，没有直连时通过中继成员转发
+    pub async fn open(&self, id: &[u8]) -> crate::Result<Channel> {
+        let unreachable = || {
+            let e = io::Error::new(io::ErrorKind::NotConnected, "unreachable");
+            Err(e).map_err(err!("{}", String::from_utf8_lossy(id)))
+        };
+        match self.route(id) {
+            Some(Route::Direct) => match self.session(id) {
+                Some(v) => v.open().await,
+                None => unreachable(),
+            },
+            Some(Route::Via(relay_id)) => match self.session(&relay_id) {
+                Some(v) => {
+                    let channel = v.open().await?;
+                    relay(&channel, id.to_vec()).await?;
+                    Ok(channel)
+                }
+                None => unreachable(),
+            },
+            None => unreachable(),
+        }
+    }
+
+    /// 路由表，按 id 排序
+    pub fn routes(&self) -> Vec<(Vec<u8>, Option<Route>)> {
+        let state = self.state.lock().unwrap();
+        state.routes.clone().into_iter().collect()
+    }
+
+    /// 在控制通道上交换直连成员，直到会话断开。`transport` 是打洞建立的会话
+    pub async fn run_session(
+        self: Arc<Self>,
+        transport: Arc<Transport>,
+        control: Channel,
+    ) -> crate::Result<()> {
+        let mut peer_id = None;
+        let result = self
+            .exchange_neighbors(&transport, &control, &mut peer_id)
+            .await;
+
+        if let Some(id) = peer_id {
+            let mut state = self.state.lock().unwrap();
+            // 可能已被新的会话替换
+            if matches!(state.sessions.get(&id), Some(v) if Arc::ptr_eq(&v.transport, &transport)) {
+                info!("mesh session with {} closed", String::from_utf8_lossy(&id));
+                state.sessions.remove(&id);
+                self.update_routes(&mut state);
+            }
+        }
+        result
+    }
+
+    async fn exchange_neighbors(
+        &self,
+        transport: &Arc<Transport>,
+        control: &Channel,
+        peer_id: &mut Option<Vec<u8>>,
+    ) -> crate::Result<()> {
+        let mut buf = vec![0u8; RECV_BUF_SIZE];
+        let mut last_recv = Instant::now();
+        control.send(&self.neighbors()).await.map_err(err!())?;
+        loop {
+            tokio::select! {
+                recv = control.recv(&mut buf) => {
+                    let Message::Neighbors { id, neighbors, relay } = recv.map_err(err!())?;
+                    last_recv = Instant::now();
+                    let mut state = self.state.lock().unwrap();
+                    if peer_id.is_none() {
+                        info!("mesh session with {} established", String::from_utf8_lossy(&id));
+                        let transport = Arc::clone(transport);
+                        let session = Session { transport, neighbors: Vec::new(), relay };
+                        state.sessions.insert(id.clone(), session);
+                        *peer_id = Some(id.clone());
+                    }
+                    if let Some(session) = state.sessions.get_mut(&id) {
+                        if Arc::ptr_eq(&session.transport, transport) {
+                            session.neighbors = neighbors;
+                            session.relay = relay;
+                        }
+                    }
+                    self.update_routes(&mut state);
+                }
+                _ = sleep(GOSSIP_INTERVAL) => {
+                    if last_recv.elapsed() >= SESSION_DEAD_DURATION {
+                        Err(io::Error::from(io::ErrorKind::TimedOut)).map_err(err!("mesh peer is unreachable"))?;
+                    }
+                    control.send(&self.neighbors()).await.map_err(err!())?;
+                }
+            }
+        }
+    }
+
+    /// 本端的控制消息
+    fn neighbors(&self) -> Message {
+        let state = self.state.lock().unwrap();
+        Message::Neighbors {
+            id: self.id.clone(),
+            neighbors: state.sessions.keys().cloned().collect(),
+            relay: self.relay,
+        }
+    }
+
+    /// 重新计算路由表，记录变化
+    fn update_routes(&self, state: &mut State) {
+        let mut routes = BTreeMap::new();
+        for id in state.members.keys().chain(state.sessions.keys()) {
+            routes.insert(id.clone(), route(state, id));
+        }
+        for (id, route) in &routes {
+            if state.routes.get(id) != Some(route) {
+                let id = String::from_utf8_lossy(id);
+                match route {
+                    Some(route) => info!("route to {}: {}", id, route),
+                    None => info!("route to {}: unreachable", id),
+                }
+            }
+        }
+        for id in state.routes.keys() {
+            if !routes.contains_key(id) {
+                info!("{} left the mesh", String::from_utf8_lossy(id));
+            }
+        }
+        state.routes = routes;
+    }
+}
+
+impl Debug for Mesh {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Mesh({})", String::from_utf8_lossy(&self.id))
+    }
+}
+
+/// 计算到成员的路由，间接路由选择 id 最小的中继成员
+fn route(state: &State, id: &[u8]) -> Option<Route> {
+    if state.sessions.contains_key(id) {
+        return Some(Route::Direct);
+    }
+    state
+        .sessions
+        .iter()
+        .filter(|(_, v)| v.relay && v.neighbors.iter().any(|v| v == id))
+        .map(|(k, _)| k)
+        .min()
+        .map(|v| Route::Via(v.clone()))
+}
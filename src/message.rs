@@ -1,3 +1,5 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
 use std::net::SocketAddr;
 
 use serde::{Deserialize, Serialize};
@@ -13,25 +15,40 @@ pub enum Message {
     Address(SocketAddr),
 
     /// peer 向外网服务器注册, 其他 peer 可通过 id 连接此 peer
-    Register { id: Vec<u8> },
+    Register { namespace: Namespace, id: Vec<u8> },
 
-    /// 注册确认
-    RegisterAck,
+    /// 注册确认，`ttl` 为注册信息的有效期（秒），peer 需在有效期内重新注册
+    RegisterAck { ttl: u64 },
 
     /// peer 向外网服务器查询另一个 peer 的外网地址
-    Lookup { peer_id: Vec<u8> },
+    Lookup {
+        namespace: Namespace,
+        peer_id: Vec<u8>,
+    },
 
-    /// 外网服务器回复 peer 查询结果
-    Peer { addr: Option<SocketAddr> },
+    /// 外网服务器回复 peer 查询结果，`fingerprint` 为对方 QUIC 证书指纹。
+    /// `addr` 为空时 `reason` 说明原因：未注册，或者没有响应打洞请求
+    Peer {
+        addr: Option<SocketAddr>,
+        fingerprint: Option<Vec<u8>>,
+        reason: Option<String>,
+    },
 
-    /// 外网服务器通知 peer 有其他 peer 想要获取其外网地址
+    /// 外网服务器通知 peer 有其他 peer 想要获取其外网地址。
+    /// 一个 socket 注册了多个 id 时，`namespace` 和 `id` 指明被查询的是哪一个
     Request {
         peer_addr: SocketAddr, // 发起查询的 peer 的外网地址
+        namespace: Vec<u8>,    // 被查询的 peer 所在的命名空间
+        id: Vec<u8>,           // 被查询的 peer
+        nonce: u64,            // 只发送给被查询的 peer，Response 中原样返回
     },
 
-    /// peer 通知外网服务器使用当前 socket 的地址作为其外网地址
+    /// peer 通知外网服务器使用当前 socket 的地址作为其外网地址。
+    /// 外网服务器只接受 `nonce` 和 Request 相同的 Response
     Response {
-        peer_addr: SocketAddr, // 发起查询的 peer 的外网地址
+        peer_addr: SocketAddr,        // 发起查询的 peer 的外网地址
+        nonce: u64,                   // Request 中的 nonce
+        fingerprint: Option<Vec<u8>>, // 使用 QUIC 时的证书指纹，转交给发起查询的 peer
     },
 
     /// Response 确认
@@ -42,6 +59,149 @@ pub enum Message {
 
     /// Hello 确认
     HelloAck,
+
+    /// peer 加入网络 `network`，同时以 `id` 注册。需定期发送以保持成员身份
+    Join {
+        namespace: Namespace,
+        network: Vec<u8>,
+        id: Vec<u8>,
+    },
+
+    /// 外网服务器回复 Join，网络中的部分成员，成员较多时分成多个消息发送
+    Members(Vec<Vec<u8>>),
+
+    /// 外网服务器之间同步 peer 的注册，`network` 不为空时表示 peer 加入了该网络
+    Sync {
+        namespace: Vec<u8>,
+        id: Vec<u8>,
+        addr: SocketAddr,
+        network: Option<Vec<u8>>,
+    },
+
+    /// 请求 peer 注册所在的外网服务器向 `addr` 转发 Request，peer 只能收到该服务器发送的数据
+    ClusterRequest {
+        peer_addr: SocketAddr, // 发起查询的 peer 的外网地址
+        namespace: Vec<u8>,    // 被查询的 peer 所在的命名空间
+        id: Vec<u8>,           // 被查询的 peer
+        addr: SocketAddr,      // 被查询的 peer 的外网地址
+    },
+
+    /// 请求发起查询的 peer 所在的外网服务器向 `to` 转发 Peer
+    ClusterPeer {
+        to: SocketAddr,
+        addr: SocketAddr,
+        fingerprint: Option<Vec<u8>>,
+    },
+
+    /// peer 退出时注销。外网服务器之间转发，注销注册在发送方服务器上的 peer
+    Unregister { namespace: Vec<u8>, id: Vec<u8> },
+
+    /// 外网服务器不能处理收到的消息，peer 收到后立即失败而不必等待超时
+    Error { code: ErrorCode, reason: String },
+
+    /// peer 订阅另一个 peer 的上线通知，需在有效期内重新订阅
+    Subscribe {
+        namespace: Namespace,
+        peer_id: Vec<u8>,
+    },
+
+    /// 订阅确认，`ttl` 为订阅的有效期（秒）
+    SubscribeAck { ttl: u64 },
+
+    /// 外网服务器通知订阅者被订阅的 peer 已注册，通知后订阅结束
+    Online { peer_id: Vec<u8> },
+
+    /// 外网服务器之间的消息。`msg` 为编码后的 Sync、ClusterRequest、ClusterPeer 或 Unregister，
+    /// `mac` 为使用集群密钥对 `time`（UNIX 时间戳，秒）和 `msg` 计算的 HMAC-SHA256
+    Cluster {
+        time: u64,
+        msg: Vec<u8>,
+        mac: Vec<u8>,
+    },
+}
+
+/// id 所在的命名空间（租户）和凭证，不同命名空间中的 id 互不冲突。
+/// 名称为空表示默认命名空间，不需要凭证
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Namespace {
+    pub name: Vec<u8>,
+    pub token: Vec<u8>,
+}
+
+/// 消息会被写入日志，不输出凭证
+impl Debug for Namespace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Namespace")
+            .field("name", &String::from_utf8_lossy(&self.name))
+            .finish_non_exhaustive()
+    }
+}
+
+/// id、网络名称、命名空间名称和凭证的最大长度
+pub const MAX_ID_LEN: usize = 64;
+
+/// 外网服务器回复的错误码
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// 消息不能发送到此地址
+    Unsupported,
+    /// id 或网络名称为空或超过 `MAX_ID_LEN`
+    InvalidId,
+    /// 只有集群中的服务器可以发送此消息，或者命名空间的凭证不正确
+    Unauthorized,
+    /// 注册表或命名空间已满
+    RegistryFull,
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Unsupported => "unsupported",
+            Self::InvalidId => "invalid id",
+            Self::Unauthorized => "unauthorized",
+            Self::RegistryFull => "registry full",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 外网服务器回复的错误，作为 `io::Error` 的内部错误返回，可以通过 `downcast_ref` 取得
+#[derive(Debug, Clone)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server error: {}: {}", self.code, self.reason)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl ServerError {
+    /// 在错误链中查找外网服务器回复的错误
+    pub fn find<'a>(mut e: &'a (dyn std::error::Error + 'static)) -> Option<&'a ServerError> {
+        loop {
+            if let Some(v) = e.downcast_ref::<ServerError>() {
+                return Some(v);
+            }
+            // io::Error 的 source 跳过了内部错误
+            if let Some(v) = e.downcast_ref::<io::Error>().and_then(|v| v.get_ref()) {
+                if let Some(v) = v.downcast_ref::<ServerError>() {
+                    return Some(v);
+                }
+            }
+            e = e.source()?;
+        }
+    }
+}
+
+impl From<ServerError> for io::Error {
+    fn from(e: ServerError) -> Self {
+        io::Error::other(e)
+    }
 }
 
 /// 附加到消息结尾，防止把来自其它地址的非 Message 数据当作 Message 处理
@@ -0,0 +1,377 @@
+//! 在一个打洞后的 `Socket` 上复用多个数据报通道
+//!
+//! 每个数据报前加 1 字节标记和 4 字节的通道 id。双方都可以打开通道，打洞发起方使用奇数 id，
+//! 另一方使用偶数 id，互不冲突。收到对方未见过的 id 时创建新通道，通过 `accept` 获取。
+//! id 0 用于保活，发起方定时发送，另一方收到后回复。
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error};
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Instant};
+
+use crate::{Datagram, Socket};
+
+/// 数据报标记，区分打洞时残留的 `Message`
+const TAG: u8 = 0xa5;
+
+/// 数据报头部大小：标记 + 通道 id
+const HEAD_SIZE: usize = 5;
+
+/// 每个通道缓存的数据报个数，超过后丢弃，和 UDP 一样由上层重传
+const CHANNEL_CAPACITY: usize = 4096;
+
+/// 数据报最大长度
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// 保活通道 id
+const KEEPALIVE_ID: u32 = 0;
+
+/// 保活间隔
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 乱序打开的对方通道最多记录这么多个，超过时认为最早缺少的 id 已丢失
+const MAX_OUT_OF_ORDER: usize = 1024;
+
+/// 复用会话
+pub struct Session {
+    inner: Arc<Inner>,
+    accept: tokio::sync::Mutex<UnboundedReceiver<Channel>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct Inner {
+    sock: Socket,
+    /// 下一个本端打开的通道 id
+    next_id: AtomicU32,
+    streams: Mutex<Streams>,
+    /// 最近一次收到数据报的时间
+    last_recv: Mutex<Instant>,
+}
+
+struct Streams {
+    /// 已打开的通道
+    open: HashMap<u32, Sender<Vec<u8>>>,
+    /// 对方打开过的通道，已关闭的通道丢弃迟到的数据报，不再重新打开
+    seen: Seen,
+}
+
+/// 对方打开过的通道 id。对方按顺序分配 id，小于 `next` 的 id 都已打开过，
+/// 只有乱序到达时才在 `above` 中记录大于 `next` 的 id，占用的内存不随打开的通道数增长
+struct Seen {
+    next: u32,
+    above: BTreeSet<u32>,
+}
+
+impl Seen {
+    /// `first` 为对方的第一个通道 id
+    fn new(first: u32) -> Self {
+        Self {
+            next: first,
+            above: BTreeSet::new(),
+        }
+    }
+
+    fn contains(&self, id: u32) -> bool {
+        id < self.next || self.above.contains(&id)
+    }
+
+    fn insert(&mut self, id: u32) {
+        if id != self.next {
+            self.above.insert(id);
+            if self.above.len() <= MAX_OUT_OF_ORDER {
+                return;
+            }
+            self.next = self.above.pop_first().unwrap();
+        }
+        self.next = self.next.wrapping_add(2);
+        while self.above.remove(&self.next) {
+            self.next = self.next.wrapping_add(2);
+        }
+    }
+}
+
+impl Session {
+    /// `initiator` 表示本端是否是打洞发起方，双方必须不同
+    pub fn new(sock: Socket, initiator: bool) -> Self {
+        let inner = Arc::new(Inner {
+            sock,
+            next_id: AtomicU32::new(if initiator { 1 } else { 2 }),
+            streams: Mutex::new(Streams {
+                open: HashMap::new(),
+                seen: Seen::new(if initiator { 2 } else { 1 }),
+            }),
+            last_recv: Mutex::new(Instant::now()),
+        });
+        let (tx, rx) = unbounded_channel();
+        let mut tasks = vec![tokio::spawn(dispatch(Arc::clone(&inner), tx))];
+        if initiator {
+            tasks.push(tokio::spawn(keepalive(Arc::clone(&inner))));
+        }
+        Self {
+            inner,
+            accept: tokio::sync::Mutex::new(rx),
+            tasks,
+        }
+    }
+
+    /// 打开通道
+    pub fn open(&self) -> Channel {
+        let id = self.inner.next_id.fetch_add(2, Ordering::Relaxed);
+        debug!("open channel {}", id);
+        let mut streams = self.inner.streams.lock().unwrap();
+        Channel::new(id, &self.inner, &mut streams)
+    }
+
+    /// 等待对方打开的通道。会话出错时返回 `None`
+    pub async fn accept(&self) -> Option<Channel> {
+        self.accept.lock().await.recv().await
+    }
+
+    /// 已打开的通道个数
+    pub fn channel_count(&self) -> usize {
+        self.inner.streams.lock().unwrap().open.len()
+    }
+
+    /// 距离最近一次收到数据报的时间
+    pub fn idle_time(&self) -> Duration {
+        self.inner.last_recv.lock().unwrap().elapsed()
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.inner.sock
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        // 结束所有通道的接收
+        self.inner.streams.lock().unwrap().open.clear();
+    }
+}
+
+/// 定时发送保活数据报
+async fn keepalive(inner: Arc<Inner>) {
+    let mut interval = interval(KEEPALIVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = inner.send(KEEPALIVE_ID, &[]).await {
+            debug!("keepalive: {}", e);
+        }
+    }
+}
+
+impl Inner {
+    /// 在通道 `id` 上发送数据报
+    async fn send(&self, id: u32, data: &[u8]) -> io::Result<()> {
+        let mut v = Vec::with_capacity(HEAD_SIZE + data.len());
+        v.push(TAG);
+        v.extend_from_slice(&id.to_be_bytes());
+        v.extend_from_slice(data);
+        self.sock.as_ref().send(&v).await?;
+        Ok(())
+    }
+}
+
+/// 读取数据报，分发到通道
+async fn dispatch(inner: Arc<Inner>, accept: UnboundedSender<Channel>) {
+    let local_parity = inner.next_id.load(Ordering::Relaxed) % 2;
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let n = match inner.sock.as_ref().recv(&mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}:{}: {}", file!(), line!(), e);
+                return;
+            }
+        };
+        if n < HEAD_SIZE || buf[0] != TAG {
+            continue;
+        }
+        let id = u32::from_be_bytes(buf[1..HEAD_SIZE].try_into().unwrap());
+        *inner.last_recv.lock().unwrap() = Instant::now();
+        if id == KEEPALIVE_ID {
+            // 本端不是发起方时回复保活数据报
+            if local_parity == 0 {
+                let _ = inner.send(KEEPALIVE_ID, &[]).await;
+            }
+            continue;
+        }
+        let data = buf[HEAD_SIZE..n].to_vec();
+
+        let (tx, channel) = {
+            let mut streams = inner.streams.lock().unwrap();
+            match streams.open.get(&id) {
+                Some(tx) => (tx.clone(), None),
+                None if id % 2 != local_parity && !streams.seen.contains(id) => {
+                    // 对方打开的新通道
+                    debug!("accept channel {}", id);
+                    streams.seen.insert(id);
+                    let channel = Channel::new(id, &inner, &mut streams);
+                    (streams.open[&id].clone(), Some(channel))
+                }
+                None => continue,
+            }
+        };
+        if let Some(channel) = channel {
+            if accept.send(channel).is_err() {
+                continue;
+            }
+        }
+        // 通道缓存已满时丢弃
+        let _ = tx.try_send(data);
+    }
+}
+
+/// 复用通道
+pub struct Channel {
+    id: u32,
+    inner: Arc<Inner>,
+    rx: tokio::sync::Mutex<Receiver<Vec<u8>>>,
+}
+
+impl Channel {
+    fn new(id: u32, inner: &Arc<Inner>, streams: &mut Streams) -> Self {
+        let (tx, rx) = channel(CHANNEL_CAPACITY);
+        streams.open.insert(id, tx);
+        Self {
+            id,
+            inner: Arc::clone(inner),
+            rx: tokio::sync::Mutex::new(rx),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        debug!("close channel {}", self.id);
+        let mut streams = self.inner.streams.lock().unwrap();
+        streams.open.remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl Datagram for Channel {
+    fn peer(&self) -> String {
+        format!("{}#{}", self.inner.sock.peer(), self.id)
+    }
+
+    async fn send_bytes(&self, data: &[u8]) -> io::Result<()> {
+        self.inner.send(self.id, data).await
+    }
+
+    async fn recv_bytes(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.rx.lock().await.recv().await {
+            Some(data) => {
+                // 和 UDP 一样，缓冲区不够时截断
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok(n)
+            }
+            None => Err(io::Error::from(ErrorKind::BrokenPipe)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[test]
+    fn test_seen() {
+        let mut seen = Seen::new(2);
+        assert!(!seen.contains(2));
+        seen.insert(2);
+        assert!(seen.contains(2) && !seen.contains(4));
+
+        // 乱序打开
+        seen.insert(6);
+        seen.insert(8);
+        assert!(!seen.contains(4) && seen.contains(6) && seen.contains(8));
+        assert_eq!(seen.above.len(), 2);
+        seen.insert(4);
+        assert_eq!((seen.next, seen.above.len()), (10, 0));
+        assert!(seen.contains(8) && !seen.contains(10));
+
+        // 缺少的 id 太多时放弃等待
+        let mut seen = Seen::new(1);
+        for i in 0..=MAX_OUT_OF_ORDER as u32 {
+            seen.insert(5 + i * 2);
+        }
+        assert!(seen.above.is_empty());
+        assert!(seen.contains(1) && seen.contains(3));
+        assert_eq!(seen.next, 5 + (MAX_OUT_OF_ORDER as u32 + 1) * 2);
+    }
+
+    async fn pair() -> (Session, Session) {
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut a = Socket::new(local).await.unwrap();
+        let mut b = Socket::new(local).await.unwrap();
+        let (addr_a, addr_b) = (
+            a.as_ref().local_addr().unwrap(),
+            b.as_ref().local_addr().unwrap(),
+        );
+        a.connect(addr_b).await.unwrap();
+        b.connect(addr_a).await.unwrap();
+        (Session::new(a, true), Session::new(b, false))
+    }
+
+    #[tokio::test]
+    async fn test_channels() {
+        let (a, b) = pair().await;
+        let mut buf = [0u8; 16];
+        let (c1, c2) = (a.open(), a.open());
+        assert_eq!((c1.id(), c2.id()), (1, 3));
+        c2.send_bytes(b"two").await.unwrap();
+        c1.send_bytes(b"one").await.unwrap();
+
+        let d2 = b.accept().await.unwrap();
+        let n = d2.recv_bytes(&mut buf).await.unwrap();
+        assert_eq!((d2.id(), &buf[..n]), (3, &b"two"[..]));
+        let d1 = b.accept().await.unwrap();
+        assert_eq!(d1.id(), 1);
+
+        // 另一方打开的通道使用偶数 id
+        let e = b.open();
+        assert_eq!(e.id(), 2);
+        e.send_bytes(b"even").await.unwrap();
+        let f = a.accept().await.unwrap();
+        let n = f.recv_bytes(&mut buf).await.unwrap();
+        assert_eq!((f.id(), &buf[..n]), (2, &b"even"[..]));
+        d1.send_bytes(b"back").await.unwrap();
+        let n = c1.recv_bytes(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"back");
+
+        // 关闭后迟到的数据报不会重新打开通道
+        drop(d1);
+        assert_eq!(b.channel_count(), 2);
+        c1.send_bytes(b"late").await.unwrap();
+        let c3 = a.open();
+        c3.send_bytes(b"three").await.unwrap();
+        let d3 = b.accept().await.unwrap();
+        assert_eq!(d3.id(), 5);
+        assert!(timeout(Duration::from_millis(100), b.accept())
+            .await
+            .is_err());
+        assert_eq!(b.channel_count(), 3);
+    }
+}
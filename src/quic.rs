@@ -0,0 +1,347 @@
+//! 在打洞后的 `Socket` 上使用 QUIC
+//!
+//! 注册端作为服务端，使用自签名证书，证书指纹通过外网服务器转交给发起端；发起端作为客户端，
+//! 只信任该指纹对应的证书。每个传输使用一个双向流，流上的数据报带 4 字节长度前缀。
+
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{debug, info};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use quinn::rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use quinn::rustls::{self, CertificateError, DigitallySignedStruct, SignatureScheme};
+use quinn::{Endpoint, EndpointConfig, RecvStream, SendStream, TokioRuntime, TransportConfig};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout, Duration};
+
+use crate::{Datagram, Socket};
+
+/// 证书中的名称，客户端不校验
+const SERVER_NAME: &str = "udp-hole-punching";
+
+/// 应用层协议
+const ALPN: &[u8] = b"uhp/1";
+
+/// 长度前缀大小
+const HEAD_SIZE: usize = 4;
+
+/// 连接空闲超时时间
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 保活间隔
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 等待对端连接的时间，对端没有使用 QUIC 或者打洞失败时不会连接
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 自签名证书
+pub struct Identity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl Identity {
+    pub fn generate() -> crate::Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+            .map_err(err!("generate certificate"))?;
+        Ok(Self {
+            cert: certified.cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()),
+        })
+    }
+
+    /// 证书的 SHA-256 指纹
+    pub fn fingerprint(&self) -> Vec<u8> {
+        Sha256::digest(&self.cert).to_vec()
+    }
+}
+
+/// QUIC 连接
+pub struct Connection {
+    endpoint: Endpoint,
+    conn: quinn::Connection,
+    /// 未关闭的流个数
+    count: Arc<AtomicUsize>,
+}
+
+impl Connection {
+    /// 作为服务端，等待对端连接
+    pub async fn server(sock: Socket, identity: &Identity) -> crate::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let mut crypto = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(err!())?
+            .with_no_client_auth()
+            .with_single_cert(vec![identity.cert.clone()], identity.key.clone_key().into())
+            .map_err(err!())?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicServerConfig::try_from(crypto).map_err(err!())?;
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport_config(Arc::new(transport_config()));
+
+        let peer = sock.peer();
+        let endpoint = endpoint(sock, Some(config))?;
+        let accept = async {
+            match endpoint.accept().await {
+                Some(incoming) => incoming.await.map_err(io::Error::from),
+                None => Err(io::Error::from(ErrorKind::BrokenPipe)),
+            }
+        };
+        let conn = match timeout(ACCEPT_TIMEOUT, accept).await {
+            Ok(v) => v.map_err(err!("accept quic from {}", peer))?,
+            Err(_) => Err(io::Error::from(ErrorKind::TimedOut))
+                .map_err(err!("accept quic from {}", peer))?,
+        };
+        info!("quic connection from {}", peer);
+        Ok(Self::new(endpoint, conn))
+    }
+
+    /// 作为客户端连接对端，`fingerprint` 为对端证书指纹
+    pub async fn client(sock: Socket, fingerprint: Vec<u8>) -> crate::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let verifier = PinnedCert {
+            fingerprint,
+            provider: Arc::clone(&provider),
+        };
+        let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(err!())?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicClientConfig::try_from(crypto).map_err(err!())?;
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        config.transport_config(Arc::new(transport_config()));
+
+        let peer = sock.peer();
+        let addr = sock.connected_addr().unwrap();
+        let endpoint = endpoint(sock, None)?;
+        let conn = endpoint
+            .connect_with(config, addr, SERVER_NAME)
+            .map_err(err!())?
+            .await
+            .map_err(err!("connect quic to {}", peer))?;
+        info!("quic connection to {}", peer);
+        Ok(Self::new(endpoint, conn))
+    }
+
+    fn new(endpoint: Endpoint, conn: quinn::Connection) -> Self {
+        Self {
+            endpoint,
+            conn,
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// 打开通道
+    pub async fn open(&self) -> crate::Result<Channel> {
+        let (send, recv) = self.conn.open_bi().await.map_err(err!())?;
+        Ok(self.channel(send, recv))
+    }
+
+    /// 等待对方打开的通道。连接关闭时返回 `None`
+    pub async fn accept(&self) -> Option<Channel> {
+        match self.conn.accept_bi().await {
+            Ok((send, recv)) => Some(self.channel(send, recv)),
+            Err(e) => {
+                debug!("quic connection closed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// 未关闭的通道个数
+    pub fn channel_count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// 等待所有通道的数据发送完成后关闭连接
+    pub async fn close(&self) {
+        while self.channel_count() > 0 && self.conn.close_reason().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.conn.close(0u32.into(), b"");
+        self.endpoint.wait_idle().await;
+    }
+
+    fn channel(&self, send: SendStream, recv: RecvStream) -> Channel {
+        let peer = format!("{}#{}", self.conn.remote_address(), send.id());
+        let (tx, rx) = unbounded_channel();
+        self.count.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(write(send, rx, Arc::clone(&self.count)));
+        Channel {
+            peer,
+            tx,
+            reader: tokio::sync::Mutex::new(Reader {
+                stream: recv,
+                buf: Vec::new(),
+            }),
+        }
+    }
+}
+
+fn transport_config() -> TransportConfig {
+    let mut config = TransportConfig::default();
+    config
+        .max_idle_timeout(Some(IDLE_TIMEOUT.try_into().unwrap()))
+        .keep_alive_interval(Some(KEEPALIVE_INTERVAL));
+    config
+}
+
+fn endpoint(sock: Socket, config: Option<quinn::ServerConfig>) -> crate::Result<Endpoint> {
+    let sock = sock.into_inner().into_std().map_err(err!())?;
+    Endpoint::new(
+        EndpointConfig::default(),
+        config,
+        sock,
+        Arc::new(TokioRuntime),
+    )
+    .map_err(err!())
+}
+
+/// 把通道的数据报写入流，通道关闭后结束流，等待对方收到所有数据
+async fn write(mut send: SendStream, mut rx: UnboundedReceiver<Vec<u8>>, count: Arc<AtomicUsize>) {
+    while let Some(frame) = rx.recv().await {
+        if let Err(e) = send.write_all(&frame).await {
+            debug!("write stream {}: {}", send.id(), e);
+            break;
+        }
+    }
+    let _ = send.finish();
+    let _ = send.stopped().await;
+    count.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// QUIC 流上的数据报通道
+///
+/// 发送由后台任务完成，接收时缓存不完整的数据报，都可以安全地取消
+pub struct Channel {
+    peer: String,
+    tx: UnboundedSender<Vec<u8>>,
+    reader: tokio::sync::Mutex<Reader>,
+}
+
+struct Reader {
+    stream: RecvStream,
+    /// 已读取但不完整的数据报
+    buf: Vec<u8>,
+}
+
+impl Reader {
+    /// 取出一个完整的数据报，和 UDP 一样，缓冲区不够时截断
+    fn frame(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.buf.len() < HEAD_SIZE {
+            return None;
+        }
+        let len = u32::from_be_bytes(self.buf[..HEAD_SIZE].try_into().unwrap()) as usize;
+        if self.buf.len() < HEAD_SIZE + len {
+            return None;
+        }
+        let n = len.min(buf.len());
+        buf[..n].copy_from_slice(&self.buf[HEAD_SIZE..HEAD_SIZE + n]);
+        self.buf.drain(..HEAD_SIZE + len);
+        Some(n)
+    }
+}
+
+#[async_trait]
+impl Datagram for Channel {
+    fn peer(&self) -> String {
+        self.peer.clone()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    async fn send_bytes(&self, data: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(HEAD_SIZE + data.len());
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(data);
+        self.tx
+            .send(frame)
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))
+    }
+
+    async fn recv_bytes(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().await;
+        let mut chunk = vec![0u8; 8192];
+        loop {
+            if let Some(n) = reader.frame(buf) {
+                return Ok(n);
+            }
+            match reader.stream.read(&mut chunk).await? {
+                Some(n) => reader.buf.extend_from_slice(&chunk[..n]),
+                None => return Err(io::Error::from(ErrorKind::BrokenPipe)),
+            }
+        }
+    }
+}
+
+/// 只信任指定指纹的证书
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
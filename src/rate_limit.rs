@@ -0,0 +1,159 @@
+//! 令牌桶限速
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// 记录已满时，两次清除之间的最小间隔，防止每个新的 key 都遍历所有记录
+const GC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 令牌桶，以固定速率补充令牌，最多积累 `burst` 个
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// 创建装满令牌的桶
+    pub fn new(burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// 补充令牌后取出一个，没有令牌时返回 `false`
+    pub fn take(&mut self, rate: f64, burst: f64) -> bool {
+        self.refill(rate, burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 是否已装满，装满的桶和新建的桶相同，可以丢弃
+    fn is_full(&mut self, rate: f64, burst: f64) -> bool {
+        self.refill(rate, burst);
+        self.tokens >= burst
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+    }
+}
+
+/// 按 key 分别限速，每个 key 每秒 `rate` 次，最多连续 `burst` 次。
+/// 最多记录 `capacity` 个 key，超过时拒绝新的 key，防止占用过多内存
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    buckets: HashMap<K, TokenBucket>,
+    rate: f64,
+    burst: f64,
+    capacity: usize,
+    /// 最近一次清除的时间
+    gc_at: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(rate: f64, burst: f64, capacity: usize) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            rate,
+            burst: burst.max(1.0),
+            capacity,
+            gc_at: Instant::now(),
+        }
+    }
+
+    /// 修改限速，已有的桶按新的限速补充令牌
+    pub fn set_rate(&mut self, rate: f64, burst: f64) {
+        self.rate = rate;
+        self.burst = burst.max(1.0);
+    }
+
+    /// 记录一次访问，超过限速时返回 `false`
+    pub fn check(&mut self, key: K) -> bool {
+        if self.buckets.len() >= self.capacity && !self.buckets.contains_key(&key) {
+            if self.gc_at.elapsed() >= GC_INTERVAL {
+                self.gc();
+            }
+            if self.buckets.len() >= self.capacity {
+                return false;
+            }
+        }
+        let burst = self.burst;
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(burst))
+            .take(self.rate, burst)
+    }
+
+    /// 丢弃已装满的桶
+    pub fn gc(&mut self) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, v| !v.is_full(rate, burst));
+        self.gc_at = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let mut bucket = TokenBucket::new(2.0);
+        assert!(bucket.take(0.001, 2.0));
+        assert!(bucket.take(0.001, 2.0));
+        assert!(!bucket.take(0.001, 2.0));
+        assert!(!bucket.is_full(0.001, 2.0));
+        sleep(Duration::from_millis(5));
+        assert!(bucket.is_full(1000.0, 2.0));
+        assert!(bucket.take(0.001, 2.0));
+    }
+
+    #[test]
+    fn test_rate() {
+        let mut limiter = RateLimiter::new(0.001, 0.5, 10);
+        // burst 至少为 1
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        assert!(limiter.check("b"));
+
+        limiter.set_rate(0.001, 3.0);
+        assert!(limiter.check("c"));
+        assert!(limiter.check("c"));
+        assert!(limiter.check("c"));
+        assert!(!limiter.check("c"));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut limiter = RateLimiter::new(1000.0, 1.0, 2);
+        assert!(limiter.check(1));
+        assert!(limiter.check(2));
+        sleep(Duration::from_millis(5));
+        // 桶已装满，但距上次清除不到 GC_INTERVAL，不遍历
+        assert!(!limiter.check(3));
+        assert!(limiter.check(1));
+
+        limiter.gc_at -= GC_INTERVAL;
+        assert!(limiter.check(3));
+        assert!(limiter.buckets.contains_key(&3));
+        assert!(!limiter.buckets.contains_key(&2));
+
+        // 定时清除不受间隔限制
+        sleep(Duration::from_millis(5));
+        assert!(!limiter.check(4));
+        limiter.gc();
+        assert!(limiter.check(4));
+    }
+}
@@ -0,0 +1,475 @@
+//! 外网服务器记录的 peer 注册信息
+
+use std::collections::HashMap;
+use std::fs::{rename, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::mesh::MEMBER_TTL;
+
+/// 网络中的成员，以及最近一次 Join 的时间
+pub type Network = HashMap<Vec<u8>, Instant>;
+
+/// peer 的外网地址，以及最近一次注册的时间
+#[derive(Debug, Clone, Copy)]
+pub struct Registration {
+    pub addr: SocketAddr,
+    pub seen: Instant,
+    /// peer 注册所在的其他外网服务器，`None` 表示注册在本服务器
+    pub node: Option<SocketAddr>,
+    /// peer 注册时连接的本服务器地址，向 peer 发送消息时使用该地址。不保存在快照中
+    pub local: Option<SocketAddr>,
+    /// 最近一次转发的打洞请求没有响应，重新注册或响应打洞请求前查询直接失败
+    pub suspect: bool,
+}
+
+/// 注册表已满时，两次清除之间的最小间隔，防止频繁遍历
+const GC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 注册被拒绝的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Full {
+    /// 注册表已满
+    Registry,
+    /// 命名空间已达到配额
+    Namespace,
+}
+
+/// 一个命名空间中注册的 peer 和网络
+#[derive(Default)]
+struct Space {
+    peers: HashMap<Vec<u8>, Registration>,
+    networks: HashMap<Vec<u8>, Network>,
+}
+
+impl Space {
+    fn is_empty(&self) -> bool {
+        self.peers.is_empty() && self.networks.is_empty()
+    }
+}
+
+/// 注册的 peer 和网络，按命名空间分开
+pub struct Registry {
+    spaces: HashMap<Vec<u8>, Space>,
+    /// 所有命名空间中注册的 peer 个数
+    len: usize,
+    /// 注册信息有效期，超过此时间没有重新注册的 peer 会被清除
+    ttl: Duration,
+    /// 最多注册的 peer 个数，网络成员也必须注册，因此同时限制了网络的大小
+    max_peers: usize,
+    /// 命名空间 => 该命名空间最多注册的 peer 个数
+    quotas: HashMap<Vec<u8>, usize>,
+    gc_at: Instant,
+    /// 上次保存后是否有变化
+    dirty: bool,
+}
+
+impl Registry {
+    pub fn new(ttl: Duration, max_peers: usize) -> Self {
+        Self {
+            spaces: HashMap::new(),
+            len: 0,
+            ttl,
+            max_peers,
+            quotas: HashMap::new(),
+            gc_at: Instant::now(),
+            dirty: false,
+        }
+    }
+
+    /// 注册的 peer 个数
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 注册的 peer 及其命名空间，包括尚未清除的过期 peer
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>, &Registration)> {
+        self.spaces
+            .iter()
+            .flat_map(|(ns, space)| space.peers.iter().map(move |(id, v)| (ns, id, v)))
+    }
+
+    /// 网络个数
+    pub fn networks(&self) -> usize {
+        self.spaces.values().map(|v| v.networks.len()).sum()
+    }
+
+    /// 命名空间中注册的 peer 个数
+    fn count(&self, namespace: &[u8]) -> usize {
+        self.spaces.get(namespace).map_or(0, |v| v.peers.len())
+    }
+
+    /// 命名空间是否已达到配额
+    fn is_over_quota(&self, namespace: &[u8]) -> bool {
+        matches!(self.quotas.get(namespace), Some(&v) if self.count(namespace) >= v)
+    }
+
+    /// 在命名空间 `namespace` 中注册或更新 peer 的外网地址。`node` 为 peer 注册所在的其他外网服务器，
+    /// `local` 为注册在本服务器时 peer 连接的地址
+    pub fn register(
+        &mut self,
+        namespace: &[u8],
+        id: Vec<u8>,
+        addr: SocketAddr,
+        node: Option<SocketAddr>,
+        local: Option<SocketAddr>,
+    ) -> Result<(), Full> {
+        let exists = matches!(self.spaces.get(namespace), Some(v) if v.peers.contains_key(&id));
+        if !exists && (self.len >= self.max_peers || self.is_over_quota(namespace)) {
+            if self.gc_at.elapsed() >= GC_INTERVAL {
+                self.gc();
+            }
+            if self.len >= self.max_peers {
+                return Err(Full::Registry);
+            }
+            if self.is_over_quota(namespace) {
+                return Err(Full::Namespace);
+            }
+        }
+        let seen = Instant::now();
+        let suspect = false;
+        let registration = Registration {
+            addr,
+            seen,
+            node,
+            local,
+            suspect,
+        };
+        let space = self.spaces.entry(namespace.to_vec()).or_default();
+        if space.peers.insert(id, registration).is_none() {
+            self.len += 1;
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// 注册信息有效期
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// 修改注册信息有效期、最多注册的 peer 个数和各命名空间的配额，已注册的 peer 不受影响
+    pub fn set_limits(&mut self, ttl: Duration, max_peers: usize, quotas: HashMap<Vec<u8>, usize>) {
+        self.ttl = ttl;
+        self.max_peers = max_peers;
+        self.quotas = quotas;
+    }
+
+    /// 查询未过期的注册信息
+    pub fn lookup(&self, namespace: &[u8], id: &[u8]) -> Option<Registration> {
+        self.spaces
+            .get(namespace)
+            .and_then(|v| v.peers.get(id))
+            .filter(|v| v.seen.elapsed() <= self.ttl)
+            .copied()
+    }
+
+    /// 设置或清除 peer 的可疑标记
+    pub fn set_suspect(&mut self, namespace: &[u8], id: &[u8], suspect: bool) {
+        if let Some(v) = self
+            .spaces
+            .get_mut(namespace)
+            .and_then(|v| v.peers.get_mut(id))
+        {
+            v.suspect = suspect;
+        }
+    }
+
+    /// 注销 peer，同时退出所有网络
+    pub fn unregister(&mut self, namespace: &[u8], id: &[u8]) {
+        let Some(space) = self.spaces.get_mut(namespace) else {
+            return;
+        };
+        if space.peers.remove(id).is_some() {
+            space.networks.retain(|_, members| {
+                members.remove(id);
+                !members.is_empty()
+            });
+            if space.is_empty() {
+                self.spaces.remove(namespace);
+            }
+            self.len -= 1;
+            self.dirty = true;
+        }
+    }
+
+    /// 以 `id` 注册并加入命名空间 `namespace` 中的网络，返回网络中的成员
+    pub fn join(
+        &mut self,
+        namespace: &[u8],
+        network: Vec<u8>,
+        id: Vec<u8>,
+        addr: SocketAddr,
+        node: Option<SocketAddr>,
+        local: Option<SocketAddr>,
+    ) -> Result<&Network, Full> {
+        self.register(namespace, id.clone(), addr, node, local)?;
+        let now = Instant::now();
+        let space = self.spaces.get_mut(namespace).unwrap();
+        let members = space.networks.entry(network).or_default();
+        members.insert(id, now);
+        members.retain(|_, v| now.duration_since(*v) < MEMBER_TTL);
+        Ok(members)
+    }
+
+    /// 清除不活跃的 peer 和网络成员，有清除时才需要重新保存快照
+    pub fn gc(&mut self) {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        let mut changed = false;
+        let mut keep = |alive: bool| {
+            changed |= !alive;
+            alive
+        };
+        self.spaces.retain(|_, space| {
+            space
+                .peers
+                .retain(|_, v| keep(now.duration_since(v.seen) <= ttl));
+            space.networks.retain(|_, members| {
+                members.retain(|_, v| keep(now.duration_since(*v) < MEMBER_TTL));
+                !members.is_empty()
+            });
+            !space.is_empty()
+        });
+        self.len = self.spaces.values().map(|v| v.peers.len()).sum();
+        self.gc_at = now;
+        self.dirty |= changed;
+    }
+
+    /// 从快照文件加载，文件不存在或格式不对时返回空的注册表
+    pub fn load(path: &Path, ttl: Duration, max_peers: usize) -> crate::Result<Self> {
+        let file = match File::open(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::new(ttl, max_peers)),
+            Err(e) => Err(e).map_err(err!("cannot open {}", path.display()))?,
+        };
+        let snapshot: Snapshot = match bincode::deserialize_from(BufReader::new(file)) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("invalid registry {}, ignored: {}", path.display(), e);
+                return Ok(Self::new(ttl, max_peers));
+            }
+        };
+
+        let registry = snapshot.restore(ttl, max_peers);
+        info!("load {} peers from {}", registry.len(), path.display());
+        Ok(registry)
+    }
+
+    /// 有变化时保存快照。先写临时文件并落盘再改名，防止写到一半时退出或断电导致快照损坏
+    pub fn save(&mut self, path: &Path) -> crate::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let tmp = path.with_extension("tmp");
+        let file = File::create(&tmp).map_err(err!("cannot create {}", tmp.display()))?;
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, &Snapshot::new(self))
+            .map_err(io::Error::other)
+            .map_err(err!("cannot write {}", tmp.display()))?;
+        let file = writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .map_err(err!("cannot write {}", tmp.display()))?;
+        file.sync_all()
+            .map_err(err!("cannot write {}", tmp.display()))?;
+        rename(&tmp, path).map_err(err!("cannot rename to {}", path.display()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// 快照中的网络成员和 Join 时间
+type SnapshotMembers = Vec<(Vec<u8>, u64)>;
+
+/// 快照中的 peer：命名空间、id、外网地址、注册时间、所在的其他外网服务器
+type SnapshotPeer = (Vec<u8>, Vec<u8>, SocketAddr, u64, Option<SocketAddr>);
+
+/// 快照文件内容，时间保存为 UNIX 时间戳（秒）
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    peers: Vec<SnapshotPeer>,
+    /// 命名空间、网络名称和成员
+    networks: Vec<(Vec<u8>, Vec<u8>, SnapshotMembers)>,
+}
+
+impl Snapshot {
+    fn new(registry: &Registry) -> Self {
+        let (now, unix) = (Instant::now(), unix_now());
+        let timestamp = |v: Instant| unix.saturating_sub(now.duration_since(v).as_secs());
+        let mut peers = Vec::with_capacity(registry.len);
+        let mut networks = Vec::new();
+        for (ns, space) in &registry.spaces {
+            for (id, v) in &space.peers {
+                peers.push((ns.clone(), id.clone(), v.addr, timestamp(v.seen), v.node));
+            }
+            for (network, members) in &space.networks {
+                let members = members
+                    .iter()
+                    .map(|(k, v)| (k.clone(), timestamp(*v)))
+                    .collect();
+                networks.push((ns.clone(), network.clone(), members));
+            }
+        }
+        Self { peers, networks }
+    }
+
+    /// 恢复注册表，丢弃已过期的 peer 和网络成员，超过 `max_peers` 的 peer 也被丢弃
+    fn restore(self, ttl: Duration, max_peers: usize) -> Registry {
+        let (now, unix) = (Instant::now(), unix_now());
+        let age = |v: u64| Duration::from_secs(unix.saturating_sub(v));
+        let instant = |age: Duration| now.checked_sub(age).unwrap_or(now);
+        let mut registry = Registry::new(ttl, max_peers);
+        for (ns, id, addr, seen, node) in self.peers {
+            if age(seen) <= ttl && registry.len < max_peers {
+                let seen = instant(age(seen));
+                let (local, suspect) = (None, false);
+                let registration = Registration {
+                    addr,
+                    seen,
+                    node,
+                    local,
+                    suspect,
+                };
+                let space = registry.spaces.entry(ns).or_default();
+                if space.peers.insert(id, registration).is_none() {
+                    registry.len += 1;
+                }
+            }
+        }
+        for (ns, network, members) in self.networks {
+            let members: Network = members
+                .into_iter()
+                .filter(|(_, v)| age(*v) < MEMBER_TTL)
+                .map(|(k, v)| (k, instant(age(v))))
+                .collect();
+            if !members.is_empty() {
+                let space = registry.spaces.entry(ns).or_default();
+                space.networks.insert(network, members);
+            }
+        }
+        registry
+    }
+}
+
+/// 显示的 id，其他命名空间中的 id 前加上命名空间
+pub fn display_id(namespace: &[u8], id: &[u8]) -> String {
+    let id = String::from_utf8_lossy(id);
+    match namespace.is_empty() {
+        true => id.into_owned(),
+        false => format!("{}/{}", String::from_utf8_lossy(namespace), id),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{remove_file, write};
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    /// 把 peer 最近一次注册的时间提前 `age`
+    fn age(registry: &mut Registry, namespace: &[u8], id: &[u8], age: Duration) {
+        let v = registry.spaces.get_mut(namespace).unwrap();
+        let v = v.peers.get_mut(id).unwrap();
+        v.seen = v.seen.checked_sub(age).unwrap();
+    }
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new(TTL, 10);
+        registry
+            .register(b"", b"a".to_vec(), addr(1), None, Some(addr(100)))
+            .unwrap();
+        registry
+            .register(b"ns", b"b".to_vec(), addr(2), Some(addr(200)), None)
+            .unwrap();
+        registry
+            .join(b"ns", b"net".to_vec(), b"c".to_vec(), addr(3), None, None)
+            .unwrap();
+        registry.set_suspect(b"", b"a", true);
+        registry
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = std::env::temp_dir().join(format!("registry-{}", std::process::id()));
+        let mut registry = registry();
+        age(&mut registry, b"ns", b"b", Duration::from_secs(30));
+        registry.save(&path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let loaded = Registry::load(&path, TTL, 10).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded.networks(), 1);
+        // 本服务器地址和可疑标记不保存
+        let a = loaded.lookup(b"", b"a").unwrap();
+        assert_eq!(
+            (a.addr, a.node, a.local, a.suspect),
+            (addr(1), None, None, false)
+        );
+        let b = loaded.lookup(b"ns", b"b").unwrap();
+        assert_eq!((b.addr, b.node), (addr(2), Some(addr(200))));
+        assert!(b.seen.elapsed() >= Duration::from_secs(29));
+        assert!(loaded.lookup(b"", b"b").is_none());
+        let members = &loaded.spaces[&b"ns".to_vec()].networks[&b"net".to_vec()];
+        assert!(members.contains_key(&b"c".to_vec()));
+
+        // 过期的 peer 和超过上限的 peer 被丢弃
+        let loaded = Registry::load(&path, Duration::from_secs(20), 10).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.lookup(b"ns", b"b").is_none());
+        assert_eq!(Registry::load(&path, TTL, 1).unwrap().len(), 1);
+
+        write(&path, b"corrupted").unwrap();
+        assert!(Registry::load(&path, TTL, 10).unwrap().is_empty());
+        remove_file(&path).unwrap();
+        assert!(Registry::load(&path, TTL, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dirty() {
+        let path = std::env::temp_dir().join(format!("registry-dirty-{}", std::process::id()));
+        let mut registry = registry();
+        assert!(registry.dirty);
+        registry.save(&path).unwrap();
+        assert!(!registry.dirty);
+
+        // 没有清除任何 peer 时不需要重新保存
+        registry.gc();
+        assert!(!registry.dirty);
+        registry.set_suspect(b"", b"a", false);
+        assert!(!registry.dirty);
+
+        age(&mut registry, b"", b"a", TTL + Duration::from_secs(1));
+        registry.gc();
+        assert!(registry.dirty);
+        assert_eq!(registry.len(), 2);
+        registry.save(&path).unwrap();
+
+        registry.unregister(b"ns", b"c");
+        assert!(registry.dirty);
+        assert_eq!(registry.networks(), 0);
+        remove_file(&path).unwrap();
+    }
+}
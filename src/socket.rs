@@ -2,6 +2,7 @@ use std::fmt::Debug;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
+use async_trait::async_trait;
 use log::{debug, info};
 use tokio::net::UdpSocket;
 
@@ -15,6 +16,47 @@ pub trait Decode: Sized {
     fn decode(data: &[u8]) -> Option<Self>;
 }
 
+/// 已连接对端的数据报通道
+///
+/// 文件传输等功能基于此运行，可以是打洞后的 `Socket`，也可以是 `mux::Channel`
+#[async_trait]
+pub trait Datagram: Send + Sync {
+    /// 对端标识，用来记录日志
+    fn peer(&self) -> String;
+
+    /// 传输的数据是否加密
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    /// 发送一个数据报
+    async fn send_bytes(&self, data: &[u8]) -> io::Result<()>;
+
+    /// 接收一个数据报，返回数据长度
+    async fn recv_bytes(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    async fn send<M>(&self, msg: &M) -> io::Result<()>
+    where
+        M: Encode + Debug + Sync,
+    {
+        debug!("send {:?} to {}", msg, self.peer());
+        self.send_bytes(&msg.encode()).await
+    }
+
+    async fn recv<T>(&self, buf: &mut [u8]) -> io::Result<T>
+    where
+        T: Decode + Debug + Send,
+    {
+        loop {
+            let n = self.recv_bytes(buf).await?;
+            if let Some(msg) = T::decode(&buf[..n]) {
+                debug!("receive {:?} from {}", msg, self.peer());
+                return Ok(msg);
+            }
+        }
+    }
+}
+
 pub struct Socket {
     inner: UdpSocket,
     /// connect 地址，用来记录日志
@@ -51,6 +93,11 @@ impl Socket {
         self.connect
     }
 
+    /// 取出内部的 `UdpSocket`，交给其他协议使用
+    pub fn into_inner(self) -> UdpSocket {
+        self.inner
+    }
+
     pub async fn send(&self, msg: &(impl Encode + Debug)) -> io::Result<()> {
         debug_assert!(self.connect.is_some());
         debug!("send {:?} to {}", msg, self.connect.unwrap());
@@ -90,6 +137,25 @@ impl Socket {
     }
 }
 
+#[async_trait]
+impl Datagram for Socket {
+    fn peer(&self) -> String {
+        debug_assert!(self.connect.is_some());
+        self.connect.unwrap().to_string()
+    }
+
+    async fn send_bytes(&self, data: &[u8]) -> io::Result<()> {
+        debug_assert!(self.connect.is_some());
+        self.inner.send(data).await?;
+        Ok(())
+    }
+
+    async fn recv_bytes(&self, buf: &mut [u8]) -> io::Result<usize> {
+        debug_assert!(self.connect.is_some());
+        self.inner.recv(buf).await
+    }
+}
+
 impl AsRef<UdpSocket> for Socket {
     fn as_ref(&self) -> &UdpSocket {
         &self.inner
@@ -0,0 +1,800 @@
+//! 基于数据报通道的可靠有序字节流
+//!
+//! 数据分段发送，接收端累计确认并通告接收窗口。发送端按往返时间估计重传超时时间，
+//! 超时后只重传最早的未确认分段并加倍超时时间，连续超时次数超过限制时连接出错；
+//! 收到 3 个重复确认时快速重传。拥塞窗口按慢启动和拥塞避免增长，丢包时减小。
+//! `shutdown` 或 drop 时发送 Fin，对端读到 EOF。
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use tokio::time::{interval, Duration, Instant};
+
+use crate::Datagram;
+
+/// 分段数据大小，和文件传输的 chunk 大小一样按 IP 最小 MTU 计算
+const SEGMENT_SIZE: usize = 496;
+
+/// 接收缓冲区大小
+const RECV_BUF_SIZE: usize = 1024;
+
+/// 接收窗口，单位为分段
+const RECV_WINDOW: u32 = 1024;
+
+/// 发送缓冲区大小，包括未确认的数据
+const SEND_BUFFER: usize = 256 * 1024;
+
+/// 还没有测量往返时间时的重传超时时间
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// 重传超时时间下限
+const MIN_RTO: Duration = Duration::from_millis(200);
+
+/// 重传超时时间上限
+const MAX_RTO: Duration = Duration::from_secs(10);
+
+/// 最早的未确认分段连续超时的次数上限
+const RETRY_COUNT: u32 = 8;
+
+/// 收到此数量的重复确认时快速重传
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// 初始拥塞窗口，单位为分段
+const INITIAL_CWND: u32 = 10;
+
+/// 每次最多发送的新分段数量，其余的在下一轮发送
+const MAX_BURST: usize = 64;
+
+/// 检查重传的间隔
+const TICK_DURATION: Duration = Duration::from_millis(50);
+
+/// 超过此时间没有发送数据时发送 Ack 保活
+const KEEPALIVE_DURATION: Duration = Duration::from_secs(5);
+
+/// 超过此时间没有收到数据时认为连接已断开
+const DEAD_DURATION: Duration = Duration::from_secs(30);
+
+/// 分段头部，分段数据附加在头部之后
+#[derive(Serialize, Deserialize, Debug)]
+enum Segment {
+    /// 数据
+    Data { seq: u32 },
+
+    /// 发送端关闭，占用一个序号
+    Fin { seq: u32 },
+
+    /// 累计确认，`next` 之前的分段都已收到，`window` 为接收窗口
+    Ack { next: u32, window: u32 },
+}
+
+/// 可靠有序字节流
+///
+/// 后台任务负责收发和重传，drop 后会继续发送剩余数据直到对方确认
+pub struct Stream {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// 通知后台任务有数据要发送
+    notify: Notify,
+}
+
+/// 未确认的分段
+struct Pending {
+    /// `None` 表示 Fin
+    data: Option<Vec<u8>>,
+    sent_at: Instant,
+    /// 重传过的分段不用来测量往返时间
+    retransmitted: bool,
+}
+
+struct State {
+    /// 待分段的数据
+    send_buf: VecDeque<u8>,
+    /// 未确认的分段
+    unacked: BTreeMap<u32, Pending>,
+    /// 未确认的数据大小
+    unacked_size: usize,
+    /// 下一个分段序号
+    send_next: u32,
+    /// 第一个未确认的序号
+    send_una: u32,
+    /// 对方的接收窗口
+    peer_window: u32,
+    /// 拥塞窗口，单位为分段
+    cwnd: u32,
+    /// 拥塞避免阶段累计确认的分段数量，达到拥塞窗口时拥塞窗口加一
+    cwnd_acked: u32,
+    /// 慢启动阈值
+    ssthresh: u32,
+    /// 平滑往返时间，还没有测量时为 `None`
+    srtt: Option<Duration>,
+    /// 往返时间偏差
+    rttvar: Duration,
+    /// 重传超时时间
+    rto: Duration,
+    /// 重传计时器到期时间，没有未确认的分段时为 `None`
+    rto_at: Option<Instant>,
+    /// 最早的未确认分段连续超时的次数
+    timeouts: u32,
+    /// 重复确认次数
+    dup_acks: u32,
+    /// 丢包恢复中，此序号之前的分段都确认后结束
+    recover: Option<u32>,
+    /// 需要重传最早的未确认分段
+    retransmit: bool,
+    /// 需要发送 Fin
+    fin: bool,
+    /// Fin 已发送
+    fin_sent: bool,
+    write_waker: Option<Waker>,
+
+    /// 已按序接收的数据
+    read_buf: VecDeque<u8>,
+    /// 乱序到达的分段，`None` 表示 Fin
+    out_of_order: BTreeMap<u32, Option<Vec<u8>>>,
+    /// 下一个期望的序号
+    recv_next: u32,
+    /// 收到对方的 Fin
+    eof: bool,
+    /// 需要发送 Ack
+    ack: bool,
+    /// 最近一次通告的接收窗口
+    advertised_window: u32,
+    read_waker: Option<Waker>,
+
+    /// `Stream` 已 drop
+    dropped: bool,
+    error: Option<(ErrorKind, String)>,
+    last_send: Instant,
+    last_recv: Instant,
+}
+
+impl Stream {
+    /// 在已连接的数据报通道上建立字节流，双方各自调用，不需要握手
+    pub fn new<D: Datagram + 'static>(sock: D) -> Self {
+        let now = Instant::now();
+        let state = State {
+            send_buf: VecDeque::new(),
+            unacked: BTreeMap::new(),
+            unacked_size: 0,
+            send_next: 0,
+            send_una: 0,
+            peer_window: RECV_WINDOW,
+            cwnd: INITIAL_CWND,
+            cwnd_acked: 0,
+            ssthresh: RECV_WINDOW,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            rto_at: None,
+            timeouts: 0,
+            dup_acks: 0,
+            recover: None,
+            retransmit: false,
+            fin: false,
+            fin_sent: false,
+            write_waker: None,
+            read_buf: VecDeque::new(),
+            out_of_order: BTreeMap::new(),
+            recv_next: 0,
+            eof: false,
+            ack: false,
+            advertised_window: RECV_WINDOW,
+            read_waker: None,
+            dropped: false,
+            error: None,
+            last_send: now,
+            last_recv: now,
+        };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(state),
+            notify: Notify::new(),
+        });
+        tokio::spawn(drive(sock, Arc::clone(&shared)));
+        Self { shared }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.dropped = true;
+        state.fin = true;
+        state.read_buf.clear();
+        drop(state);
+        self.shared.notify.notify_one();
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.read_buf.is_empty() {
+            let n = state.read_buf.len().min(buf.remaining());
+            let (a, b) = state.read_buf.as_slices();
+            if n <= a.len() {
+                buf.put_slice(&a[..n]);
+            } else {
+                buf.put_slice(a);
+                buf.put_slice(&b[..n - a.len()]);
+            }
+            state.read_buf.drain(..n);
+
+            // 接收窗口变大较多时通知对方
+            if state.advertised_window < RECV_WINDOW / 2
+                && state.window() >= state.advertised_window + RECV_WINDOW / 4
+            {
+                state.ack = true;
+                drop(state);
+                self.shared.notify.notify_one();
+            }
+            Poll::Ready(Ok(()))
+        } else if state.eof {
+            Poll::Ready(Ok(()))
+        } else if let Some(e) = state.io_error() {
+            Poll::Ready(Err(e))
+        } else {
+            state.read_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(e) = state.io_error() {
+            return Poll::Ready(Err(e));
+        }
+        if state.fin {
+            return Poll::Ready(Err(io::Error::from(ErrorKind::BrokenPipe)));
+        }
+
+        let available = SEND_BUFFER.saturating_sub(state.send_buf.len() + state.unacked_size);
+        if available == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = available.min(buf.len());
+        state.send_buf.extend(&buf[..n]);
+        drop(state);
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.state.lock().unwrap().fin = true;
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// 后台任务，收发分段
+async fn drive<D: Datagram>(sock: D, shared: Arc<Shared>) {
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let mut tick = interval(TICK_DURATION);
+    loop {
+        let mut received = None;
+        tokio::select! {
+            recv = sock.recv_bytes(&mut buf) => match recv {
+                Ok(n) => received = Some(n),
+                Err(e) => {
+                    shared.state.lock().unwrap().fail(e.kind(), e.to_string());
+                    return;
+                }
+            },
+            _ = shared.notify.notified() => {}
+            _ = tick.tick() => {}
+        }
+
+        let output = {
+            let mut state = shared.state.lock().unwrap();
+            if let Some(n) = received {
+                state.on_segment(&buf[..n]);
+            }
+            state.output()
+        };
+        let (packets, more, done) = match output {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("stream with {} failed: {}", sock.peer(), e);
+                return;
+            }
+        };
+        for packet in packets {
+            if let Err(e) = sock.send_bytes(&packet).await {
+                shared.state.lock().unwrap().fail(e.kind(), e.to_string());
+                return;
+            }
+        }
+        if done {
+            debug!("stream with {} closed", sock.peer());
+            return;
+        }
+        // 还有分段可以发送，不等待下一次检查
+        if more {
+            shared.notify.notify_one();
+        }
+    }
+}
+
+impl State {
+    /// 当前接收窗口
+    fn window(&self) -> u32 {
+        let used = self.read_buf.len() / SEGMENT_SIZE + self.out_of_order.len();
+        RECV_WINDOW.saturating_sub(used as u32)
+    }
+
+    fn io_error(&self) -> Option<io::Error> {
+        self.error
+            .as_ref()
+            .map(|(kind, msg)| io::Error::new(*kind, msg.clone()))
+    }
+
+    fn fail(&mut self, kind: ErrorKind, msg: String) {
+        self.error = Some((kind, msg));
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// 处理收到的分段
+    fn on_segment(&mut self, mut data: &[u8]) {
+        let segment: Segment = match bincode::deserialize_from(&mut data) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        self.last_recv = Instant::now();
+
+        let (seq, payload) = match segment {
+            Segment::Data { seq } => (seq, Some(data.to_vec())),
+            Segment::Fin { seq } => (seq, None),
+            Segment::Ack { next, window } => {
+                self.on_ack(next, window);
+                return;
+            }
+        };
+
+        self.ack = true;
+        let in_window = match self.recv_next.checked_add(self.window()) {
+            Some(end) => (self.recv_next..end).contains(&seq),
+            None => seq >= self.recv_next,
+        };
+        if !in_window {
+            return;
+        }
+        self.out_of_order.entry(seq).or_insert(payload);
+        while let Some(payload) = self.out_of_order.remove(&self.recv_next) {
+            self.recv_next += 1;
+            match payload {
+                Some(v) if !self.dropped => self.read_buf.extend(v),
+                Some(_) => {}
+                None => self.eof = true,
+            }
+        }
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn on_ack(&mut self, next: u32, window: u32) {
+        let window_opened = window > self.peer_window;
+        self.peer_window = window;
+        if next < self.send_una || next > self.send_next {
+            return;
+        }
+        if next == self.send_una {
+            // 对方读取数据后通告更大的接收窗口，不算重复确认
+            if !self.unacked.is_empty() && !window_opened {
+                self.on_dup_ack();
+            }
+            return;
+        }
+
+        let now = Instant::now();
+        let remain = self.unacked.split_off(&next);
+        let acked = std::mem::replace(&mut self.unacked, remain);
+        for pending in acked.values() {
+            self.unacked_size -= pending.data.as_ref().map_or(0, |v| v.len());
+        }
+        // 确认的分段中有重传过的，最后一个分段可能在对方等待了一段时间，不测量往返时间
+        if acked.values().all(|v| !v.retransmitted) {
+            if let Some((_, pending)) = acked.iter().next_back() {
+                self.update_rtt(now - pending.sent_at);
+            }
+        }
+        self.send_una = next;
+        self.dup_acks = 0;
+        self.timeouts = 0;
+
+        match self.recover {
+            // 恢复点之前还有分段丢失，立即重传
+            Some(recover) if next < recover => self.retransmit = true,
+            Some(_) => {
+                self.recover = None;
+                self.cwnd = self.ssthresh;
+            }
+            None => self.grow_cwnd(acked.len() as u32),
+        }
+        self.rto_at = (!self.unacked.is_empty()).then(|| now + self.rto);
+
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn on_dup_ack(&mut self) {
+        self.dup_acks += 1;
+        if self.dup_acks == DUP_ACK_THRESHOLD && self.recover.is_none() {
+            self.on_loss();
+            self.cwnd = self.ssthresh;
+            self.retransmit = true;
+        }
+    }
+
+    /// 发现丢包，减小慢启动阈值，进入丢包恢复
+    fn on_loss(&mut self) {
+        self.ssthresh = (self.in_flight() / 2).max(2);
+        self.cwnd_acked = 0;
+        self.recover = Some(self.send_next);
+    }
+
+    /// 确认了 `acked` 个新分段，慢启动阶段每个确认的分段加一，拥塞避免阶段每个往返时间加一
+    fn grow_cwnd(&mut self, acked: u32) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked;
+        } else {
+            self.cwnd_acked += acked;
+            if self.cwnd_acked >= self.cwnd {
+                self.cwnd_acked -= self.cwnd;
+                self.cwnd += 1;
+            }
+        }
+        self.cwnd = self.cwnd.min(RECV_WINDOW);
+    }
+
+    /// 根据新测量的往返时间更新重传超时时间（RFC 6298）
+    fn update_rtt(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let diff = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + diff / 4;
+                srtt * 7 / 8 + rtt / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + (self.rttvar * 4).max(TICK_DURATION)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// 已发送未确认的分段数量
+    fn in_flight(&self) -> u32 {
+        self.send_next - self.send_una
+    }
+
+    /// 需要发送的分段、是否还有新分段等待发送，以及连接是否已结束
+    fn output(&mut self) -> io::Result<(Vec<Vec<u8>>, bool, bool)> {
+        if let Some(e) = self.io_error() {
+            return Err(e);
+        }
+        let now = Instant::now();
+        if now - self.last_recv > DEAD_DURATION {
+            self.fail(ErrorKind::TimedOut, "peer not responding".to_string());
+            return Err(io::Error::from(ErrorKind::TimedOut));
+        }
+
+        let mut packets = Vec::new();
+
+        // 重传超时，只重传最早的分段，超时时间加倍
+        if self.rto_at.is_some_and(|v| now >= v) {
+            self.timeouts += 1;
+            if self.timeouts > RETRY_COUNT {
+                self.fail(ErrorKind::TimedOut, "retransmission timed out".to_string());
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            self.on_loss();
+            self.cwnd = 1;
+            self.dup_acks = 0;
+            self.rto = (self.rto * 2).min(MAX_RTO);
+            self.retransmit = true;
+        }
+        if std::mem::take(&mut self.retransmit) {
+            if let Some((seq, pending)) = self.unacked.iter_mut().next() {
+                pending.retransmitted = true;
+                pending.sent_at = now;
+                packets.push(encode(*seq, pending.data.as_deref()));
+                self.rto_at = Some(now + self.rto);
+            }
+        }
+
+        // 发送窗口内的新分段，窗口为拥塞窗口和对方接收窗口中较小的一个。
+        // 对方接收窗口为 0 时等待对方读取后通告窗口，通告丢失时由保活的 Ack 通告。
+        // 收到重复确认时多发送新分段，拥塞窗口较小时也能触发快速重传（RFC 3042）
+        let limited = match self.recover {
+            None => self.dup_acks.min(DUP_ACK_THRESHOLD - 1),
+            Some(_) => 0,
+        };
+        let window = (self.cwnd + limited).min(self.peer_window);
+        let mut more = false;
+        while self.in_flight() < window && !self.fin_sent {
+            if packets.len() >= MAX_BURST {
+                more = true;
+                break;
+            }
+            let data = if !self.send_buf.is_empty() {
+                let n = self.send_buf.len().min(SEGMENT_SIZE);
+                Some(self.send_buf.drain(..n).collect::<Vec<u8>>())
+            } else if self.fin {
+                self.fin_sent = true;
+                None
+            } else {
+                break;
+            };
+
+            let seq = self.send_next;
+            self.send_next += 1;
+            packets.push(encode(seq, data.as_deref()));
+            self.unacked_size += data.as_ref().map_or(0, |v| v.len());
+            let pending = Pending {
+                data,
+                sent_at: now,
+                retransmitted: false,
+            };
+            self.unacked.insert(seq, pending);
+            self.rto_at.get_or_insert(now + self.rto);
+        }
+
+        if self.ack || now - self.last_send > KEEPALIVE_DURATION {
+            self.ack = false;
+            self.advertised_window = self.window();
+            let segment = Segment::Ack {
+                next: self.recv_next,
+                window: self.advertised_window,
+            };
+            packets.push(bincode::serialize(&segment).unwrap());
+        }
+        if !packets.is_empty() {
+            self.last_send = now;
+        }
+
+        let done = self.fin_sent && self.unacked.is_empty() && (self.eof || self.dropped);
+        Ok((packets, more, done))
+    }
+}
+
+/// 编码数据或 Fin 分段
+fn encode(seq: u32, data: Option<&[u8]>) -> Vec<u8> {
+    match data {
+        Some(data) => {
+            let mut v = bincode::serialize(&Segment::Data { seq }).unwrap();
+            v.extend_from_slice(data);
+            v
+        }
+        None => bincode::serialize(&Segment::Fin { seq }).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+
+    /// 内存中的数据报通道，按 `fault` 丢弃或调换发送的数据报
+    struct Link {
+        tx: UnboundedSender<Vec<u8>>,
+        rx: tokio::sync::Mutex<UnboundedReceiver<Vec<u8>>>,
+        fault: Fault,
+        sent: AtomicUsize,
+        held: Mutex<Option<Vec<u8>>>,
+    }
+
+    #[derive(Clone, Copy)]
+    enum Fault {
+        None,
+        /// 丢弃每 n 个数据报中的一个
+        Drop(usize),
+        /// 每两个数据报调换顺序
+        Swap,
+    }
+
+    fn pair(a: Fault, b: Fault) -> (Link, Link) {
+        let (tx1, rx1) = unbounded_channel();
+        let (tx2, rx2) = unbounded_channel();
+        let link = |tx, rx, fault| Link {
+            tx,
+            rx: tokio::sync::Mutex::new(rx),
+            fault,
+            sent: AtomicUsize::new(0),
+            held: Mutex::new(None),
+        };
+        (link(tx1, rx2, a), link(tx2, rx1, b))
+    }
+
+    #[async_trait]
+    impl Datagram for Link {
+        fn peer(&self) -> String {
+            "link".to_string()
+        }
+
+        async fn send_bytes(&self, data: &[u8]) -> io::Result<()> {
+            let n = self.sent.fetch_add(1, Ordering::Relaxed) + 1;
+            let _ = match self.fault {
+                Fault::None => self.tx.send(data.to_vec()),
+                Fault::Drop(v) if n.is_multiple_of(v) => Ok(()),
+                Fault::Drop(_) => self.tx.send(data.to_vec()),
+                Fault::Swap => {
+                    let mut held = self.held.lock().unwrap();
+                    match held.take() {
+                        Some(v) => self.tx.send(data.to_vec()).and(self.tx.send(v)),
+                        None => {
+                            *held = Some(data.to_vec());
+                            Ok(())
+                        }
+                    }
+                }
+            };
+            Ok(())
+        }
+
+        async fn recv_bytes(&self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.rx.lock().await.recv().await {
+                Some(v) => {
+                    buf[..v.len()].copy_from_slice(&v);
+                    Ok(v.len())
+                }
+                None => Err(io::Error::from(ErrorKind::ConnectionReset)),
+            }
+        }
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|v| (v % 251) as u8).collect()
+    }
+
+    /// 从 `a` 发送 `len` 字节到 `b`，`b` 读到 EOF 后返回收到的数据
+    async fn transfer(a: Fault, b: Fault, len: usize) {
+        let (a, b) = pair(a, b);
+        let (mut a, mut b) = (Stream::new(a), Stream::new(b));
+        let sent = data(len);
+        let expected = sent.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&sent).await.unwrap();
+            a.shutdown().await.unwrap();
+            a
+        });
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(20), b.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.len(), expected.len());
+        assert!(received == expected);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        transfer(Fault::None, Fault::None, 300_000).await;
+    }
+
+    #[tokio::test]
+    async fn test_loss() {
+        transfer(Fault::Drop(7), Fault::None, 200_000).await;
+        // Ack 丢失
+        transfer(Fault::None, Fault::Drop(4), 200_000).await;
+    }
+
+    #[tokio::test]
+    async fn test_reorder() {
+        transfer(Fault::Swap, Fault::Swap, 200_000).await;
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (a, b) = pair(Fault::None, Fault::None);
+        let (mut a, mut b) = (Stream::new(a), Stream::new(b));
+
+        a.write_all(b"hello").await.unwrap();
+        a.shutdown().await.unwrap();
+        let err = a.write_all(b"more").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+        // 半关闭，对方仍然可以发送
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        b.write_all(b"bye").await.unwrap();
+        drop(b);
+
+        let mut received = Vec::new();
+        a.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[tokio::test]
+    async fn test_window_exhaustion() {
+        let (a, b) = pair(Fault::None, Fault::None);
+        let (mut a, mut b) = (Stream::new(a), Stream::new(b));
+        let len = 2 * 1024 * 1024;
+        let sent = data(len);
+        let expected = sent.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&sent).await.unwrap();
+            a.shutdown().await.unwrap();
+            a
+        });
+
+        // 对方不读取时，接收窗口和发送缓冲区用完后写入阻塞
+        sleep(Duration::from_millis(500)).await;
+        assert!(!writer.is_finished());
+        {
+            let state = b.shared.state.lock().unwrap();
+            assert_eq!(state.window(), 0);
+        }
+
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(20), b.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == expected);
+        writer.await.unwrap();
+    }
+
+    #[test]
+    fn test_rto() {
+        let (a, _b) = pair(Fault::None, Fault::None);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stream = runtime.block_on(async { Stream::new(a) });
+        let mut state = stream.shared.state.lock().unwrap();
+        assert_eq!(state.rto, INITIAL_RTO);
+
+        state.update_rtt(Duration::from_millis(400));
+        assert_eq!(state.srtt, Some(Duration::from_millis(400)));
+        assert_eq!(state.rto, Duration::from_millis(1200));
+
+        // 往返时间稳定后接近下限
+        for _ in 0..50 {
+            state.update_rtt(Duration::from_millis(10));
+        }
+        assert_eq!(state.rto, MIN_RTO);
+
+        for _ in 0..50 {
+            state.update_rtt(Duration::from_secs(30));
+        }
+        assert_eq!(state.rto, MAX_RTO);
+    }
+}
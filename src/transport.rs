@@ -0,0 +1,150 @@
+//! 打洞后的会话，在 `Socket` 上复用通道，或者使用 QUIC
+
+use std::io;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+#[cfg(feature = "quic")]
+use crate::quic;
+#[cfg(feature = "quic")]
+pub use crate::quic::Identity;
+use crate::{mux, Datagram, Socket};
+
+/// 没有启用 `quic` feature 时无法创建
+#[cfg(not(feature = "quic"))]
+pub enum Identity {}
+
+#[cfg(not(feature = "quic"))]
+impl Identity {
+    pub fn generate() -> crate::Result<Self> {
+        Err(io::Error::other("quic feature is not enabled")).map_err(err!())
+    }
+
+    pub fn fingerprint(&self) -> Vec<u8> {
+        match *self {}
+    }
+}
+
+/// 会话
+pub enum Transport {
+    Mux(mux::Session),
+    #[cfg(feature = "quic")]
+    Quic(quic::Connection),
+}
+
+impl Transport {
+    /// 作为注册端建立会话，`identity` 不为空时使用 QUIC
+    pub async fn server(sock: Socket, identity: Option<&Identity>) -> crate::Result<Self> {
+        match identity {
+            None => Ok(Self::Mux(mux::Session::new(sock, false))),
+            #[cfg(feature = "quic")]
+            Some(identity) => Ok(Self::Quic(quic::Connection::server(sock, identity).await?)),
+            #[cfg(not(feature = "quic"))]
+            Some(identity) => match *identity {},
+        }
+    }
+
+    /// 作为发起端建立会话，`fingerprint` 为对端的证书指纹，不为空时使用 QUIC
+    pub async fn client(sock: Socket, fingerprint: Option<Vec<u8>>) -> crate::Result<Self> {
+        match fingerprint {
+            None => Ok(Self::Mux(mux::Session::new(sock, true))),
+            #[cfg(feature = "quic")]
+            Some(fingerprint) => Ok(Self::Quic(
+                quic::Connection::client(sock, fingerprint).await?,
+            )),
+            #[cfg(not(feature = "quic"))]
+            Some(_) => Err(io::Error::other(
+                "peer requires quic, but quic feature is not enabled",
+            ))
+            .map_err(err!()),
+        }
+    }
+
+    /// 打开通道
+    pub async fn open(&self) -> crate::Result<Channel> {
+        match self {
+            Self::Mux(v) => Ok(Channel::Mux(v.open())),
+            #[cfg(feature = "quic")]
+            Self::Quic(v) => Ok(Channel::Quic(v.open().await?)),
+        }
+    }
+
+    /// 等待对方打开的通道。会话结束时返回 `None`
+    pub async fn accept(&self) -> Option<Channel> {
+        match self {
+            Self::Mux(v) => v.accept().await.map(Channel::Mux),
+            #[cfg(feature = "quic")]
+            Self::Quic(v) => v.accept().await.map(Channel::Quic),
+        }
+    }
+
+    /// 未关闭的通道个数
+    pub fn channel_count(&self) -> usize {
+        match self {
+            Self::Mux(v) => v.channel_count(),
+            #[cfg(feature = "quic")]
+            Self::Quic(v) => v.channel_count(),
+        }
+    }
+
+    /// 距离最近一次收到对方数据的时间。QUIC 自己检测连接是否断开，总是返回 0
+    pub fn idle_time(&self) -> Duration {
+        match self {
+            Self::Mux(v) => v.idle_time(),
+            #[cfg(feature = "quic")]
+            Self::Quic(_) => Duration::ZERO,
+        }
+    }
+
+    /// 结束会话，QUIC 会等待数据发送完成
+    pub async fn close(&self) {
+        match self {
+            Self::Mux(_) => {}
+            #[cfg(feature = "quic")]
+            Self::Quic(v) => v.close().await,
+        }
+    }
+}
+
+/// 会话中的通道
+pub enum Channel {
+    Mux(mux::Channel),
+    #[cfg(feature = "quic")]
+    Quic(quic::Channel),
+}
+
+#[async_trait]
+impl Datagram for Channel {
+    fn peer(&self) -> String {
+        match self {
+            Self::Mux(v) => v.peer(),
+            #[cfg(feature = "quic")]
+            Self::Quic(v) => v.peer(),
+        }
+    }
+
+    fn is_encrypted(&self) -> bool {
+        match self {
+            Self::Mux(_) => false,
+            #[cfg(feature = "quic")]
+            Self::Quic(_) => true,
+        }
+    }
+
+    async fn send_bytes(&self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Mux(v) => v.send_bytes(data).await,
+            #[cfg(feature = "quic")]
+            Self::Quic(v) => v.send_bytes(data).await,
+        }
+    }
+
+    async fn recv_bytes(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Mux(v) => v.recv_bytes(buf).await,
+            #[cfg(feature = "quic")]
+            Self::Quic(v) => v.recv_bytes(buf).await,
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! TUN 设备，只支持 Linux
+
+#[cfg(target_os = "linux")]
+pub use linux::Tun;
+
+/// 不支持的平台上无法创建
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+pub enum Tun {}
+
+#[cfg(not(target_os = "linux"))]
+impl Tun {
+    pub fn create(_name: &str) -> crate::Result<Self> {
+        Err(std::io::Error::other("tun is only supported on linux")).map_err(err!())
+    }
+
+    pub fn configure(&self, _addr: &str, _mtu: u32) -> crate::Result<()> {
+        match *self {}
+    }
+
+    pub fn name(&self) -> &str {
+        match *self {}
+    }
+
+    pub fn acquire(&self) -> Option<TunGuard<'_>> {
+        match *self {}
+    }
+
+    pub async fn read(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        match *self {}
+    }
+
+    pub async fn write(&self, _buf: &[u8]) -> std::io::Result<usize> {
+        match *self {}
+    }
+}
+
+/// 占用 TUN 设备，drop 时释放
+pub struct TunGuard<'a>(&'a std::sync::atomic::AtomicBool);
+
+impl Drop for TunGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, std::sync::atomic::Ordering::Release);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fmt::{Debug, Formatter};
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use log::info;
+    use tokio::io::unix::AsyncFd;
+
+    use super::TunGuard;
+
+    const TUNSETIFF: libc::c_ulong = 0x400454ca;
+    const IFF_TUN: libc::c_short = 0x0001;
+    const IFF_NO_PI: libc::c_short = 0x1000;
+
+    /// `struct ifreq` 中用到的部分
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; libc::IFNAMSIZ],
+        flags: libc::c_short,
+        _pad: [u8; 22],
+    }
+
+    pub struct Tun {
+        fd: AsyncFd<File>,
+        name: String,
+        /// 是否已被一个隧道使用
+        busy: AtomicBool,
+    }
+
+    impl Tun {
+        /// 创建 TUN 设备，`name` 可以包含 `%d`，由内核分配编号。需要 CAP_NET_ADMIN 权限
+        pub fn create(name: &str) -> crate::Result<Self> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open("/dev/net/tun")
+                .map_err(err!("cannot open /dev/net/tun"))?;
+
+            if name.len() >= libc::IFNAMSIZ {
+                let e = io::Error::from(io::ErrorKind::InvalidInput);
+                Err(e).map_err(err!("tun name {} is too long", name))?;
+            }
+            let mut req = IfReq {
+                name: [0; libc::IFNAMSIZ],
+                flags: IFF_TUN | IFF_NO_PI,
+                _pad: [0; 22],
+            };
+            req.name[..name.len()].copy_from_slice(name.as_bytes());
+            // SAFETY: req 的布局和内核的 struct ifreq 兼容，且足够大
+            let ret = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req) };
+            if ret < 0 {
+                Err(io::Error::last_os_error()).map_err(err!("cannot create tun {}", name))?;
+            }
+
+            let len = req
+                .name
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(req.name.len());
+            let name = String::from_utf8_lossy(&req.name[..len]).to_string();
+            info!("create tun {}", name);
+            Ok(Self {
+                fd: AsyncFd::new(file).map_err(err!())?,
+                name,
+                busy: AtomicBool::new(false),
+            })
+        }
+
+        /// 设置地址（如 `10.9.0.1/24`）和 MTU，启用设备
+        pub fn configure(&self, addr: &str, mtu: u32) -> crate::Result<()> {
+            ip(&["addr", "add", addr, "dev", &self.name])?;
+            ip(&[
+                "link",
+                "set",
+                "dev",
+                &self.name,
+                "mtu",
+                &mtu.to_string(),
+                "up",
+            ])
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// 占用设备，已被占用时返回 `None`
+        pub fn acquire(&self) -> Option<TunGuard<'_>> {
+            self.busy
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .ok()
+                .map(|_| TunGuard(&self.busy))
+        }
+
+        /// 读取一个 IP 包
+        pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let mut guard = self.fd.readable().await?;
+                if let Ok(result) = guard.try_io(|v| v.get_ref().read(buf)) {
+                    return result;
+                }
+            }
+        }
+
+        /// 写入一个 IP 包
+        pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+            loop {
+                let mut guard = self.fd.writable().await?;
+                if let Ok(result) = guard.try_io(|v| v.get_ref().write(buf)) {
+                    return result;
+                }
+            }
+        }
+    }
+
+    impl Debug for Tun {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Tun({})", self.name)
+        }
+    }
+
+    /// 执行 ip 命令
+    fn ip(args: &[&str]) -> crate::Result<()> {
+        let status = Command::new("ip")
+            .args(args)
+            .status()
+            .map_err(err!("cannot run ip"))?;
+        if !status.success() {
+            let e = io::Error::other(format!("ip {} failed: {}", args.join(" "), status));
+            Err(e).map_err(err!())?;
+        }
+        Ok(())
+    }
+}
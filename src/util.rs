@@ -1,10 +1,10 @@
 use std::env::{set_var, var};
 use std::io;
-use std::io::ErrorKind;
 use std::net::SocketAddr;
 
 use tokio::net::lookup_host;
 use tokio::runtime::{Builder, Runtime};
+use tokio::signal::ctrl_c;
 
 use crate::error::Result;
 
@@ -29,6 +29,13 @@ pub fn init_logger() {
     env_logger::init();
 }
 
+/// 随机数，用来生成外网服务器和 peer 之间的一次性凭证
+pub fn random_u64() -> u64 {
+    let mut buf = [0u8; 8];
+    getrandom::getrandom(&mut buf).expect("cannot get random bytes");
+    u64::from_le_bytes(buf)
+}
+
 /// 解析域名
 pub async fn resolve(host: &str) -> Result<SocketAddr> {
     match lookup_host(host)
@@ -37,10 +44,54 @@ pub async fn resolve(host: &str) -> Result<SocketAddr> {
         .next()
     {
         Some(addr) => Ok(addr),
-        None => Err(io::Error::new(
-            ErrorKind::Other,
-            format!("cannot resolve {}", host),
-        ))
-        .map_err(err!()),
+        None => Err(io::Error::other(format!("cannot resolve {}", host))).map_err(err!()),
+    }
+}
+
+/// 等待退出信号：Ctrl-C，或者 Unix 上的 SIGTERM
+pub async fn shutdown() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = ctrl_c() => {}
+                _ = term.recv() => {}
+            },
+            Err(_) => {
+                let _ = ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c().await;
+    }
+}
+
+/// SIGHUP 信号，非 Unix 平台上不会收到
+pub struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    pub fn new() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::hangup()).map_err(err!())?;
+            Ok(Self { signal })
+        }
+        #[cfg(not(unix))]
+        Ok(Self {})
+    }
+
+    /// 等待下一个信号
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
     }
 }